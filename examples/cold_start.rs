@@ -0,0 +1,66 @@
+// Time-to-parallelism of a cold runtime: submit 10k small tasks, print how
+// long until every processor has run a task, and until all tasks are done.
+//
+//   cargo run --release --example cold_start [burst]
+//
+// With `burst`, the tasks are queued while the executor is paused, and the
+// time is measured from resume, so the whole backlog is there at once.
+
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const TASKS: usize = 10_000;
+
+fn main() {
+  let burst = std::env::args().any(|arg| arg == "burst");
+
+  // more processors than cpus is time sliced, don't count that as blocking
+  lelet::Builder::new()
+    .blocking_threshold(Duration::from_secs(1))
+    .init()
+    .unwrap();
+  let processors = lelet::topology().len();
+
+  if burst {
+    lelet::pause();
+  }
+
+  let start = Arc::new(Mutex::new(Instant::now()));
+  let seen = Arc::new(Mutex::new((HashSet::new(), None)));
+  let handles: Vec<_> = (0..TASKS)
+    .map(|_| {
+      let (start, seen) = (start.clone(), seen.clone());
+      lelet::spawn(async move {
+        let elapsed = start.lock().unwrap().elapsed();
+        let mut seen = seen.lock().unwrap();
+        if seen.0.insert(thread::current().id()) && seen.0.len() == processors {
+          seen.1 = Some(elapsed);
+        }
+        drop(seen);
+
+        let spin = Instant::now();
+        while spin.elapsed() < Duration::from_micros(20) {}
+      })
+    })
+    .collect();
+  let submitted = start.lock().unwrap().elapsed();
+
+  if burst {
+    *start.lock().unwrap() = Instant::now();
+    lelet::resume();
+  }
+  for h in handles {
+    lelet::block_on(h).unwrap();
+  }
+  let done = start.lock().unwrap().elapsed();
+
+  println!(
+    "processors {}, submitted in {:?}, all processors busy after {:?}, done after {:?}",
+    processors,
+    submitted,
+    seen.lock().unwrap().1,
+    done
+  );
+}
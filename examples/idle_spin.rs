@@ -0,0 +1,77 @@
+// CPU time and spawn-to-run latency of the idle path, under steady and
+// bursty load, to compare the adaptive spin before parking with a fixed one.
+//
+//   cargo run --release --example idle_spin [adaptive]
+//
+// Without `adaptive`, the processors always spin 11 times (the default),
+// with it, the spin count is adapted between 2 and 64, see
+// Builder::sleep_spins. Linux only, the cpu time is read from /proc.
+
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const RUN: Duration = Duration::from_secs(2);
+
+// user + system time of the process
+fn cpu_time() -> Duration {
+  let stat = std::fs::read_to_string("/proc/self/stat").unwrap();
+  let fields: Vec<&str> = stat.rsplit(") ").next().unwrap().split(' ').collect();
+  let ticks: u64 = fields[11].parse::<u64>().unwrap() + fields[12].parse::<u64>().unwrap();
+  Duration::from_millis(ticks * 10)
+}
+
+// spawn `burst` tasks every `interval`, for RUN
+fn load(name: &str, burst: usize, interval: Duration) {
+  let latencies = Arc::new(Mutex::new(Vec::new()));
+  let cpu = cpu_time();
+  let started = Instant::now();
+  while started.elapsed() < RUN {
+    for _ in 0..burst {
+      let latencies = latencies.clone();
+      let spawned = Instant::now();
+      drop(lelet::spawn(async move {
+        latencies.lock().unwrap().push(spawned.elapsed());
+      }));
+    }
+    thread::sleep(interval);
+  }
+  thread::sleep(Duration::from_millis(100));
+  let cpu = cpu_time() - cpu;
+
+  let mut latencies = latencies.lock().unwrap();
+  latencies.sort();
+  let at = |q: f64| latencies[((latencies.len() - 1) as f64 * q) as usize];
+  println!(
+    "{}: {} tasks, cpu {:?} ({:.0}% of wall), latency p50 {:?} p99 {:?}",
+    name,
+    latencies.len(),
+    cpu,
+    cpu.as_secs_f64() * 100.0 / (RUN.as_secs_f64() + 0.1),
+    at(0.5),
+    at(0.99)
+  );
+}
+
+fn main() {
+  let adaptive = std::env::args().any(|arg| arg == "adaptive");
+  let builder = lelet::Builder::new();
+  let builder = if adaptive {
+    builder.sleep_spins(2, 64)
+  } else {
+    builder
+  };
+  builder.init().unwrap();
+  println!(
+    "{} processors, {} spin",
+    lelet::topology().len(),
+    if adaptive { "adaptive" } else { "fixed" }
+  );
+
+  load("steady, 1 task every 200us", 1, Duration::from_micros(200));
+  load(
+    "bursty, 100 tasks every 20ms",
+    100,
+    Duration::from_millis(20),
+  );
+}
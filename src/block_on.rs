@@ -0,0 +1,161 @@
+use std::cell::RefCell;
+use std::future::Future;
+use std::panic::{self, AssertUnwindSafe};
+use std::pin::Pin;
+use std::sync::{Arc, Condvar, Mutex};
+use std::task::{Context, Poll};
+use std::thread;
+
+use crate::config;
+
+/// Run the future on the current thread, blocking until it is complete.
+///
+/// Tasks spawned by the future are run in the executor as usual, and they are
+/// detached: they keep running after this return (until the process exit),
+/// use [`block_on_join`] to wait for them.
+///
+/// The future is polled on the current thread, not on the executor machines,
+/// so [`PanicPolicy`] does not apply to it, panic inside the future is always
+/// propagated to the caller (after the future is dropped), never abort the
+/// process. Panic inside spawned task is reported by its [`JoinHandle`] as
+/// usual.
+///
+/// If the future never complete, this block forever, use [`timeout`] to give
+/// up.
+///
+/// [`PanicPolicy`]: enum.PanicPolicy.html
+/// [`JoinHandle`]: struct.JoinHandle.html
+/// [`timeout`]: fn.timeout.html
+/// [`block_on_join`]: fn.block_on_join.html
+pub fn block_on<F: Future>(f: F) -> F::Output {
+  let parker = Arc::new(Parker::default());
+
+  let waker = {
+    let parker = parker.clone();
+    async_task::waker_fn(move || parker.unpark())
+  };
+  let cx = &mut Context::from_waker(&waker);
+
+  let mut f = f;
+  // this is safe because f is shadowed and never moved again
+  let mut f = unsafe { Pin::new_unchecked(&mut f) };
+
+  loop {
+    match panic::catch_unwind(AssertUnwindSafe(|| f.as_mut().poll(cx))) {
+      Ok(Poll::Ready(output)) => return output,
+      Ok(Poll::Pending) => parker.park(),
+
+      // the panic hook is already called, don't call it again
+      Err(payload) => panic::resume_unwind(payload),
+    }
+  }
+}
+
+/// Same as [`block_on`], but also wait until all tasks spawned by the future
+/// are done.
+///
+/// This include tasks spawned by those tasks, and so on, but not the ones
+/// spawned by [`spawn_blocking`] or [`unblock`]. Task is done when its future
+/// is dropped, either completed or cancelled, its [`JoinHandle`] doesn't need
+/// to be awaited. This is the usual pattern for `main`, the process doesn't
+/// exit while there are still pending tasks.
+///
+/// If the future panic, the future is dropped first, then this still wait
+/// for the tasks before the panic is propagated, so the tasks never outlive
+/// the call, see [`Builder::scope_wait_on_panic`].
+///
+/// [`block_on`]: fn.block_on.html
+/// [`spawn_blocking`]: fn.spawn_blocking.html
+/// [`unblock`]: fn.unblock.html
+/// [`JoinHandle`]: struct.JoinHandle.html
+/// [`Builder::scope_wait_on_panic`]: struct.Builder.html#method.scope_wait_on_panic
+pub fn block_on_join<F: Future>(f: F) -> F::Output {
+  let scope = Arc::new(Scope::default());
+
+  // wait in drop, so it is done on unwind too
+  let join = Join(&scope);
+
+  let output = {
+    let prev = SCOPE.with(|s| s.replace(Some(scope.clone())));
+    defer! {
+      SCOPE.with(|s| *s.borrow_mut() = prev);
+    }
+    block_on(f)
+  };
+
+  drop(join);
+  output
+}
+
+struct Join<'a>(&'a Scope);
+
+impl Drop for Join<'_> {
+  fn drop(&mut self) {
+    if thread::panicking() && !config::get().scope_wait_on_panic {
+      return;
+    }
+
+    let mut live = self.0.live.lock().unwrap();
+    while *live > 0 {
+      live = self.0.cond.wait(live).unwrap();
+    }
+  }
+}
+
+thread_local! {
+  // set while running block_on_join, inherited by spawned tasks
+  static SCOPE: RefCell<Option<Arc<Scope>>> = const { RefCell::new(None) };
+}
+
+// tasks spawned (directly or indirectly) from block_on_join
+#[derive(Default)]
+pub(crate) struct Scope {
+  live: Mutex<usize>,
+  cond: Condvar,
+}
+
+impl Scope {
+  // count the task as live until the guard is dropped
+  pub(crate) fn enter(self: &Arc<Scope>) -> ScopeGuard {
+    *self.live.lock().unwrap() += 1;
+    ScopeGuard(self.clone())
+  }
+}
+
+pub(crate) struct ScopeGuard(Arc<Scope>);
+
+impl Drop for ScopeGuard {
+  fn drop(&mut self) {
+    let mut live = self.0.live.lock().unwrap();
+    *live -= 1;
+    if *live == 0 {
+      self.0.cond.notify_all();
+    }
+  }
+}
+
+// scope of block_on_join that is running on the current thread
+pub(crate) fn current_scope() -> Option<Arc<Scope>> {
+  SCOPE.with(|s| s.borrow().clone())
+}
+
+#[derive(Default)]
+struct Parker {
+  notified: Mutex<bool>,
+  cond: Condvar,
+}
+
+impl Parker {
+  fn park(&self) {
+    let mut notified = self.notified.lock().unwrap();
+    while !*notified {
+      notified = self.cond.wait(notified).unwrap();
+    }
+    *notified = false;
+  }
+
+  fn unpark(&self) {
+    *self.notified.lock().unwrap() = true;
+    self.cond.notify_one();
+  }
+}
@@ -0,0 +1,38 @@
+//! Compatibility with other async ecosystems.
+//!
+//! Libraries that are generic over the executor usually need one of these:
+//!
+//! * `block_on(future)`, to drive a future from synchronous code,
+//!   see [`block_on`].
+//! * `spawn(future) -> JoinHandle<T>`, with the same shape as `tokio::spawn`
+//!   (awaiting the handle give `Result<T, JoinError>`), see [`spawn`].
+//! * `spawn_blocking(closure) -> JoinHandle<T>`, with the same shape as
+//!   `tokio::task::spawn_blocking`, see [`spawn_blocking`].
+//! * `futures::task::Spawn` trait, implemented by [`Spawner`]
+//!   (require `futures` feature).
+//!
+//! [`block_on`]: ../fn.block_on.html
+//! [`spawn`]: ../fn.spawn.html
+//! [`spawn_blocking`]: ../fn.spawn_blocking.html
+//! [`Spawner`]: struct.Spawner.html
+
+pub use crate::block_on::block_on;
+pub use crate::executor::{spawn, spawn_blocking, JoinHandle};
+pub use crate::panic::JoinError;
+
+/// Spawner that run the task in lelet executor.
+///
+/// Implement `futures::task::Spawn` when `futures` feature is enabled.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Spawner;
+
+#[cfg(feature = "futures")]
+impl futures_task::Spawn for Spawner {
+  fn spawn_obj(
+    &self,
+    future: futures_task::FutureObj<'static, ()>,
+  ) -> Result<(), futures_task::SpawnError> {
+    spawn(future);
+    Ok(())
+  }
+}
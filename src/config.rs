@@ -0,0 +1,1262 @@
+#[cfg(feature = "cpu-time")]
+use std::any::Any;
+use std::future::Future;
+use std::pin::Pin;
+use std::thread;
+use std::time::Duration;
+
+use once_cell::sync::OnceCell;
+
+use crate::block_on::block_on;
+use crate::executor::{self, ShutdownMode};
+#[cfg(feature = "event-log")]
+use crate::metrics;
+use crate::panic::JoinError;
+use crate::utils::random_seed;
+
+// singleton: CONFIG, frozen when the executor is started
+static CONFIG: OnceCell<Config> = OnceCell::new();
+
+type PanicHandler = Box<dyn Fn(&JoinError) + Send + Sync>;
+#[cfg(feature = "panic-log")]
+type PanicLogFormat = Box<dyn Fn(&JoinError) -> String + Send + Sync>;
+type StallHandler = Box<dyn Fn() + Send + Sync>;
+type IdleHook = Box<dyn Fn() -> bool + Send + Sync>;
+type BoxFuture = Pin<Box<dyn Future<Output = ()> + Send + 'static>>;
+pub(crate) type TaskWrapper = Box<dyn Fn(BoxFuture) -> BoxFuture + Send + Sync>;
+type WatermarkHandler = Box<dyn Fn(Watermark) + Send + Sync>;
+#[cfg(feature = "cpu-time")]
+type CpuTimeHandler = Box<dyn Fn(Option<&(dyn Any + Send + Sync)>, Duration) + Send + Sync>;
+#[cfg(feature = "event-log")]
+type TaskEventSink = Box<dyn Fn(&metrics::TaskEvent) + Send + Sync>;
+
+pub struct Config {
+  pub steal_retry_limit: usize,
+  pub max_queued_memory: usize,
+  pub seed: u64,
+  pub steal_order: StealOrder,
+  pub steal_groups: Option<Vec<usize>>,
+  pub init_mode: InitMode,
+  pub panic_policy: PanicPolicy,
+  pub panic_handler: Option<PanicHandler>,
+  #[cfg(feature = "panic-log")]
+  pub panic_log_format: Option<PanicLogFormat>,
+  pub aging_threshold: Duration,
+  pub max_threads: usize,
+  pub max_processors: usize,
+  pub stall_policy: StallPolicy,
+  pub stall_handler: Option<StallHandler>,
+  pub queue_topology: QueueTopology,
+  pub slow_poll_threshold: Option<Duration>,
+  pub rebalance: bool,
+  pub spawn_locality: bool,
+  pub inherit_priority: bool,
+  pub machine_panic_policy: MachinePanicPolicy,
+  pub push_start: Option<usize>,
+  pub push_stride: usize,
+  pub push_rotation: PushRotation,
+  pub task_count: TaskCount,
+  pub blocking_threshold: Duration,
+  pub max_replacement_rate: Option<usize>,
+  pub max_replacements_per_check: Option<usize>,
+  pub oversubscription_factor: Option<usize>,
+  pub machine_idle_timeout: Option<Duration>,
+  pub timer_granularity: Option<Duration>,
+  #[cfg(all(unix, feature = "signal-dump"))]
+  pub dump_signal: Option<i32>,
+  pub inherit_ratio: Option<usize>,
+  pub wake_strategy: WakeStrategy,
+  pub wake_up_capacity: usize,
+  pub sleep_spins: (usize, usize),
+  pub sleep_backoff: SleepBackoff,
+  pub idle_hook: Option<IdleHook>,
+  pub task_wrapper: Option<TaskWrapper>,
+  pub initial_inherit_batch: Option<usize>,
+  pub scope_wait_on_panic: bool,
+  pub shutdown_drain: bool,
+  pub shutdown_on_idle: Option<Duration>,
+  #[cfg(feature = "registry")]
+  pub max_task_runtime: Option<Duration>,
+  #[cfg(feature = "metrics-log")]
+  pub metrics_log: Option<(Duration, log::Level)>,
+  pub queue_watermarks: Option<(usize, usize)>,
+  pub watermark_debounce: Duration,
+  pub watermark_handler: Option<WatermarkHandler>,
+  #[cfg(feature = "cpu-time")]
+  pub cpu_time_handler: Option<CpuTimeHandler>,
+  #[cfg(feature = "event-log")]
+  pub task_event_sink: Option<TaskEventSink>,
+}
+
+/// Order of task source when the machine's own queue is empty.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StealOrder {
+  /// Take tasks inherited from the replaced (blocking) machine first,
+  /// then the global queue.
+  InheritFirst,
+
+  /// Take tasks from the global queue first,
+  /// then tasks inherited from the replaced (blocking) machine.
+  GlobalFirst,
+}
+
+impl Default for Config {
+  fn default() -> Config {
+    Config {
+      steal_retry_limit: 64,
+      max_queued_memory: usize::MAX,
+      seed: random_seed(),
+      steal_order: StealOrder::InheritFirst,
+      steal_groups: None,
+      init_mode: InitMode::Lazy,
+      panic_policy: PanicPolicy::Abort,
+      panic_handler: None,
+      #[cfg(feature = "panic-log")]
+      panic_log_format: None,
+      aging_threshold: Duration::from_millis(100),
+      max_threads: usize::MAX,
+      max_processors: 0,
+      stall_policy: StallPolicy::Report,
+      stall_handler: None,
+      queue_topology: QueueTopology::PerProcessor,
+      slow_poll_threshold: None,
+      rebalance: false,
+      spawn_locality: true,
+      inherit_priority: true,
+      machine_panic_policy: MachinePanicPolicy::Abort,
+      push_start: Some(0),
+      push_stride: 1,
+      push_rotation: PushRotation::Global,
+      task_count: TaskCount::Batched,
+      blocking_threshold: Duration::from_millis(10),
+      max_replacement_rate: None,
+      max_replacements_per_check: None,
+      oversubscription_factor: None,
+      machine_idle_timeout: None,
+      timer_granularity: None,
+      #[cfg(all(unix, feature = "signal-dump"))]
+      dump_signal: Some(libc::SIGQUIT),
+      inherit_ratio: None,
+      wake_strategy: WakeStrategy::One,
+      wake_up_capacity: 1,
+      sleep_spins: (11, 11),
+      sleep_backoff: SleepBackoff::Snooze,
+      idle_hook: None,
+      task_wrapper: None,
+      initial_inherit_batch: None,
+      scope_wait_on_panic: true,
+      shutdown_drain: false,
+      shutdown_on_idle: None,
+      #[cfg(feature = "registry")]
+      max_task_runtime: None,
+      #[cfg(feature = "metrics-log")]
+      metrics_log: None,
+      queue_watermarks: None,
+      watermark_debounce: Duration::from_secs(1),
+      watermark_handler: None,
+      #[cfg(feature = "cpu-time")]
+      cpu_time_handler: None,
+      #[cfg(feature = "event-log")]
+      task_event_sink: None,
+    }
+  }
+}
+
+pub fn get() -> &'static Config {
+  CONFIG.get_or_init(Config::default)
+}
+
+/// When the executor (processors, machines and sysmon thread) is started.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum InitMode {
+  /// Start on first use (e.g. first [`spawn`]), on the caller thread, so
+  /// the first use is slower, it wait for the machine and sysmon threads to
+  /// be spawned. Nothing is started if the executor is never used.
+  ///
+  /// [`spawn`]: fn.spawn.html
+  Lazy,
+
+  /// Start in [`Builder::init`], on the caller thread.
+  ///
+  /// [`Builder::init`]: struct.Builder.html#method.init
+  Eager,
+
+  /// Start in background thread when [`Builder::init`] is called,
+  /// first use will wait for it if it is not done yet.
+  ///
+  /// [`Builder::init`]: struct.Builder.html#method.init
+  Background,
+}
+
+/// What to do when a task panic.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PanicPolicy {
+  /// Abort the process.
+  Abort,
+
+  /// Catch the panic, and report it as [`JoinError`] to the [`JoinHandle`]
+  /// and to the panic handler (see [`Builder::panic_handler`]).
+  ///
+  /// [`JoinError`]: struct.JoinError.html
+  /// [`JoinHandle`]: struct.JoinHandle.html
+  /// [`Builder::panic_handler`]: struct.Builder.html#method.panic_handler
+  Catch,
+}
+
+/// What to do when a machine (the thread that run a processor) panic outside
+/// of a task poll.
+///
+/// Panic inside a task poll is handled by [`PanicPolicy`], and never reach the
+/// machine. Panics that reach the machine are:
+///
+/// * panic in destructor of a task (its future or its output), e.g. when
+///   the task is completed or cancelled.
+/// * panic in the executor itself (a bug).
+///
+/// [`PanicPolicy`]: enum.PanicPolicy.html
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MachinePanicPolicy {
+  /// Abort the process.
+  Abort,
+
+  /// Print error to stderr, and replace the machine with a new one,
+  /// the tasks in the machine local queue are dropped.
+  ///
+  /// This is risky, the panic may leave shared state (e.g. a `Mutex` that is
+  /// poisoned, or a task that is never woken up) inconsistent, use it only
+  /// when keeping the process alive is more important.
+  Replace,
+}
+
+/// What to do when all processors are blocking and no thread can be spawned
+/// to replace them (the executor is stalled).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StallPolicy {
+  /// Print error to stderr and call the stall handler
+  /// (see [`Builder::stall_handler`]), then keep waiting,
+  /// the executor will recover if one of the blocking task return.
+  ///
+  /// [`Builder::stall_handler`]: struct.Builder.html#method.stall_handler
+  Report,
+
+  /// Same as `Report`, then abort the process.
+  Abort,
+}
+
+/// Layout of the global queues (where spawned or woken up tasks are pushed).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum QueueTopology {
+  /// Each processor has its own global queue, tasks are pushed to the
+  /// processor that last run it (or in round robin for new task),
+  /// and processor pop from its own queue first before the others.
+  ///
+  /// Less contention and better locality.
+  PerProcessor,
+
+  /// Single global queue shared by all processors.
+  ///
+  /// Better fairness (tasks run in the order they are pushed, regardless
+  /// of which processor run them), at the cost of contention on the queue.
+  Shared,
+}
+
+/// Where the rotation of new tasks across processors is kept, see
+/// [`Builder::push_stride`].
+///
+/// [`Builder::push_stride`]: struct.Builder.html#method.push_stride
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PushRotation {
+  /// Single rotation shared by all spawning threads.
+  ///
+  /// Consecutive tasks go to consecutive processors, but the position is
+  /// updated without synchronization, so threads spawning at the same time
+  /// contend on it, and may push to the same processor.
+  Global,
+
+  /// Each spawning thread has its own rotation, starting from different
+  /// processors.
+  ///
+  /// No contention between threads, but the tasks are only spread evenly
+  /// per thread, a thread that spawn few tasks always push to about the
+  /// same processors.
+  PerThread,
+}
+
+/// How completed tasks are counted, see [`pending_tasks`].
+///
+/// [`pending_tasks`]: fn.pending_tasks.html
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TaskCount {
+  /// Each machine count the tasks completed on it locally, and subtract
+  /// them from the shared count in batch, when it has enough of them or
+  /// when it is about to sleep. Tasks spawned from the machine take back
+  /// from its local count first.
+  ///
+  /// Spawning and completing tasks rarely touch the shared count, but the
+  /// count can be higher than the actual number of alive tasks (never
+  /// lower), by up to the batch size per machine, until the machines are
+  /// idle.
+  Batched,
+
+  /// Every spawn and completion update the shared count right away.
+  ///
+  /// The count is exact, e.g. for tests, but it is contended by all
+  /// machines when tasks are spawned and completed at high rate.
+  Exact,
+}
+
+/// Which sleeping processors are woken up when a task is pushed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WakeStrategy {
+  /// Wake up one sleeping processor, it wake up another one if it got more
+  /// tasks than it can run alone.
+  ///
+  /// Less wasted wake up, but a burst of tasks is spread to idle processors
+  /// one by one.
+  One,
+
+  /// Wake up all sleeping processors.
+  ///
+  /// A burst of tasks is picked up by all idle processors right away, at the
+  /// cost of waking up processors that find nothing to do (thundering herd).
+  All,
+}
+
+/// What the processor do in each spin before parking,
+/// see [`Builder::sleep_backoff`].
+///
+/// [`Builder::sleep_backoff`]: struct.Builder.html#method.sleep_backoff
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SleepBackoff {
+  /// Busy spin with exponentially more spin loop hints, then yield the
+  /// thread to the OS once the backoff is completed (crossbeam
+  /// `Backoff::snooze`).
+  Snooze,
+
+  /// Only busy spin with exponentially more spin loop hints, never yield
+  /// the thread (crossbeam `Backoff::spin`).
+  ///
+  /// Lowest wake up latency, but burn the cpu even if other threads could
+  /// use it.
+  Spin,
+
+  /// Yield the thread to the OS in every spin.
+  ///
+  /// Friendlier when there are more threads than cpus.
+  Yield,
+}
+
+/// Queue depth crossed, see [`Builder::queue_watermarks`].
+///
+/// [`Builder::queue_watermarks`]: struct.Builder.html#method.queue_watermarks
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Watermark {
+  /// A processor has at least `high` queued tasks, start shedding load
+  /// (e.g. reject new requests).
+  High,
+
+  /// All processors are back to at most `low` queued tasks.
+  Low,
+}
+
+/// Executor configuration.
+///
+/// The executor is a singleton, so the configuration can only be applied once
+/// and before the executor is running (before first [`spawn`]).
+///
+/// [`spawn`]: fn.spawn.html
+#[derive(Default)]
+pub struct Builder {
+  config: Config,
+
+  // not in the config, it is not Sync, and it is taken by the driver thread
+  shutdown_signal: Option<(BoxFuture, ShutdownMode)>,
+}
+
+impl Builder {
+  pub fn new() -> Builder {
+    Builder::default()
+  }
+
+  /// How many times stealing is retried on contention before giving up.
+  ///
+  /// When the limit is reached, the queue is treated as empty and the
+  /// processor move on to the next source of task.
+  ///
+  /// Default is 64.
+  pub fn steal_retry_limit(mut self, limit: usize) -> Builder {
+    self.config.steal_retry_limit = limit;
+    self
+  }
+
+  /// Limit of estimated memory held by queued tasks, in bytes.
+  ///
+  /// When the limit is reached, [`try_spawn`] will return error,
+  /// [`spawn`] is not affected by this limit.
+  ///
+  /// Default is unlimited.
+  ///
+  /// [`try_spawn`]: fn.try_spawn.html
+  /// [`spawn`]: fn.spawn.html
+  pub fn max_queued_memory(mut self, bytes: usize) -> Builder {
+    self.config.max_queued_memory = bytes;
+    self
+  }
+
+  /// Seed for randomized scheduling decision (e.g. which machine to steal from).
+  ///
+  /// Set this to fixed value for reproducible scheduling in benchmark or test,
+  /// note that thread timing still affect the scheduling.
+  ///
+  /// Default is random.
+  pub fn seed(mut self, seed: u64) -> Builder {
+    self.config.seed = seed;
+    self
+  }
+
+  /// Which one is taken first, inherited tasks or the global queue.
+  ///
+  /// When a machine is blocking, its queued tasks is inherited by the machine
+  /// that replace it, and only that machine can take them. So whatever the
+  /// order is, the other source is still checked periodically, so inherited
+  /// tasks are always drained eventually and never leaked.
+  ///
+  /// Default is [`StealOrder::InheritFirst`].
+  ///
+  /// [`StealOrder::InheritFirst`]: enum.StealOrder.html#variant.InheritFirst
+  pub fn steal_order(mut self, order: StealOrder) -> Builder {
+    self.config.steal_order = order;
+    self
+  }
+
+  /// Group of each processor, indexed by processor index, idle machine steal
+  /// from processors in its own group first, before the other groups.
+  ///
+  /// This is for NUMA systems, e.g. `vec![0, 0, 1, 1]` for 4 processors on
+  /// 2 sockets, stealing within the same socket is cheaper than reaching
+  /// across. Processor that is not in the list is in group 0. Lelet doesn't
+  /// pin the machine threads to cpus, the grouping only help as much as the
+  /// threads stay on the node of their processor (e.g. the OS scheduler keep
+  /// them there, or they are pinned from inside the task).
+  ///
+  /// Default is none, all processors are in the same group.
+  pub fn steal_groups(mut self, groups: Vec<usize>) -> Builder {
+    self.config.steal_groups = Some(groups);
+    self
+  }
+
+  /// Interleave inherited tasks with fresh tasks, one inherited task is run
+  /// for every `ratio` other tasks.
+  ///
+  /// By default, the new machine move a batch of inherited tasks to its local
+  /// queue when it start (and when its local queue is empty), and run all of
+  /// them before the tasks that are pushed after the replacement (except the
+  /// periodic check, see [`steal_order`]). With this, inherited tasks are taken
+  /// one by one instead, so neither of them wait for the other. The global
+  /// queue is checked before the inherited tasks, regardless of
+  /// [`steal_order`].
+  ///
+  /// Default is disabled, zero is treated as 1.
+  ///
+  /// [`steal_order`]: #method.steal_order
+  pub fn inherit_ratio(mut self, ratio: usize) -> Builder {
+    self.config.inherit_ratio = Some(std::cmp::max(1, ratio));
+    self
+  }
+
+  /// How many inherited tasks a new machine move to its local queue when it
+  /// start, the rest are taken later (see [`steal_order`]).
+  ///
+  /// Tasks in the local queue can be stolen by other processors, but tasks
+  /// that are still in the inherited queue can only be taken by the new
+  /// machine. So smaller batch let the new machine start on fresh tasks
+  /// sooner, but other idle processors can't help with the rest of the
+  /// backlog. Ignored when [`inherit_ratio`] is set.
+  ///
+  /// Default is `None`, half of the inherited tasks, up to 32.
+  ///
+  /// [`steal_order`]: #method.steal_order
+  /// [`inherit_ratio`]: #method.inherit_ratio
+  pub fn initial_inherit_batch(mut self, batch: Option<usize>) -> Builder {
+    self.config.initial_inherit_batch = batch;
+    self
+  }
+
+  /// Whether [`block_on_join`] still wait for its tasks when the future panic,
+  /// before the panic is propagated.
+  ///
+  /// Waiting keep the guarantee that the tasks never outlive the call, but if
+  /// the tasks depend on the future to make progress (other than by dropping
+  /// it), the panic is never propagated. Disable this to propagate the panic
+  /// right away, leaving the tasks running in the background.
+  ///
+  /// Default is `true`.
+  ///
+  /// [`block_on_join`]: fn.block_on_join.html
+  pub fn scope_wait_on_panic(mut self, wait: bool) -> Builder {
+    self.config.scope_wait_on_panic = wait;
+    self
+  }
+
+  /// Whether [`shutdown`] run the queued tasks before stopping the
+  /// processors, instead of dropping them.
+  ///
+  /// When enabled, each machine run the tasks in its own queue, then the
+  /// global queues, until they are empty, without stealing from the other
+  /// machines or sleeping, then stop. Tasks that are woken up while draining
+  /// (e.g. by a timer or other task) are run too, they are pushed to a
+  /// processor that is still draining, or dropped if all are stopped. Spawn
+  /// is still rejected, so the draining end once the tasks are done, but a
+  /// task that keep waking itself (or each other) keep it going, use
+  /// [`ShutdownMode::Immediate`] to stop it.
+  ///
+  /// Default is `false`, it can be overridden per call with
+  /// [`shutdown_with_mode`].
+  ///
+  /// [`shutdown`]: fn.shutdown.html
+  /// [`shutdown_with_mode`]: fn.shutdown_with_mode.html
+  /// [`ShutdownMode::Immediate`]: enum.ShutdownMode.html#variant.Immediate
+  pub fn shutdown_drain(mut self, drain: bool) -> Builder {
+    self.config.shutdown_drain = drain;
+    self
+  }
+
+  /// Shut down the executor once there is no task left for `grace`, e.g. for
+  /// CLI program that spawn its work and then wait with [`wait_shutdown`].
+  ///
+  /// A task is left until it is completed or cancelled (its future is
+  /// dropped), a task waiting (e.g. for a timer) is still left, unused
+  /// [`JoinHandle`] of completed task is not. Tasks of [`spawn_blocking`] and
+  /// [`spawn_dedicated`] are not counted. The idle time start after the first
+  /// task is spawned, and any new task within the grace period reset it.
+  ///
+  /// This is checked by the sysmon, so the shutdown may happen a bit (up to
+  /// one sysmon check interval) after the grace period. Don't use this for
+  /// server that can be idle between requests, unless the grace period is
+  /// meant as an idle exit.
+  ///
+  /// Default is disabled.
+  ///
+  /// [`wait_shutdown`]: fn.wait_shutdown.html
+  /// [`JoinHandle`]: struct.JoinHandle.html
+  /// [`spawn_blocking`]: fn.spawn_blocking.html
+  /// [`spawn_dedicated`]: fn.spawn_dedicated.html
+  pub fn shutdown_on_idle(mut self, grace: Duration) -> Builder {
+    self.config.shutdown_on_idle = Some(grace);
+    self
+  }
+
+  /// Shut down the executor when `signal` is complete, e.g. on SIGTERM, as
+  /// if [`shutdown_with_mode`] is called with `mode`.
+  ///
+  /// The signal is awaited by its own thread (started in [`Builder::init`]),
+  /// not by a task, so it doesn't keep [`Builder::shutdown_on_idle`] from
+  /// shutting down, and it doesn't take a processor. The shutdown itself is
+  /// run on that thread too, use [`wait_shutdown`] to wait for it.
+  ///
+  /// Any future works, e.g. [`CancellationToken::cancelled`], with the token
+  /// cancelled from a signal handler thread (like the one of
+  /// `signal-hook` crate), or the receiving side of a channel that is
+  /// closed when the program should stop. If the signal is complete before
+  /// the executor is started, the executor is started and then shut down
+  /// right away. If the executor is shut down in other way, the signal is
+  /// still awaited, completing it then is the same as calling
+  /// [`shutdown_with_mode`] again.
+  ///
+  /// Default is none.
+  ///
+  /// [`shutdown_with_mode`]: fn.shutdown_with_mode.html
+  /// [`Builder::init`]: struct.Builder.html#method.init
+  /// [`Builder::shutdown_on_idle`]: struct.Builder.html#method.shutdown_on_idle
+  /// [`wait_shutdown`]: fn.wait_shutdown.html
+  /// [`CancellationToken::cancelled`]: sync/struct.CancellationToken.html#method.cancelled
+  pub fn shutdown_signal(
+    mut self,
+    signal: impl Future<Output = ()> + Send + 'static,
+    mode: ShutdownMode,
+  ) -> Builder {
+    self.shutdown_signal = Some((Box::pin(signal), mode));
+    self
+  }
+
+  /// When the executor is started, see [`InitMode`].
+  ///
+  /// Default is [`InitMode::Lazy`].
+  ///
+  /// [`InitMode`]: enum.InitMode.html
+  /// [`InitMode::Lazy`]: enum.InitMode.html#variant.Lazy
+  pub fn init_mode(mut self, mode: InitMode) -> Builder {
+    self.config.init_mode = mode;
+    self
+  }
+
+  /// What to do when a task panic, see [`PanicPolicy`].
+  ///
+  /// Default is [`PanicPolicy::Abort`].
+  ///
+  /// [`PanicPolicy`]: enum.PanicPolicy.html
+  /// [`PanicPolicy::Abort`]: enum.PanicPolicy.html#variant.Abort
+  pub fn panic_policy(mut self, policy: PanicPolicy) -> Builder {
+    self.config.panic_policy = policy;
+    self
+  }
+
+  /// Function to call when a task panic and the panic is caught.
+  ///
+  /// The error contain task id, spawn location, panic payload, and backtrace.
+  ///
+  /// This is the single place to handle panic of detached (fire-and-forget)
+  /// tasks, nobody await their [`JoinHandle`]. It is called for every caught
+  /// panic, whether the handle is kept or not, on the thread that run the
+  /// task, right after the panic is caught, before the handle is notified.
+  /// Nothing is done when it is not set.
+  ///
+  /// Only called with [`PanicPolicy::Catch`], and for [`spawn_blocking`].
+  ///
+  /// [`JoinHandle`]: struct.JoinHandle.html
+  /// [`PanicPolicy::Catch`]: enum.PanicPolicy.html#variant.Catch
+  /// [`spawn_blocking`]: fn.spawn_blocking.html
+  pub fn panic_handler(mut self, handler: impl Fn(&JoinError) + Send + Sync + 'static) -> Builder {
+    self.config.panic_handler = Some(Box::new(handler));
+    self
+  }
+
+  /// How long a queued task wait before its priority is increased by one level.
+  ///
+  /// A task that wait for `n * threshold` is treated as if it is spawned
+  /// with `n` level higher priority (up to [`Priority::High`]), so low priority
+  /// task is guaranteed to be run eventually, even under constant high
+  /// priority load. The priority is back to the original when the task is run.
+  /// Tasks are only promoted while there are higher priority tasks queued,
+  /// otherwise they are run in order anyway.
+  ///
+  /// The queues are checked by the sysmon thread every 100ms (it doesn't
+  /// back off while tasks are queued), so the actual waiting time before the
+  /// task is promoted can be up to 100ms longer than this.
+  ///
+  /// Default is 100ms.
+  ///
+  /// [`Priority::High`]: enum.Priority.html#variant.High
+  pub fn priority_aging(mut self, threshold: Duration) -> Builder {
+    self.config.aging_threshold = threshold;
+    self
+  }
+
+  /// Maximum number of threads in the thread pool, including the threads
+  /// that run the processors and [`spawn_blocking`].
+  ///
+  /// When the limit is reached, blocking processor can't be replaced, and
+  /// [`spawn_blocking`] wait for idle thread. Must be more than the number of
+  /// processors (number of cpus), otherwise the executor can't be started.
+  ///
+  /// Default is unlimited (limited by the OS).
+  ///
+  /// [`spawn_blocking`]: fn.spawn_blocking.html
+  pub fn max_threads(mut self, max: usize) -> Builder {
+    self.config.max_threads = max;
+    self
+  }
+
+  /// Maximum number of processors that [`add_processor`] can grow the
+  /// executor to, e.g. when the container CPU quota is increased.
+  ///
+  /// The executor still start with one processor per CPU, the rest are
+  /// spare: their queues are created at start, but they are retired (see
+  /// [`retire_processor`]) and have no machine thread until they are added.
+  /// So the processor list never change size, and the push and steal paths
+  /// don't need lock to read it, the cost is a few empty queues per spare
+  /// processor. Stealing and waking up skip retired processors, but still
+  /// iterate over them.
+  ///
+  /// Values below the number of CPUs have no effect.
+  ///
+  /// Default is the number of CPUs (no spare processor).
+  ///
+  /// [`add_processor`]: fn.add_processor.html
+  /// [`retire_processor`]: fn.retire_processor.html
+  pub fn max_processors(mut self, max: usize) -> Builder {
+    self.config.max_processors = max;
+    self
+  }
+
+  /// What to do when the executor is stalled, see [`StallPolicy`].
+  ///
+  /// Default is [`StallPolicy::Report`].
+  ///
+  /// [`StallPolicy`]: enum.StallPolicy.html
+  /// [`StallPolicy::Report`]: enum.StallPolicy.html#variant.Report
+  pub fn stall_policy(mut self, policy: StallPolicy) -> Builder {
+    self.config.stall_policy = policy;
+    self
+  }
+
+  /// Function to call when the executor is stalled.
+  ///
+  /// It is called once per stall (on the sysmon thread), and called again only
+  /// if the executor recovered and then stalled again.
+  pub fn stall_handler(mut self, handler: impl Fn() + Send + Sync + 'static) -> Builder {
+    self.config.stall_handler = Some(Box::new(handler));
+    self
+  }
+
+  /// Call the watermark handler (see [`watermark_handler`]) with
+  /// [`Watermark::High`] when any processor has at least `high` queued tasks,
+  /// and with [`Watermark::Low`] when all of them are back to at most `low`.
+  ///
+  /// This is a backpressure signal for the application, the executor itself
+  /// doesn't reject anything. The queues are checked by the sysmon thread
+  /// every 100ms (it doesn't back off while tasks are queued or the high
+  /// watermark is signalled), so the handler is called up to 100ms after the
+  /// watermark is crossed, see also [`watermark_debounce`]. `low` larger than
+  /// `high` is treated as `high`.
+  ///
+  /// Default is disabled.
+  ///
+  /// [`watermark_handler`]: #method.watermark_handler
+  /// [`watermark_debounce`]: #method.watermark_debounce
+  /// [`Watermark::High`]: enum.Watermark.html#variant.High
+  /// [`Watermark::Low`]: enum.Watermark.html#variant.Low
+  pub fn queue_watermarks(mut self, high: usize, low: usize) -> Builder {
+    self.config.queue_watermarks = Some((high, std::cmp::min(low, high)));
+    self
+  }
+
+  /// Minimum time between two watermark handler calls, so the handler is not
+  /// flapping when the queue depth is hovering around the watermarks.
+  ///
+  /// Default is 1s.
+  pub fn watermark_debounce(mut self, debounce: Duration) -> Builder {
+    self.config.watermark_debounce = debounce;
+    self
+  }
+
+  /// Function to call when the queue depth cross the watermarks, see
+  /// [`queue_watermarks`].
+  ///
+  /// It is called on the sysmon thread, so it should return quickly.
+  ///
+  /// [`queue_watermarks`]: #method.queue_watermarks
+  pub fn watermark_handler(
+    mut self,
+    handler: impl Fn(Watermark) + Send + Sync + 'static,
+  ) -> Builder {
+    self.config.watermark_handler = Some(Box::new(handler));
+    self
+  }
+
+  /// Function to call after every poll of a task, with the task metadata
+  /// (see [`spawn_tagged`]) and how long the poll take.
+  ///
+  /// Use this to attribute the time to the metadata, e.g. for billing
+  /// tenants, downcast the metadata to your type to get the tenant id.
+  /// It is called on the executor thread, so it should return quickly.
+  ///
+  /// Only available with `cpu-time` feature, see
+  /// [`metrics::processor_cpu_time`].
+  ///
+  /// [`spawn_tagged`]: fn.spawn_tagged.html
+  /// [`metrics::processor_cpu_time`]: metrics/fn.processor_cpu_time.html
+  #[cfg(feature = "cpu-time")]
+  pub fn cpu_time_handler(
+    mut self,
+    handler: impl Fn(Option<&(dyn Any + Send + Sync)>, Duration) + Send + Sync + 'static,
+  ) -> Builder {
+    self.config.cpu_time_handler = Some(Box::new(handler));
+    self
+  }
+
+  /// Function to call with every task lifecycle event, instead of recording
+  /// it in the ring buffer of [`metrics::take_task_events`].
+  ///
+  /// Use this to stream the events to a file or other process, without
+  /// losing the old ones. It is called on the executor threads (and the
+  /// spawning threads) in the middle of scheduling, so it must be quick,
+  /// e.g. push to a channel.
+  ///
+  /// Only available with `event-log` feature.
+  ///
+  /// [`metrics::take_task_events`]: metrics/fn.take_task_events.html
+  #[cfg(feature = "event-log")]
+  pub fn task_event_sink(
+    mut self,
+    sink: impl Fn(&metrics::TaskEvent) + Send + Sync + 'static,
+  ) -> Builder {
+    self.config.task_event_sink = Some(Box::new(sink));
+    self
+  }
+
+  /// Format the message that is logged with `log::error!` when a task panic
+  /// and the panic is caught.
+  ///
+  /// The error contain task id, spawn location, panic payload (see
+  /// [`JoinError::panic_payload`]), and backtrace (captured according to
+  /// `RUST_BACKTRACE`). The function is called on the thread that run the
+  /// task, before the panic handler (see [`panic_handler`]).
+  ///
+  /// Only available with `panic-log` feature.
+  ///
+  /// Default is the error itself, the thread, and the backtrace if it is
+  /// captured.
+  ///
+  /// [`JoinError::panic_payload`]: struct.JoinError.html#method.panic_payload
+  /// [`panic_handler`]: #method.panic_handler
+  #[cfg(feature = "panic-log")]
+  pub fn panic_log_format(
+    mut self,
+    format: impl Fn(&JoinError) -> String + Send + Sync + 'static,
+  ) -> Builder {
+    self.config.panic_log_format = Some(Box::new(format));
+    self
+  }
+
+  /// Log a snapshot of the executor (queued tasks per processor, busy
+  /// processors and number of threads) every `interval`, with the given
+  /// level, using `log` crate.
+  ///
+  /// The snapshot is taken by the sysmon thread, that check less often when
+  /// the executor is idle, so the actual interval can be up to 1.6s longer.
+  /// It stop when the executor is shutting down.
+  ///
+  /// Only available with `metrics-log` feature.
+  ///
+  /// Default is disabled.
+  #[cfg(feature = "metrics-log")]
+  pub fn metrics_log(mut self, interval: Duration, level: log::Level) -> Builder {
+    self.config.metrics_log = Some((interval, level));
+    self
+  }
+
+  /// Layout of the global queues, see [`QueueTopology`].
+  ///
+  /// Default is [`QueueTopology::PerProcessor`].
+  ///
+  /// [`QueueTopology`]: enum.QueueTopology.html
+  /// [`QueueTopology::PerProcessor`]: enum.QueueTopology.html#variant.PerProcessor
+  pub fn queue_topology(mut self, topology: QueueTopology) -> Builder {
+    self.config.queue_topology = topology;
+    self
+  }
+
+  /// Which sleeping processors are woken up when a task is pushed,
+  /// see [`WakeStrategy`].
+  ///
+  /// Default is [`WakeStrategy::One`].
+  ///
+  /// [`WakeStrategy`]: enum.WakeStrategy.html
+  /// [`WakeStrategy::One`]: enum.WakeStrategy.html#variant.One
+  pub fn wake_strategy(mut self, strategy: WakeStrategy) -> Builder {
+    self.config.wake_strategy = strategy;
+    self
+  }
+
+  /// Buffer size of the channels used to wake up sleeping processors, per
+  /// processor.
+  ///
+  /// Pushing a task wake up a processor with `try_send` on the channel, when
+  /// the buffer is full the notification is dropped, that is fine as long as
+  /// the pending notification is not consumed yet, the processor that take it
+  /// will find the task. A larger buffer make the `try_send` rarely fail, so
+  /// burst of pushes queue more notifications, and the processors wake up
+  /// for each of them, even if the tasks are already taken by others, those
+  /// are redundant wake ups, the processor just find nothing and sleep again.
+  ///
+  /// With [`WakeStrategy::All`], the shared channel has this many slots for
+  /// each processor.
+  ///
+  /// Default is 1, zero is treated as 1.
+  ///
+  /// [`WakeStrategy::All`]: enum.WakeStrategy.html#variant.All
+  pub fn wake_up_capacity(mut self, capacity: usize) -> Builder {
+    self.config.wake_up_capacity = std::cmp::max(1, capacity);
+    self
+  }
+
+  /// Bound of how many times an idle processor spin, checking for new
+  /// tasks, before parking its machine.
+  ///
+  /// The actual count is adapted between `min` and `max`, it doubles when
+  /// the processor is woken up shortly after parking, and halves when it is
+  /// parked for long, see [`ProcessorInfo::spin_limit`]. Use the same value
+  /// for both to fix the count. Spinning longer avoid the park/unpark cost
+  /// on bursty workloads, at the cost of burning cpu when idle. What each
+  /// spin does is configured by [`sleep_backoff`].
+  ///
+  /// The adaptation is opt-in, it didn't show lower wake up latency in the
+  /// `idle_spin` example (steady and bursty load), and used up to twice the
+  /// cpu with one processor, measure it on your workload first.
+  ///
+  /// Default is 11 for both (fixed, until the crossbeam `Backoff` is
+  /// completed), `max` lower than `min` is treated as `min`.
+  ///
+  /// [`ProcessorInfo::spin_limit`]: struct.ProcessorInfo.html#structfield.spin_limit
+  /// [`sleep_backoff`]: #method.sleep_backoff
+  pub fn sleep_spins(mut self, min: usize, max: usize) -> Builder {
+    self.config.sleep_spins = (min, std::cmp::max(min, max));
+    self
+  }
+
+  /// What an idle processor do in each spin before parking, see
+  /// [`SleepBackoff`] and [`sleep_spins`].
+  ///
+  /// Default is [`SleepBackoff::Snooze`].
+  ///
+  /// [`SleepBackoff`]: enum.SleepBackoff.html
+  /// [`SleepBackoff::Snooze`]: enum.SleepBackoff.html#variant.Snooze
+  /// [`sleep_spins`]: #method.sleep_spins
+  pub fn sleep_backoff(mut self, backoff: SleepBackoff) -> Builder {
+    self.config.sleep_backoff = backoff;
+    self
+  }
+
+  /// Function to call when a machine has no task to run, right before it
+  /// park, e.g. to poll an external reactor without a dedicated thread.
+  ///
+  /// Return `true` if it produced work (e.g. woke up tasks), then the machine
+  /// look for tasks again instead of parking. It is called on the machine
+  /// thread, possibly by several machines at the same time, and every time a
+  /// machine is about to park, so it must be quick and must not block, the
+  /// processor is not considered blocking while idle, so a blocking hook is
+  /// not detected and its queued tasks wait until other processors steal
+  /// them.
+  ///
+  /// Default is none.
+  pub fn idle_hook(mut self, hook: impl Fn() -> bool + Send + Sync + 'static) -> Builder {
+    self.config.idle_hook = Some(Box::new(hook));
+    self
+  }
+
+  /// Function to wrap the future of every spawned task, e.g. to attach
+  /// tracing span, measure poll time or set task local context.
+  ///
+  /// The wrapper get the task future boxed, and return the future to run
+  /// instead, it must run the given future to completion, otherwise the
+  /// task panic, handled like any other task panic (see [`PanicPolicy`]).
+  /// The wrapper is called once per spawn, on the spawning thread, the panic
+  /// catching, deadline, and other executor layers are still outside of it.
+  ///
+  /// Installing a wrapper cost extra allocations per spawn (the boxed
+  /// future, the boxed wrapper future, and the output slot) and two dynamic
+  /// calls per poll, even when the wrapper do nothing. Without a wrapper,
+  /// spawning is unchanged, no boxing at all.
+  ///
+  /// Tasks of [`spawn_dedicated`] and [`spawn_local`] are not wrapped, their
+  /// future is not `Send`.
+  ///
+  /// Default is none.
+  ///
+  /// [`PanicPolicy`]: enum.PanicPolicy.html
+  /// [`spawn_dedicated`]: fn.spawn_dedicated.html
+  /// [`spawn_local`]: fn.spawn_local.html
+  pub fn task_wrapper(
+    mut self,
+    wrapper: impl Fn(
+        Pin<Box<dyn Future<Output = ()> + Send + 'static>>,
+      ) -> Pin<Box<dyn Future<Output = ()> + Send + 'static>>
+      + Send
+      + Sync
+      + 'static,
+  ) -> Builder {
+    self.config.task_wrapper = Some(Box::new(wrapper));
+    self
+  }
+
+  /// Print warning to stderr when a task run (poll) longer than `threshold`
+  /// without yielding, with its id and spawn location.
+  ///
+  /// Use this to find tasks that are not cooperative. It is okay to block
+  /// in a task (the executor will replace the processor's machine), but the
+  /// other tasks queued on the same machine are delayed until then.
+  ///
+  /// Default is disabled.
+  pub fn slow_poll_threshold(mut self, threshold: Duration) -> Builder {
+    self.config.slow_poll_threshold = Some(threshold);
+    self
+  }
+
+  /// Let the sysmon periodically move tasks from overloaded processor to the
+  /// least loaded one.
+  ///
+  /// Processor is overloaded when its queued tasks is much more than the least
+  /// loaded one. Tasks that run on it are moved on their next yield point,
+  /// see [`migrate_current`].
+  ///
+  /// Default is disabled, idle processors already steal from the others.
+  ///
+  /// [`migrate_current`]: fn.migrate_current.html
+  pub fn rebalance(mut self, enabled: bool) -> Builder {
+    self.config.rebalance = enabled;
+    self
+  }
+
+  /// Push tasks spawned from inside a task to the processor that run the
+  /// parent, instead of the next processor in the rotation.
+  ///
+  /// The child usually work on the same data as the parent, so it is better
+  /// run on the same processor, while the data is still in its cache. Idle
+  /// processors still steal the child, so the work is spread anyway when
+  /// there are enough tasks. Disable this when tasks spawn many independent
+  /// children that should be spread right away, e.g. an accept loop.
+  ///
+  /// Default is enabled.
+  pub fn spawn_locality(mut self, enabled: bool) -> Builder {
+    self.config.spawn_locality = enabled;
+    self
+  }
+
+  /// Tasks spawned from inside a task get the priority of the parent,
+  /// instead of [`Priority::Normal`], unless the priority is given (see
+  /// [`spawn_with_priority`]).
+  ///
+  /// Without this, a high priority task that fan out its work and wait for
+  /// the children is only as fast as normal tasks, and a low priority
+  /// background job can flood the executor with normal priority children.
+  /// The inherited priority is the base priority of the parent, not the one
+  /// promoted by aging (see [`priority_aging`]).
+  ///
+  /// Default is enabled.
+  ///
+  /// [`Priority::Normal`]: enum.Priority.html#variant.Normal
+  /// [`spawn_with_priority`]: fn.spawn_with_priority.html
+  /// [`priority_aging`]: #method.priority_aging
+  pub fn inherit_priority(mut self, enabled: bool) -> Builder {
+    self.config.inherit_priority = enabled;
+    self
+  }
+
+  /// What to do when a machine panic, see [`MachinePanicPolicy`].
+  ///
+  /// Default is [`MachinePanicPolicy::Abort`].
+  ///
+  /// [`MachinePanicPolicy`]: enum.MachinePanicPolicy.html
+  /// [`MachinePanicPolicy::Abort`]: enum.MachinePanicPolicy.html#variant.Abort
+  pub fn machine_panic_policy(mut self, policy: MachinePanicPolicy) -> Builder {
+    self.config.machine_panic_policy = policy;
+    self
+  }
+
+  /// Index of the processor that the first spawned task is pushed to,
+  /// `None` means random (derived from [`seed`]).
+  ///
+  /// New tasks are pushed to processors in rotation, starting from this index,
+  /// see [`push_stride`]. Index larger than the number of processors wrap
+  /// around.
+  ///
+  /// Default is `Some(0)`.
+  ///
+  /// [`seed`]: #method.seed
+  /// [`push_stride`]: #method.push_stride
+  pub fn push_start(mut self, index: Option<usize>) -> Builder {
+    self.config.push_start = index;
+    self
+  }
+
+  /// How far the rotation move after each new task is pushed.
+  ///
+  /// Use stride larger than 1 to spread consecutive tasks to processors that
+  /// are not adjacent (e.g. not sharing cache). The stride should not share
+  /// a common factor with the number of processors, otherwise some processors
+  /// are skipped by the rotation (they still steal tasks from the others).
+  ///
+  /// Default is 1, zero is treated as 1.
+  pub fn push_stride(mut self, stride: usize) -> Builder {
+    self.config.push_stride = std::cmp::max(1, stride);
+    self
+  }
+
+  /// Where the rotation of new tasks is kept, see [`PushRotation`].
+  ///
+  /// Default is [`PushRotation::Global`].
+  ///
+  /// [`PushRotation`]: enum.PushRotation.html
+  /// [`PushRotation::Global`]: enum.PushRotation.html#variant.Global
+  pub fn push_rotation(mut self, rotation: PushRotation) -> Builder {
+    self.config.push_rotation = rotation;
+    self
+  }
+
+  /// How completed tasks are counted, see [`TaskCount`].
+  ///
+  /// Default is [`TaskCount::Batched`].
+  ///
+  /// [`TaskCount`]: enum.TaskCount.html
+  /// [`TaskCount::Batched`]: enum.TaskCount.html#variant.Batched
+  pub fn task_count(mut self, count: TaskCount) -> Builder {
+    self.config.task_count = count;
+    self
+  }
+
+  /// How long a task can run without yielding, before its processor is
+  /// considered blocking and moved to a new machine (thread).
+  ///
+  /// Too low value will make the sysmon replace machines that are just
+  /// running a slightly long poll, see [`max_replacement_rate`].
+  ///
+  /// Default is 10ms.
+  ///
+  /// [`max_replacement_rate`]: #method.max_replacement_rate
+  pub fn blocking_threshold(mut self, threshold: Duration) -> Builder {
+    self.config.blocking_threshold = threshold;
+    self
+  }
+
+  /// Maximum number of machine replacements per second.
+  ///
+  /// If the sysmon replace machines faster than this, the blocking threshold
+  /// is probably too low for the workload, the effective threshold is doubled
+  /// (up to 1 second) and a warning is printed to stderr, so the executor
+  /// does not keep spawning threads. The threshold is never lowered back.
+  ///
+  /// Default is `None` (no limit).
+  pub fn max_replacement_rate(mut self, rate: Option<usize>) -> Builder {
+    self.config.max_replacement_rate = rate;
+    self
+  }
+
+  /// Maximum number of machines (threads) the sysmon create in one check,
+  /// zero is treated as 1.
+  ///
+  /// When many processors start blocking at once, only this many are moved
+  /// to new machines, the rest wait for the next sysmon check (about 100ms
+  /// later), so the threads are spawned gradually instead of all at once.
+  /// This also apply to processors released by idle machines (see
+  /// [`machine_idle_timeout`]). The deferred processors are blocked
+  /// meanwhile, so their queued tasks wait longer.
+  ///
+  /// Default is `None` (no limit).
+  ///
+  /// [`machine_idle_timeout`]: #method.machine_idle_timeout
+  pub fn max_replacements_per_check(mut self, limit: Option<usize>) -> Builder {
+    self.config.max_replacements_per_check = limit.map(|limit| std::cmp::max(1, limit));
+    self
+  }
+
+  /// Print a warning to stderr (once) when the number of threads exceed
+  /// this many times the number of CPUs.
+  ///
+  /// The threads include the machines, the machines created by the sysmon to
+  /// replace the blocking ones, and the threads of [`spawn_blocking`]. Much
+  /// more threads than CPUs usually mean too many blocking tasks, or too low
+  /// blocking threshold (see [`blocking_threshold`]), or other thread pools
+  /// in the same process.
+  ///
+  /// Default is `None` (disabled), `Some(4)` is a good start.
+  ///
+  /// [`spawn_blocking`]: fn.spawn_blocking.html
+  /// [`blocking_threshold`]: #method.blocking_threshold
+  pub fn oversubscription_warning(mut self, factor: Option<usize>) -> Builder {
+    self.config.oversubscription_factor = factor;
+    self
+  }
+
+  /// Cancel tasks that is alive (since spawned) longer than this, as a
+  /// safety net against runaway tasks.
+  ///
+  /// The tasks are checked by the sysmon, so the task may live a bit longer
+  /// than this, up to the sysmon check interval (100ms, or 1.6s when the
+  /// executor is idle). Running task is cancelled after its current poll
+  /// return, awaiting its [`JoinHandle`] return [`JoinError`] with
+  /// [`JoinError::is_timeout`]. Tasks of [`spawn_blocking`] are not
+  /// cancelled.
+  ///
+  /// Only available with `registry` feature.
+  ///
+  /// Default is disabled.
+  ///
+  /// [`JoinHandle`]: struct.JoinHandle.html
+  /// [`JoinError`]: struct.JoinError.html
+  /// [`JoinError::is_timeout`]: struct.JoinError.html#method.is_timeout
+  /// [`spawn_blocking`]: fn.spawn_blocking.html
+  #[cfg(feature = "registry")]
+  pub fn max_task_runtime(mut self, max: Duration) -> Builder {
+    self.config.max_task_runtime = Some(max);
+    self
+  }
+
+  /// Let the machine (thread) exit after it is idle (sleeping) for this long.
+  ///
+  /// The machine release its processor before exiting, the sysmon will create
+  /// new machine for the processor when new task is pushed to it. This keep
+  /// the number of threads low after a spike of blocking tasks, at the cost of
+  /// thread spawning latency when the executor is busy again.
+  ///
+  /// Default is disabled.
+  pub fn machine_idle_timeout(mut self, timeout: Duration) -> Builder {
+    self.config.machine_idle_timeout = Some(timeout);
+    self
+  }
+
+  /// Coalesce timers (e.g. [`sleep`], [`timeout`]) whose deadlines are
+  /// within `granularity` of each other, so the timer thread wake up once and
+  /// fire them together.
+  ///
+  /// The deadlines are rounded up to the next multiple of `granularity`, so
+  /// a timer never fire early, but may fire up to `granularity` late. Use
+  /// this for workloads with many timers, e.g. a timeout on every request,
+  /// where the imprecision is cheaper than waking the timer thread per timer.
+  ///
+  /// Default is disabled, every timer fire at its own deadline.
+  ///
+  /// [`sleep`]: fn.sleep.html
+  /// [`timeout`]: fn.timeout.html
+  pub fn timer_coalescing(mut self, granularity: Duration) -> Builder {
+    self.config.timer_granularity = Some(granularity).filter(|g| *g > Duration::from_secs(0));
+    self
+  }
+
+  /// Dump the scheduler state to stderr when the process receive `signal`,
+  /// like golang goroutine dump on `SIGQUIT`, for diagnosing a stuck
+  /// process.
+  ///
+  /// The dump contain the topology (see [`topology`]), queue depth of each
+  /// processor, number of machines created (including replacements of
+  /// blocking machines) and threads, and all live tasks (see
+  /// [`task_dump`]).
+  ///
+  /// The handler is installed when the executor is started, it replace the
+  /// default action of the signal (e.g. `SIGQUIT` no longer terminate the
+  /// process). The handler only set a flag, because formatting, allocating
+  /// and locking are not async-signal-safe, the sysmon thread see the flag
+  /// and print the dump, so it is printed within 1.6s (the longest sysmon
+  /// backoff) after the signal, even if all processors are stuck.
+  ///
+  /// Default is `Some(SIGQUIT)`, `None` doesn't install the handler.
+  ///
+  /// Only available with `signal-dump` feature, on unix.
+  ///
+  /// [`topology`]: fn.topology.html
+  /// [`task_dump`]: fn.task_dump.html
+  #[cfg(all(unix, feature = "signal-dump"))]
+  pub fn dump_signal(mut self, signal: Option<i32>) -> Builder {
+    self.config.dump_signal = signal;
+    self
+  }
+
+  /// Apply the configuration.
+  ///
+  /// Return error if the executor is already configured or running.
+  pub fn init(self) -> Result<(), String> {
+    let mode = self.config.init_mode;
+    let shutdown_signal = self.shutdown_signal;
+
+    CONFIG
+      .set(self.config)
+      .map_err(|_| "executor is already configured or running".to_string())?;
+
+    match mode {
+      InitMode::Lazy => {}
+      InitMode::Eager => executor::start(),
+      InitMode::Background => {
+        thread::spawn(executor::start);
+      }
+    }
+
+    if let Some((signal, mode)) = shutdown_signal {
+      thread::spawn(move || {
+        block_on(signal);
+        executor::shutdown_with_mode(mode);
+      });
+    }
+
+    Ok(())
+  }
+}
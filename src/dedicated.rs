@@ -0,0 +1,232 @@
+use std::cell::{Cell, RefCell};
+use std::collections::VecDeque;
+use std::future::Future;
+use std::panic::Location;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::sync::{mpsc, Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::thread::{self, Thread};
+
+use crate::executor::{self, JoinHandle, Priority, Task, TaskTag};
+#[cfg(feature = "registry")]
+use crate::registry;
+
+thread_local! {
+  // set on the thread of spawn_dedicated
+  static LOCAL: RefCell<Option<Rc<Local>>> = const { RefCell::new(None) };
+}
+
+/// Run the future on its own new thread, outside of the machines.
+///
+/// This is for thread-affine work, that must stay on the same OS thread, like
+/// GUI or GPU context. The future is created by calling `f` on the new thread,
+/// so it doesn't need to be [`Send`], and it is always polled there. The
+/// thread doesn't take part in work stealing, its tasks are never run by the
+/// machines, and it doesn't steal from them either.
+///
+/// Use [`spawn_local`] inside the future to run more tasks on the same
+/// thread, [`spawn`] still run the task in the machines as usual. The
+/// returned [`JoinHandle`] is complete when the future and all of its local
+/// tasks are done, then the thread exits.
+///
+/// Don't block the thread, e.g. with [`JoinHandle::join`] on a local task,
+/// the other local tasks can't run meanwhile.
+///
+/// [`Send`]: https://doc.rust-lang.org/std/marker/trait.Send.html
+/// [`spawn_local`]: fn.spawn_local.html
+/// [`spawn`]: fn.spawn.html
+/// [`JoinHandle`]: struct.JoinHandle.html
+/// [`JoinHandle::join`]: struct.JoinHandle.html#method.join
+#[track_caller]
+pub fn spawn_dedicated<F, Fut, T>(f: F) -> JoinHandle<T>
+where
+  F: FnOnce() -> Fut + Send + 'static,
+  Fut: Future<Output = T> + 'static,
+  T: Send + 'static,
+{
+  // create the tag here, to inherit trace id, deadline and scope of the caller
+  let tag = TaskTag::new(
+    executor::task_size::<Fut>(),
+    Location::caller(),
+    Priority::Normal,
+  );
+
+  // the task must be created on the new thread, send the handle back
+  let (sender, receiver) = mpsc::sync_channel(1);
+  thread::spawn(move || {
+    let local = Rc::new(Local {
+      queue: Arc::new(Queue {
+        tasks: Mutex::new(VecDeque::new()),
+        thread: thread::current(),
+      }),
+      live: Cell::new(0),
+      waker: RefCell::new(None),
+      done: Cell::new(false),
+    });
+    LOCAL.with(|l| *l.borrow_mut() = Some(local.clone()));
+
+    // outside of the async block, so it is dropped even if never polled
+    let done = Done(local.clone());
+    let main = {
+      let local = local.clone();
+      async move {
+        let _done = done;
+        let output = f().await;
+        Idle(local).await;
+        output
+      }
+    };
+    let (task, handle) = executor::spawn_raw_local(main, tag, local.schedule());
+
+    if executor::is_shutting_down() {
+      executor::reject(task);
+      sender.send(handle).unwrap();
+      return;
+    }
+
+    // register after spawn, the tag is not moved anymore
+    #[cfg(feature = "registry")]
+    registry::register(task.tag());
+
+    task.schedule();
+    sender.send(handle).unwrap();
+
+    local.run();
+    LOCAL.with(|l| l.borrow_mut().take());
+  });
+
+  receiver.recv().unwrap()
+}
+
+/// Run the task on the current thread, only inside [`spawn_dedicated`].
+///
+/// The future doesn't need to be [`Send`], it is always polled on this
+/// thread, in between the other local tasks.
+///
+/// Panic if it is not called from the thread of [`spawn_dedicated`].
+///
+/// [`spawn_dedicated`]: fn.spawn_dedicated.html
+/// [`Send`]: https://doc.rust-lang.org/std/marker/trait.Send.html
+#[track_caller]
+pub fn spawn_local<F, T>(f: F) -> JoinHandle<T>
+where
+  F: Future<Output = T> + 'static,
+  T: Send + 'static,
+{
+  let local = match LOCAL.with(|l| l.borrow().clone()) {
+    Some(local) => local,
+    None => panic!("spawn_local is called outside of spawn_dedicated"),
+  };
+
+  let tag = TaskTag::new(
+    executor::task_size::<F>(),
+    Location::caller(),
+    Priority::Normal,
+  );
+
+  // count it now, not on the first poll, so the main future can't miss it
+  let live = local.enter();
+  let f = async move {
+    let _live = live;
+    f.await
+  };
+  let (task, handle) = executor::spawn_raw_local(f, tag, local.schedule());
+
+  #[cfg(feature = "registry")]
+  registry::register(task.tag());
+
+  task.schedule();
+  handle
+}
+
+// woken tasks, the tasks can be woken from any thread
+struct Queue {
+  tasks: Mutex<VecDeque<Task>>,
+  thread: Thread,
+}
+
+struct Local {
+  queue: Arc<Queue>,
+
+  // number of local tasks that is not dropped yet
+  live: Cell<usize>,
+
+  // the main future, waiting for live to be zero
+  waker: RefCell<Option<Waker>>,
+
+  // the main future is dropped
+  done: Cell<bool>,
+}
+
+impl Local {
+  fn schedule(&self) -> impl Fn(Task) + Send + Sync + 'static {
+    let queue = self.queue.clone();
+    move |t| {
+      queue.tasks.lock().unwrap().push_back(t);
+      queue.thread.unpark();
+    }
+  }
+
+  fn enter(self: &Rc<Local>) -> LiveGuard {
+    self.live.set(self.live.get() + 1);
+    LiveGuard(self.clone())
+  }
+
+  fn run(&self) {
+    // wait for the local tasks too, even if the main future panic, the tasks
+    // must not be dropped on other thread
+    while !self.done.get() || self.live.get() > 0 {
+      // don't hold the lock while running, the task may wake itself
+      let t = self.queue.tasks.lock().unwrap().pop_front();
+      match t {
+        Some(t) => {
+          executor::run_as_current(t);
+        }
+        None => thread::park(),
+      }
+    }
+
+    // only cancelled tasks can be left here, drop them on this thread
+    let tasks = std::mem::take(&mut *self.queue.tasks.lock().unwrap());
+    drop(tasks);
+  }
+}
+
+struct LiveGuard(Rc<Local>);
+
+impl Drop for LiveGuard {
+  fn drop(&mut self) {
+    let local = &self.0;
+    local.live.set(local.live.get() - 1);
+    if local.live.get() == 0 {
+      let waker = local.waker.borrow_mut().take();
+      if let Some(waker) = waker {
+        waker.wake();
+      }
+    }
+  }
+}
+
+struct Done(Rc<Local>);
+
+impl Drop for Done {
+  fn drop(&mut self) {
+    self.0.done.set(true);
+  }
+}
+
+// wait until all local tasks are dropped
+struct Idle(Rc<Local>);
+
+impl Future for Idle {
+  type Output = ();
+
+  fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+    if self.0.live.get() == 0 {
+      return Poll::Ready(());
+    }
+    *self.0.waker.borrow_mut() = Some(cx.waker().clone());
+    Poll::Pending
+  }
+}
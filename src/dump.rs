@@ -0,0 +1,67 @@
+use std::fmt::Write;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use crate::executor;
+use crate::registry;
+use crate::thread_pool;
+
+// set by the signal handler, taken by the sysmon
+//
+// the handler can only do async-signal-safe things, so it doesn't allocate,
+// lock or write to stderr itself, storing to an atomic is safe, the actual
+// dump is done on the sysmon thread on its next check
+static REQUESTED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn on_signal(_: libc::c_int) {
+  REQUESTED.store(true, Ordering::Relaxed);
+}
+
+// see Builder::dump_signal, called once when the executor is started
+pub(crate) fn install(signal: libc::c_int) {
+  unsafe {
+    let mut action: libc::sigaction = std::mem::zeroed();
+    action.sa_sigaction = on_signal as extern "C" fn(libc::c_int) as libc::sighandler_t;
+    action.sa_flags = libc::SA_RESTART;
+    libc::sigemptyset(&mut action.sa_mask);
+    if libc::sigaction(signal, &action, std::ptr::null_mut()) != 0 {
+      eprintln!(
+        "lelet: cannot install handler for signal {}: {}",
+        signal,
+        std::io::Error::last_os_error()
+      );
+    }
+  }
+}
+
+// only called from sysmon thread
+pub(crate) fn check() {
+  if REQUESTED.swap(false, Ordering::Relaxed) {
+    eprint!("{}", dump());
+  }
+}
+
+fn dump() -> String {
+  // format everything first, so the dump is written to stderr in one go
+  let mut out = String::new();
+  let _ = writeln!(out, "lelet: scheduler dump");
+  let _ = writeln!(
+    out,
+    "state {:?}, {} machines created, {} threads, {} bytes queued",
+    executor::runtime_state(),
+    executor::machines_created(),
+    thread_pool::threads(),
+    executor::queued_memory(),
+  );
+
+  for p in executor::topology() {
+    let _ = writeln!(out, "{:?}", p);
+  }
+
+  let tasks = registry::task_dump();
+  let _ = writeln!(out, "{} live tasks", tasks.len());
+  for t in tasks {
+    let _ = writeln!(out, "{:?}", t);
+  }
+
+  out
+}
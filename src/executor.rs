@@ -3,59 +3,301 @@
 // so understand some terminology like machine and processor will help you
 // understand this code.
 
+use std::any::Any;
+use std::cell::Cell;
 use std::future::Future;
-use std::hint::unreachable_unchecked;
 use std::mem::transmute;
-use std::sync::atomic::{AtomicBool, AtomicPtr, AtomicU64, AtomicUsize, Ordering};
-use std::sync::Arc;
+use std::panic::{self, AssertUnwindSafe, Location};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicPtr, AtomicU64, AtomicU8, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::task::{Context, Poll};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
-use crossbeam_channel::{bounded, Receiver, Sender};
+use crossbeam_channel::{bounded, Receiver, Select, Sender};
 use crossbeam_deque::{Injector, Steal, Stealer, Worker};
+use crossbeam_epoch as epoch;
 use crossbeam_utils::Backoff;
 use once_cell::sync::Lazy;
 
 #[cfg(feature = "tracing")]
 use log::trace;
 
+use crate::block_on::{self, block_on, Scope};
+use crate::config;
+use crate::config::{
+  MachinePanicPolicy, PanicPolicy, PushRotation, QueueTopology, SleepBackoff, StallPolicy,
+  StealOrder, TaskCount, WakeStrategy, Watermark,
+};
+#[cfg(all(unix, feature = "signal-dump"))]
+use crate::dump;
+#[cfg(feature = "metrics")]
+use crate::metrics;
+use crate::panic::{CatchUnwind, JoinError};
+#[cfg(feature = "registry")]
+use crate::registry;
 use crate::thread_pool;
+use crate::timer::{sleep_until, timeout, Sleep, Timeout};
 use crate::utils::abort_on_panic;
 use crate::utils::monotonic_ms;
+use crate::utils::monotonic_us;
+use crate::utils::Rng;
+#[cfg(feature = "waker-stats")]
+use crate::wakers::{CountWakes, WakeCounts};
 
-// how long a processor considered to be blocking
-const BLOCKING_THRESHOLD: Duration = Duration::from_millis(10);
+// the blocking threshold is never relaxed more than this,
+// see Executor::check_replacement_rate
+const MAX_BLOCKING_THRESHOLD: Duration = Duration::from_secs(1);
 
-// interval of sysmon check, it is okay to be higher than BLOCKING_THRESHOLD
+// interval of sysmon check, it is okay to be higher than blocking threshold
 // because idle processor will assist the sysmon
 const SYSMON_CHECK_INTERVAL: Duration = Duration::from_millis(100);
 
-struct TaskTag {
-  #[cfg(feature = "tracing")]
-  id: usize,
+// how many completions a machine count locally before subtracting them from
+// the shared count, see TaskCount::Batched
+const COMPLETION_BATCH: usize = 64;
+
+// processor is overloaded when its queued tasks is more than twice the least
+// loaded processor plus this, see Executor::rebalance
+const REBALANCE_THRESHOLD: usize = 16;
+
+// when the executor is idle, the interval is doubled on every check up to this
+const SYSMON_MAX_CHECK_INTERVAL: Duration = Duration::from_millis(1600);
+
+// processor.machine_id when it is not held by any machine,
+// see Machine::exit_idle
+const NO_MACHINE: usize = usize::MAX;
+
+// processor.machine_id when it is stopped by shutdown while not held by any
+// machine, no machine can hold it anymore
+const STOPPED_MACHINE: usize = usize::MAX - 1;
+
+// processor.machine_id of spare processor that is not added yet, it has no
+// machine thread, see add_processor
+const SPARE_MACHINE: usize = usize::MAX - 2;
+
+// parking shorter than this means the processor should spin a bit longer
+const SHORT_PARK: Duration = Duration::from_micros(500);
+
+pub(crate) struct TaskTag {
+  pub(crate) id: usize,
+
+  // where the task is spawned
+  pub(crate) location: &'static Location<'static>,
 
   schedule_hint: AtomicUsize,
+
+  // processor where the task is queued, for accounting
+  queued_on: AtomicUsize,
+
+  // estimated memory held by the task, in bytes
+  size: usize,
+
+  // relative cost of the task, see spawn_with_cost
+  cost: usize,
+
+  // correlation id, inherited from the spawner task, 0 means none
+  trace_id: AtomicU64,
+
+  // the task is cancelled when this passed, inherited from the spawner task,
+  // see spawn_with_deadline
+  deadline: Option<Instant>,
+
+  // see block_on_join, inherited from the spawner task
+  scope: Option<Arc<Scope>>,
+
+  // user metadata, see spawn_tagged
+  pub(crate) meta: Option<Arc<dyn Meta>>,
+
+  // base priority, the task may be promoted while queued, see promote_aged_tasks
+  priority: Priority,
+
+  // always pushed to this processor while it is reserved, see reserve_processor
+  pinned: Option<usize>,
+
+  // for aging and queue latency metrics, in microseconds
+  enqueued_at: AtomicU64,
+
+  #[cfg(feature = "registry")]
+  pub(crate) state: registry::State,
+
+  // how many times the task is run
+  #[cfg(feature = "registry")]
+  pub(crate) runs: AtomicUsize,
+
+  // see cancel_matching
+  #[cfg(feature = "registry")]
+  pub(crate) cancel: Arc<registry::Cancel>,
+
+  // see Builder::max_task_runtime
+  #[cfg(feature = "registry")]
+  pub(crate) spawned_at: Instant,
+
+  // see wakers module
+  #[cfg(feature = "waker-stats")]
+  pub(crate) wake_counts: Arc<WakeCounts>,
+
+  // total time spent running the task, in nanoseconds, see metrics::CpuTimed
+  #[cfg(feature = "cpu-time")]
+  pub(crate) cpu_ns: Arc<AtomicU64>,
+}
+
+pub(crate) type Task = async_task::Task<TaskTag>;
+
+// type erased task metadata, Debug for diagnostic
+pub(crate) trait Meta: Any + Send + Sync + std::fmt::Debug {
+  fn into_any(self: Arc<Self>) -> Arc<dyn Any + Send + Sync>;
+
+  #[cfg(feature = "cpu-time")]
+  fn as_any(&self) -> &(dyn Any + Send + Sync);
+}
+
+impl<M: Any + Send + Sync + std::fmt::Debug> Meta for M {
+  fn into_any(self: Arc<Self>) -> Arc<dyn Any + Send + Sync> {
+    self
+  }
+
+  #[cfg(feature = "cpu-time")]
+  fn as_any(&self) -> &(dyn Any + Send + Sync) {
+    self
+  }
+}
+
+/// Priority of a task, see [`spawn_with_priority`].
+///
+/// [`spawn_with_priority`]: fn.spawn_with_priority.html
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+  Low = 0,
+  Normal = 1,
+  High = 2,
 }
 
-type Task = async_task::Task<TaskTag>;
+const PRIORITY_LEVELS: usize = 3;
 
 // singleton: EXECUTOR
 struct Executor {
   // all processors
   processors: Vec<Processor>,
 
+  // per processor global queues or shared global queues
+  queue_topology: QueueTopology,
+
+  // global queues shared by all processors, used with shared topology
+  injectors: [GlobalQueue; PRIORITY_LEVELS],
+
   // used to select which processor got the task
   processor_push_index_hint: AtomicUsize,
+  processor_push_stride: usize,
+  push_rotation: PushRotation,
+
+  // number of processors that is not retired
+  active_processors: AtomicUsize,
+
+  // machine[i] is currently running processor[i],
+  // the pointers are owned Arc (from Arc::into_raw), see Executor::machine
+  machines: Vec<AtomicPtr<Machine>>,
+
+  // how many times to retry stealing on contention
+  steal_retry_limit: usize,
+
+  // inherited tasks first or global queue first
+  steal_order: StealOrder,
+
+  // group of each processor, see Builder::steal_groups
+  steal_groups: Option<Vec<usize>>,
+  inherit_ratio: Option<usize>,
+
+  // run queued tasks on shutdown, see Builder::shutdown_drain,
+  // can be changed by shutdown_with_mode
+  shutdown_drain: AtomicBool,
+
+  // tasks that are spawned and not completed yet, minus the completions
+  // not flushed by the machines, see pending_tasks
+  alive_tasks: AtomicUsize,
+  task_count: TaskCount,
+
+  // see Builder::shutdown_on_idle
+  shutdown_on_idle: Option<Duration>,
+  spawned_any: AtomicBool,
+  initial_inherit_batch: Option<usize>,
+
+  // sysmon redirect tasks from overloaded processor, see rebalance
+  rebalance: bool,
+
+  // how long a task wait before promoted, in microseconds
+  aging_threshold: u64,
+
+  // estimated memory held by queued tasks, in bytes
+  queued_memory: AtomicUsize,
+  max_queued_memory: usize,
+
+  // sum of cost hint of all queued tasks
+  queued_cost: AtomicUsize,
+
+  // to wakeup sleeping processor, the message is when it is sent
+  // (monotonic_us), see metrics::wake_latency
+  wake_up: Sender<u64>,
+  wake_up_notif: Receiver<u64>,
+  wake_strategy: WakeStrategy,
+
+  // bound of how many times processor spin before parking,
+  // the actual value is adapted according to how often it is woken up
+  sleep_spins: (usize, usize),
+  sleep_backoff: SleepBackoff,
+
+  // warn when a single run of a task take longer than this
+  slow_poll_threshold: Option<Duration>,
+
+  // effective blocking threshold, may be relaxed by sysmon,
+  // see check_replacement_rate
+  blocking_threshold_ms: AtomicU64,
+  max_replacement_rate: Option<usize>,
+
+  // see Builder::max_replacements_per_check
+  max_replacements_per_check: Option<usize>,
+
+  // see Builder::oversubscription_warning
+  oversubscription_factor: Option<usize>,
+
+  // (high, low) queue depth, see Builder::queue_watermarks
+  watermarks: Option<(usize, usize)>,
+  watermark_debounce: Duration,
+  replacements: AtomicUsize,
+
+  // see Builder::machine_idle_timeout
+  machine_idle_timeout: Option<Duration>,
+
+  // set by shutdown, never unset
+  shutting_down: AtomicBool,
+
+  // see runtime_state
+  state: AtomicU8,
+
+  // machines wait at the top of their loop while paused, see pause
+  paused: AtomicBool,
+  pause_lock: Mutex<()>,
+  pause_cond: Condvar,
+
+  // number of processors that is stopped, see Processor::stop
+  stopped: Mutex<usize>,
+  stopped_cond: Condvar,
 
-  // machine[i] is currently running processor[i]
-  machines: Vec<Arc<Machine>>,
+  // number of live critical tasks, see spawn_critical
+  critical: Mutex<usize>,
+  critical_cond: Condvar,
 
-  // used to select which machine to be stealed first
-  machine_steal_index_hint: AtomicUsize,
+  // all processors are blocking and can't be replaced, see check_stall
+  stalled: AtomicBool,
 
-  // to wakeup sleeping processor
-  wake_up: Sender<()>,
-  wake_up_notif: Receiver<()>,
+  // any task is run since last sysmon check, see sysmon_main
+  busy: AtomicBool,
+
+  // the sysmon is backing off, and need to be woken up when busy
+  sysmon_idle: AtomicBool,
+  sysmon_wake: Sender<()>,
+  sysmon_wake_notif: Receiver<()>,
 
   // for sysmon assist
   check_running: AtomicBool,
@@ -65,14 +307,127 @@ struct Executor {
 struct Processor {
   id: usize,
 
-  // current machine that hold the processor
+  // current machine that hold the processor, or NO_MACHINE
   machine_id: AtomicUsize,
 
   // for blocking detection
   last_seen: AtomicU64,
 
-  // global queue dedicated to this processor
+  // global queues dedicated to this processor, one for each priority,
+  // unused with shared topology
+  injectors: [GlobalQueue; PRIORITY_LEVELS],
+
+  // number of tasks pushed to this processor that is not running yet
+  queued: AtomicUsize,
+
+  // sum of cost hint of the queued tasks, see spawn_with_cost
+  queued_cost: AtomicUsize,
+
+  // parked in sleep, for diagnostic only, see topology
+  sleeping: AtomicBool,
+
+  // how many times to spin before parking, see Processor::sleep
+  spin_limit: AtomicUsize,
+
+  // when this processor is overloaded, tasks run on it is moved to this
+  // processor on their next yield, usize::MAX means none, see rebalance
+  redirect: AtomicUsize,
+
+  // retired processor is not pushed to, and its machine is parked
+  retired: AtomicBool,
+  restore_lock: Mutex<()>,
+  restore_cond: Condvar,
+
+  // reserved processor only run its pinned tasks, see reserve_processor
+  reserved: AtomicBool,
+
+  // its queues are not run anymore, see Processor::stop
+  stopped: AtomicBool,
+
+  // total time spent running tasks, in nanoseconds
+  #[cfg(feature = "cpu-time")]
+  run_ns: AtomicU64,
+
+  // where the run tasks come from, see metrics::task_sources
+  #[cfg(feature = "metrics")]
+  sources: metrics::SourceCounters,
+
+  // when the processor is woken up from parking, in microseconds (0 means
+  // not woken up), cleared by the first task run after it
+  #[cfg(feature = "metrics")]
+  woken_at: AtomicU64,
+
+  // wake up this processor only, reserved processor is not woken up by
+  // Executor::wake_up, because it can't run other tasks
+  wake_up: Sender<u64>,
+  wake_up_notif: Receiver<u64>,
+}
+
+// global queue of one priority level, the injector plus the head task that
+// is taken out by the sysmon to check its age, see promote_aged_tasks
+struct GlobalQueue {
   injector: Injector<Task>,
+
+  // the oldest task of the queue (from Task::into_raw) or null,
+  // only set by the sysmon, taken before the injector so the order is kept
+  head: AtomicPtr<TaskTag>,
+}
+
+impl GlobalQueue {
+  fn new() -> GlobalQueue {
+    GlobalQueue {
+      injector: Injector::new(),
+      head: AtomicPtr::new(std::ptr::null_mut()),
+    }
+  }
+
+  fn push(&self, t: Task) {
+    self.injector.push(t);
+  }
+
+  fn steal(&self) -> Steal<Task> {
+    match self.take_head() {
+      Some(t) => Steal::Success(t),
+      None => self.injector.steal(),
+    }
+  }
+
+  fn steal_batch_and_pop(&self, dest: &Worker<Task>) -> Steal<Task> {
+    match self.take_head() {
+      Some(t) => Steal::Success(t),
+      None => self.injector.steal_batch_and_pop(dest),
+    }
+  }
+
+  fn is_empty(&self) -> bool {
+    self.head.load(Ordering::SeqCst).is_null() && self.injector.is_empty()
+  }
+
+  fn take_head(&self) -> Option<Task> {
+    // cheap check first, the head is rarely set
+    if self.head.load(Ordering::Relaxed).is_null() {
+      return None;
+    }
+    let head = self.head.swap(std::ptr::null_mut(), Ordering::SeqCst);
+
+    // this is safe because the pointer is from Task::into_raw, and it is
+    // swapped out, so only we own it
+    (!head.is_null()).then(|| unsafe { Task::from_raw(head) })
+  }
+
+  // only called by the sysmon, with the task just taken from this queue
+  fn hold_head(&self, t: Task) {
+    let old = self
+      .head
+      .swap(t.into_raw() as *mut TaskTag, Ordering::SeqCst);
+    debug_assert!(old.is_null());
+  }
+}
+
+impl Drop for GlobalQueue {
+  fn drop(&mut self) {
+    drop(self.take_head());
+  }
 }
 
 struct Machine {
@@ -86,16 +441,44 @@ struct Machine {
 }
 
 static EXECUTOR: Lazy<Executor> = Lazy::new(|| {
-  // the number is processor is fix
+  let config = config::get();
+
+  // the number is processor is fix, the ones beyond the cpus are spare,
+  // retired and without machine until they are added, see add_processor
   let num_cpus = std::cmp::max(1, num_cpus::get());
+  let num_processors = std::cmp::max(num_cpus, config.max_processors);
 
-  let mut processors = Vec::with_capacity(num_cpus);
-  for id in 0..num_cpus {
+  let mut processors = Vec::with_capacity(num_processors);
+  for id in 0..num_processors {
+    let (wake_up, wake_up_notif) = bounded(config.wake_up_capacity);
+    let spare = id >= num_cpus;
     let p = Processor {
       id,
-      machine_id: AtomicUsize::new(0),
-      last_seen: AtomicU64::new(0),
-      injector: Injector::new(),
+      machine_id: AtomicUsize::new(if spare { SPARE_MACHINE } else { 0 }),
+      last_seen: AtomicU64::new(if spare { u64::MAX } else { 0 }),
+      injectors: [GlobalQueue::new(), GlobalQueue::new(), GlobalQueue::new()],
+      queued: AtomicUsize::new(0),
+      queued_cost: AtomicUsize::new(0),
+      sleeping: AtomicBool::new(false),
+      spin_limit: AtomicUsize::new(std::cmp::min(
+        std::cmp::max(11, config.sleep_spins.0),
+        config.sleep_spins.1,
+      )),
+      redirect: AtomicUsize::new(usize::MAX),
+      retired: AtomicBool::new(spare),
+      restore_lock: Mutex::new(()),
+      restore_cond: Condvar::new(),
+      reserved: AtomicBool::new(false),
+      stopped: AtomicBool::new(false),
+      #[cfg(feature = "cpu-time")]
+      run_ns: AtomicU64::new(0),
+      #[cfg(feature = "metrics")]
+      sources: metrics::SourceCounters::new(),
+
+      #[cfg(feature = "metrics")]
+      woken_at: AtomicU64::new(0),
+      wake_up,
+      wake_up_notif,
     };
 
     #[cfg(feature = "tracing")]
@@ -104,13 +487,22 @@ static EXECUTOR: Lazy<Executor> = Lazy::new(|| {
     processors.push(p);
   }
 
+  // initial machines have nothing to inherit, the worker is dropped at the end
+  // of this scope, but that is fine, the stealers share the queue with it
+  // (reference counted), not borrow it, and no one can push to the queue
+  // after the worker is dropped, so the stealers are always empty
   let empty_worker = Worker::new_fifo();
-  let mut machines = Vec::with_capacity(num_cpus);
-  for index in 0..num_cpus {
-    machines.push(Machine::move_processor_to_new_machine(
-      &processors[index],
-      empty_worker.stealer(),
-    ));
+  let mut machines = Vec::with_capacity(num_processors);
+  for p in &processors {
+    // spare processor get a placeholder without thread, that has nothing
+    // to steal, so machines can be indexed the same way
+    machines.push(if p.is_spare() {
+      let id = MACHINE_ID_COUNTER.fetch_add(1, Ordering::Relaxed);
+      Machine::new(id, empty_worker.stealer(), empty_worker.stealer())
+    } else {
+      Machine::move_processor_to_new_machine(p, empty_worker.stealer())
+        .expect("cannot spawn thread for the machine")
+    });
   }
 
   // just to make sure,
@@ -118,59 +510,345 @@ static EXECUTOR: Lazy<Executor> = Lazy::new(|| {
   for index in 0..processors.len() {
     let p = &processors[index];
     assert_eq!(index, p.id);
-    assert_eq!(p.machine_id.load(Ordering::Relaxed), machines[index].id,);
+    if !p.is_spare() {
+      assert_eq!(p.machine_id.load(Ordering::Relaxed), machines[index].id);
+    }
+  }
+
+  #[cfg(all(unix, feature = "signal-dump"))]
+  if let Some(signal) = config.dump_signal {
+    dump::install(signal);
   }
 
   thread::spawn(move || abort_on_panic(move || EXECUTOR.sysmon_main()));
 
-  // channel with buffer size 1 is enough to give notification
-  // when new task is arrive
-  let (wake_up, wake_up_notif) = bounded(1);
+  // channel with buffer size 1 (the default capacity) is enough to give
+  // notification when new task is arrive, waking up all need the slots for
+  // each processor
+  let (wake_up, wake_up_notif) = bounded(match config.wake_strategy {
+    WakeStrategy::One => config.wake_up_capacity,
+    WakeStrategy::All => processors.len() * config.wake_up_capacity,
+  });
+
+  // where the first task is pushed to, random is derived from the seed,
+  // so it is still reproducible
+  let push_start = match config.push_start {
+    Some(index) => index % num_cpus,
+    None => Rng::new(config.seed).below(num_cpus),
+  };
+  let (sysmon_wake, sysmon_wake_notif) = bounded(1);
 
   Executor {
     processors,
-    processor_push_index_hint: AtomicUsize::new(0),
+    queue_topology: config.queue_topology,
+    injectors: [GlobalQueue::new(), GlobalQueue::new(), GlobalQueue::new()],
+
+    processor_push_index_hint: AtomicUsize::new(push_start),
+    processor_push_stride: config.push_stride,
+    push_rotation: config.push_rotation,
+    active_processors: AtomicUsize::new(num_cpus),
+
+    machines: machines
+      .into_iter()
+      .map(|m| AtomicPtr::new(Arc::into_raw(m) as *mut Machine))
+      .collect(),
+
+    steal_retry_limit: config.steal_retry_limit,
+    steal_order: config.steal_order,
+    steal_groups: config.steal_groups.as_ref().map(|groups| {
+      (0..num_processors)
+        .map(|i| groups.get(i).copied().unwrap_or(0))
+        .collect()
+    }),
+    inherit_ratio: config.inherit_ratio,
+    shutdown_drain: AtomicBool::new(config.shutdown_drain),
+    shutdown_on_idle: config.shutdown_on_idle,
+    alive_tasks: AtomicUsize::new(0),
+    task_count: config.task_count,
+    spawned_any: AtomicBool::new(false),
+    initial_inherit_batch: config.initial_inherit_batch,
+    rebalance: config.rebalance,
+    aging_threshold: std::cmp::max(1, config.aging_threshold.as_micros() as u64),
+
+    queued_memory: AtomicUsize::new(0),
+    max_queued_memory: config.max_queued_memory,
 
-    machines,
-    machine_steal_index_hint: AtomicUsize::new(0),
+    queued_cost: AtomicUsize::new(0),
 
     wake_up,
     wake_up_notif,
+    wake_strategy: config.wake_strategy,
+    sleep_spins: config.sleep_spins,
+    sleep_backoff: config.sleep_backoff,
+
+    slow_poll_threshold: config.slow_poll_threshold,
+
+    blocking_threshold_ms: AtomicU64::new(config.blocking_threshold.as_millis() as u64),
+    max_replacement_rate: config.max_replacement_rate,
+    max_replacements_per_check: config.max_replacements_per_check,
+    oversubscription_factor: config.oversubscription_factor,
+    watermarks: config.queue_watermarks,
+    watermark_debounce: config.watermark_debounce,
+    replacements: AtomicUsize::new(0),
+
+    machine_idle_timeout: config.machine_idle_timeout,
+
+    shutting_down: AtomicBool::new(false),
+    state: AtomicU8::new(RuntimeState::Running as u8),
+    paused: AtomicBool::new(false),
+    pause_lock: Mutex::new(()),
+    pause_cond: Condvar::new(),
+    stopped: Mutex::new(0),
+    stopped_cond: Condvar::new(),
+
+    critical: Mutex::new(0),
+    critical_cond: Condvar::new(),
+
+    stalled: AtomicBool::new(false),
+
+    busy: AtomicBool::new(false),
+    sysmon_idle: AtomicBool::new(false),
+    sysmon_wake,
+    sysmon_wake_notif,
 
     check_running: AtomicBool::new(false),
     check_next: AtomicU64::new(0),
   }
 });
 
-#[cfg(feature = "tracing")]
 static TASK_ID_COUNTER: AtomicUsize = AtomicUsize::new(0);
 
+// id that will be given to the next spawned task
+#[cfg(feature = "registry")]
+pub(crate) fn next_task_id() -> usize {
+  TASK_ID_COUNTER.load(Ordering::Relaxed)
+}
+
 static MACHINE_ID_COUNTER: AtomicUsize = AtomicUsize::new(0);
 
+// number of machines created since the executor is started, including the
+// replacement of blocking machines
+#[cfg(all(unix, feature = "signal-dump"))]
+pub(crate) fn machines_created() -> usize {
+  MACHINE_ID_COUNTER.load(Ordering::Relaxed)
+}
+
 impl TaskTag {
-  fn new() -> TaskTag {
+  pub(crate) fn new(
+    size: usize,
+    location: &'static Location<'static>,
+    priority: Priority,
+  ) -> TaskTag {
     let tag = TaskTag {
-      #[cfg(feature = "tracing")]
       id: TASK_ID_COUNTER.fetch_add(1, Ordering::Relaxed),
 
-      schedule_hint: AtomicUsize::new(usize::MAX),
+      location,
+
+      // the processor that run the parent, see Builder::spawn_locality
+      schedule_hint: AtomicUsize::new(match config::get().spawn_locality {
+        true => with_current(|t| t.and_then(|t| t.get_last_processor())).unwrap_or(usize::MAX),
+        false => usize::MAX,
+      }),
+
+      queued_on: AtomicUsize::new(usize::MAX),
+
+      size,
+
+      cost: 1,
+
+      trace_id: AtomicU64::new(with_current(|t| t.map(|t| t.get_trace_id()).unwrap_or(0))),
+
+      deadline: with_current(|t| t.and_then(|t| t.deadline)),
+
+      scope: block_on::current_scope()
+        .or_else(|| with_current(|t| t.and_then(|t| t.scope.clone()))),
+
+      meta: None,
+
+      priority,
+
+      pinned: None,
+
+      enqueued_at: AtomicU64::new(0),
+
+      #[cfg(feature = "registry")]
+      state: registry::State::new(),
+
+      #[cfg(feature = "registry")]
+      runs: AtomicUsize::new(0),
+
+      #[cfg(feature = "registry")]
+      cancel: Arc::new(registry::Cancel::default()),
+
+      #[cfg(feature = "registry")]
+      spawned_at: Instant::now(),
+
+      #[cfg(feature = "waker-stats")]
+      wake_counts: Arc::new(WakeCounts::default()),
+
+      #[cfg(feature = "cpu-time")]
+      cpu_ns: Arc::new(AtomicU64::new(0)),
     };
 
     #[cfg(feature = "tracing")]
-    trace!("{} is created", TaskTag::string_rep(tag.id));
+    trace!(
+      "{} is created at {} with trace id {}",
+      TaskTag::string_rep(tag.id),
+      tag.location,
+      tag.get_trace_id()
+    );
 
     tag
   }
 
+  pub(crate) fn get_trace_id(&self) -> u64 {
+    self.trace_id.load(Ordering::Relaxed)
+  }
+
+  pub(crate) fn get_last_processor(&self) -> Option<usize> {
+    match self.schedule_hint.load(Ordering::Relaxed) {
+      usize::MAX => None,
+      index => Some(index),
+    }
+  }
+
   #[cfg(feature = "tracing")]
   fn string_rep(id: usize) -> String {
     format!("T({})", id)
   }
 }
 
-#[cfg(feature = "tracing")]
+thread_local! {
+  // tag of the task that is currently running on this thread
+  static CURRENT: Cell<*const TaskTag> = const { Cell::new(std::ptr::null()) };
+
+  // this thread is running a machine
+  static ON_MACHINE: Cell<bool> = const { Cell::new(false) };
+
+  // next processor to push new task to, see PushRotation::PerThread
+  static PUSH_INDEX: Cell<usize> = const { Cell::new(usize::MAX) };
+
+  // completions on this machine, see TaskCount::Batched
+  static COMPLETED: Completions = const { Completions(Cell::new(0)) };
+}
+
+// completions that are not subtracted from Executor::alive_tasks yet,
+// flushed when the machine is idle, or when the thread exit
+struct Completions(Cell<usize>);
+
+impl Completions {
+  fn flush(&self) {
+    let n = self.0.replace(0);
+    if n > 0 {
+      EXECUTOR.complete_tasks(n);
+    }
+  }
+}
+
+impl Drop for Completions {
+  fn drop(&mut self) {
+    self.flush();
+  }
+}
+
+// count new task, see pending_tasks
+fn spawn_counted() {
+  // take back a local completion, instead of touching the shared count
+  let local = EXECUTOR.task_count == TaskCount::Batched
+    && in_executor()
+    && COMPLETED
+      .try_with(|c| match c.0.get() {
+        0 => false,
+        n => {
+          c.0.set(n - 1);
+          true
+        }
+      })
+      .unwrap_or(false);
+  if !local {
+    EXECUTOR.alive_tasks.fetch_add(1, Ordering::SeqCst);
+  }
+}
+
+// count completed (or cancelled) task, see pending_tasks
+fn complete_counted() {
+  // only the machines are sure to flush when idle
+  let local = EXECUTOR.task_count == TaskCount::Batched
+    && in_executor()
+    && COMPLETED
+      .try_with(|c| {
+        let n = c.0.get() + 1;
+        if n < COMPLETION_BATCH {
+          c.0.set(n);
+        } else {
+          c.0.set(0);
+          EXECUTOR.complete_tasks(n);
+        }
+      })
+      .is_ok();
+  if !local {
+    EXECUTOR.complete_tasks(1);
+  }
+}
+
+// run the task, and mark it as current task while it is running
+pub(crate) fn run_as_current(t: Task) -> bool {
+  let tag: *const TaskTag = t.tag();
+
+  #[cfg(feature = "registry")]
+  {
+    t.tag().state.set_running();
+    t.tag().runs.fetch_add(1, Ordering::Relaxed);
+  }
+
+  let prev = CURRENT.with(|c| c.replace(tag));
+  defer! {
+    CURRENT.with(|c| c.set(prev));
+  }
+  t.run()
+}
+
+// the task may be already dropped after run, so these are called by the
+// task itself, from inside the poll
+#[cfg(feature = "registry")]
+pub(crate) fn mark_current_polled(ready: bool) {
+  with_current(|t| {
+    if let Some(t) = t {
+      if ready {
+        t.state.set_completed();
+      } else {
+        t.state.set_idle();
+      }
+    }
+  });
+}
+
+// priority for the new task, the base priority (not promoted by aging) of
+// the current task, see Builder::inherit_priority
+pub(crate) fn inherited_priority() -> Priority {
+  parent_priority(config::get().inherit_priority)
+}
+
+fn parent_priority(inherit: bool) -> Priority {
+  if !inherit {
+    return Priority::Normal;
+  }
+  with_current(|t| t.map_or(Priority::Normal, |t| t.priority))
+}
+
+fn with_current<R>(f: impl FnOnce(Option<&TaskTag>) -> R) -> R {
+  // this is safe because the pointer is only set while the task is running,
+  // and the task (and its tag) is not dropped while it is running
+  CURRENT.with(|c| f(unsafe { c.get().as_ref() }))
+}
+
+#[cfg(any(feature = "tracing", feature = "registry"))]
 impl Drop for TaskTag {
   fn drop(&mut self) {
+    #[cfg(feature = "registry")]
+    registry::unregister(self);
+
+    #[cfg(feature = "tracing")]
     trace!("{} is destroyed", TaskTag::string_rep(self.id));
   }
 }
@@ -186,8 +864,8 @@ impl Executor {
 
     if self
       .check_running
-      .compare_and_swap(false, true, Ordering::Relaxed)
-      == true
+      .compare_exchange(false, true, Ordering::Relaxed, Ordering::Relaxed)
+      .is_err()
     {
       // check already running on other thread
       // only one check allowed at a time
@@ -198,11 +876,19 @@ impl Executor {
       self.check_running.store(false, Ordering::Relaxed)
     }
 
-    if monotonic_ms < BLOCKING_THRESHOLD.as_millis() as u64 {
+    let blocking_threshold = self.blocking_threshold_ms.load(Ordering::Relaxed);
+    if monotonic_ms < blocking_threshold {
       return;
     }
 
-    let must_seen_at = monotonic_ms - BLOCKING_THRESHOLD.as_millis() as u64;
+    let must_seen_at = monotonic_ms - blocking_threshold;
+
+    // number of processors that is blocking and can't be replaced
+    let mut stuck = 0;
+
+    // new machines created in this check, see Builder::max_replacements_per_check
+    let mut created = 0;
+    let mut deferred = false;
 
     for index in 0..self.processors.len() {
       let p = &self.processors[index];
@@ -211,38 +897,68 @@ impl Executor {
         continue;
       }
 
-      let current: &Arc<Machine> = &self.machines[index];
-      let new: &Arc<Machine> = &Machine::move_processor_to_new_machine(p, current.stealer.clone());
+      if self
+        .max_replacements_per_check
+        .map(|limit| created >= limit)
+        .unwrap_or(false)
+      {
+        deferred = true;
+        continue;
+      }
+
+      // the processor is released by its idle machine, not blocking
+      let idle = p.machine_id.load(Ordering::Relaxed) == NO_MACHINE;
+
+      let inherit = self.with_machine(index, |m| m.stealer.clone());
+      let new = match Machine::move_processor_to_new_machine(p, inherit) {
+        Some(new) => new,
+        None => {
+          if !p.is_retired() {
+            stuck += 1;
+          }
+          continue;
+        }
+      };
+
+      created += 1;
+      if !idle {
+        self.replacements.fetch_add(1, Ordering::Relaxed);
+      }
 
       #[cfg(feature = "tracing")]
       trace!(
-        "{:?} is blocking while running on {:?}, replacing with {:?}",
+        "{:?} is blocking while running on M({}), replacing with {:?}",
         p,
-        current,
+        self.with_machine(index, |m| m.id),
         new
       );
 
-      // force swap on immutable list, atomic update the Arc/pointer in the list
-      // this is safe because:
-      // 1) Arc have same size with *mut ()
-      // 2) Arc counter is not touched when swaping
-      // 3) only one thread is doing this (guarded by self.check_running)
-      unsafe {
-        // #1
-        if false {
-          // do not run this code, this is for compile time checking only
-          // transmute null_mut() to Arc will surely crashing the program
-          //
-          // https://internals.rust-lang.org/t/compile-time-assert/6751/2
-          transmute::<*mut (), Arc<Machine>>(std::ptr::null_mut());
-        }
+      // only one thread is doing this (guarded by self.check_running),
+      // take back the ownership of the old Arc, the old machine keep its own
+      // clone until its thread exit, and readers that loaded the pointer
+      // before the swap are still pinned, so drop it after they are done,
+      // see with_machine
+      let new = Arc::into_raw(new) as *mut Machine;
+      let old = self.machines[index].swap(new, Ordering::AcqRel);
+      let old = unsafe { Arc::from_raw(old) };
+      let guard = epoch::pin();
+      guard.defer(move || drop(old));
 
-        // #2
-        let current = transmute::<&Arc<Machine>, &AtomicPtr<()>>(current);
-        let new = transmute::<&Arc<Machine>, &AtomicPtr<()>>(&new);
-        let old = current.swap(new.load(Ordering::Relaxed), Ordering::Relaxed);
-        new.store(old, Ordering::Relaxed);
-      }
+      // replacement is rare, don't leave it in this thread's local garbage
+      guard.flush();
+    }
+
+    self.check_stall(stuck);
+
+    if deferred {
+      // the rest is for the next sysmon check, not the next assist, which
+      // can be right away, also make sure the sysmon is not backing off
+      let half_interval = SYSMON_CHECK_INTERVAL.as_millis() as u64 / 2;
+      self
+        .check_next
+        .store(monotonic_ms + half_interval, Ordering::Relaxed);
+      self.mark_busy();
+      return;
     }
 
     self.check_next.store(
@@ -253,310 +969,3142 @@ impl Executor {
         .chain(std::iter::once(monotonic_ms))
         .min()
         .unwrap()
-        + BLOCKING_THRESHOLD.as_millis() as u64,
+        + blocking_threshold,
       Ordering::Relaxed,
     );
   }
 
-  fn sysmon_main(&self) {
-    loop {
-      thread::sleep(SYSMON_CHECK_INTERVAL);
-      self.sysmon_check();
-    }
-  }
+  // all processors are blocking, and no thread is available to replace them,
+  // no task can make progress
+  fn check_stall(&self, stuck: usize) {
+    let stalled = stuck > 0 && stuck >= self.active_processors.load(Ordering::Relaxed);
 
-  fn sysmon_assist(&self) {
-    self.sysmon_check();
-  }
+    // only report once, until the executor is recovered
+    if self.stalled.swap(stalled, Ordering::Relaxed) || !stalled {
+      return;
+    }
 
-  fn push(&self, t: Task) {
-    let mut index = t.tag().schedule_hint.load(Ordering::Relaxed);
+    let config = config::get();
 
-    // if the task does not have prefered processor, we pick one
-    if index > self.processors.len() {
-      index = self.processor_push_index_hint.load(Ordering::Relaxed);
+    eprintln!(
+      "lelet: all {} processors are blocking and no thread can be spawned to replace them, \
+       the executor is stalled",
+      stuck
+    );
 
-      // rotate the index, for fair load
-      self
-        .processor_push_index_hint
-        .store((index + 1) % self.processors.len(), Ordering::Relaxed);
+    if let Some(handler) = &config.stall_handler {
+      handler();
     }
 
-    self.processors[index].push(t);
+    if config.stall_policy == StallPolicy::Abort {
+      eprintln!("lelet: aborting because of stall policy");
+      std::process::abort();
+    }
   }
 
-  fn pop(&self, index: usize, dest: &Worker<Task>) -> Option<Task> {
-    // pop from global queue that dedicated to processor[index],
-    // if None, proceed to another global queue
-    let (l, r) = self.processors.split_at(index);
-    r.iter()
-      .chain(l.iter())
-      .map(|p| p.pop(dest))
-      .filter(|s| matches!(s, Some(_)))
-      .nth(0)
-      .flatten()
-  }
-
-  fn steal(&self, dest: &Worker<Task>) -> Option<Task> {
-    let m = self.machine_steal_index_hint.load(Ordering::Relaxed);
-    let (l, r) = self.machines.split_at(m);
-    (1..)
-      .zip(r.iter().chain(l.iter()))
-      .map(|(hint_add, m)| {
-        (
-          hint_add,
-          // steal until success or empty
-          std::iter::repeat_with(|| m.stealer.steal_batch_and_pop(dest))
-            .filter(|s| !matches!(s, Steal::Retry)) // not Steal::Retry (*)
-            .map(|s| match s {
-              Steal::Success(task) => Some(task),
-              Steal::Empty => None,
-              Steal::Retry => unsafe { unreachable_unchecked() }, // (*)
-            })
-            .nth(0)
-            .unwrap(),
-        )
-      })
-      .filter(|(_, s)| matches!(s, Some(_)))
-      .nth(0)
-      .map(|(hint_add, s)| {
-        self
-          .machine_steal_index_hint
-          .store((m + hint_add) % self.machines.len(), Ordering::Relaxed);
-        s
-      })
-      .flatten()
-  }
-}
+  // relax the blocking threshold if machines are replaced too often,
+  // only called from sysmon thread
+  fn check_replacement_rate(&self, window_start: &mut u64) {
+    let limit = match self.max_replacement_rate {
+      Some(limit) => limit as u64,
+      None => return,
+    };
 
-impl Processor {
-  fn sleep(&self) {
-    let backoff = Backoff::new();
-    loop {
-      match EXECUTOR.wake_up_notif.try_recv() {
-        Ok(()) => return,
-        Err(_) => {
-          if backoff.is_completed() {
-            #[cfg(feature = "tracing")]
-            trace!("{:?} entering sleep", self);
+    let now = monotonic_ms();
+    let elapsed = now - *window_start;
+    if elapsed < 1000 {
+      return;
+    }
 
-            #[cfg(feature = "tracing")]
-            defer! {
-              trace!("{:?} leaving sleep", self);
-            }
+    let replaced = self.replacements.swap(0, Ordering::Relaxed) as u64;
+    *window_start = now;
 
-            EXECUTOR.wake_up_notif.recv().unwrap();
-            return;
-          } else {
-            backoff.snooze();
-          }
-        }
-      }
+    let rate = replaced * 1000 / elapsed;
+    let current = self.blocking_threshold_ms.load(Ordering::Relaxed);
+    let max = MAX_BLOCKING_THRESHOLD.as_millis() as u64;
+    if rate <= limit || current >= max {
+      return;
     }
-  }
+
+    let relaxed = std::cmp::min(std::cmp::max(1, current * 2), max);
+    self.blocking_threshold_ms.store(relaxed, Ordering::Relaxed);
+
+    eprintln!(
+      "lelet: {} machines replaced per second (limit is {}), \
+       relaxing blocking threshold from {}ms to {}ms, \
+       consider setting higher Builder::blocking_threshold",
+      rate, limit, current, relaxed
+    );
+  }
+
+  // warn once when there are too many threads for the CPUs,
+  // only called from sysmon thread
+  fn check_oversubscription(&self, warned: &mut bool) {
+    let factor = match self.oversubscription_factor {
+      Some(factor) if !*warned => factor,
+      _ => return,
+    };
+
+    let threads = thread_pool::threads();
+    let cpus = num_cpus::get();
+    if threads <= cpus.saturating_mul(factor) {
+      return;
+    }
+    *warned = true;
+
+    eprintln!(
+      "lelet: {} threads running on {} CPUs, \
+       too many blocking tasks or other thread pools in the process? \
+       see Builder::oversubscription_warning",
+      threads, cpus
+    );
+  }
+
+  // cancel tasks that run too long, only called from sysmon thread
+  #[cfg(feature = "registry")]
+  fn check_task_runtime(&self) {
+    if let Some(max) = config::get().max_task_runtime {
+      registry::cancel_expired(max);
+    }
+  }
+
+  // call the watermark handler when the deepest processor queue cross the
+  // watermarks, only called from sysmon thread
+  fn check_watermarks(&self, high: &mut bool, changed_at: &mut Option<Instant>) {
+    let (high_mark, low_mark) = match self.watermarks {
+      Some(marks) => marks,
+      None => return,
+    };
+
+    if let Some(changed_at) = changed_at {
+      if changed_at.elapsed() < self.watermark_debounce {
+        return;
+      }
+    }
+
+    let depth = self
+      .processors
+      .iter()
+      .filter(|p| !p.is_retired())
+      .map(|p| p.queued.load(Ordering::Relaxed))
+      .max()
+      .unwrap_or(0);
+
+    let crossed = match *high {
+      false if depth >= high_mark => Watermark::High,
+      true if depth <= low_mark => Watermark::Low,
+      _ => return,
+    };
+    *high = crossed == Watermark::High;
+    *changed_at = Some(Instant::now());
+
+    if let Some(handler) = &config::get().watermark_handler {
+      handler(crossed);
+    }
+  }
+
+  // see Builder::metrics_log, only called from sysmon thread
+  #[cfg(feature = "metrics-log")]
+  fn log_metrics(&self, logged_at: &mut Instant) {
+    let (interval, level) = match config::get().metrics_log {
+      Some(log) => log,
+      None => return,
+    };
+    if logged_at.elapsed() < interval || self.is_shutting_down() {
+      return;
+    }
+    *logged_at = Instant::now();
+
+    let active = self.processors.iter().filter(|p| !p.is_retired());
+    let busy = active
+      .clone()
+      .filter(|p| !p.sleeping.load(Ordering::Relaxed))
+      .count();
+    let queued: Vec<usize> = self
+      .processors
+      .iter()
+      .map(|p| p.queued.load(Ordering::Relaxed))
+      .collect();
+
+    log::log!(
+      level,
+      "lelet: queued {:?}, busy {}/{} processors, {} threads",
+      queued,
+      busy,
+      active.count(),
+      thread_pool::threads()
+    );
+  }
+
+  fn sysmon_main(&self) {
+    let mut interval = SYSMON_CHECK_INTERVAL;
+    let mut window_start = monotonic_ms();
+    let (mut high, mut changed_at) = (false, None);
+    let mut oversubscribed = false;
+    let mut idle_since = None;
+    #[cfg(feature = "metrics-log")]
+    let mut logged_at = Instant::now();
+    loop {
+      let _ = self.sysmon_wake_notif.recv_timeout(interval);
+      self.sysmon_check();
+      self.check_replacement_rate(&mut window_start);
+      self.check_watermarks(&mut high, &mut changed_at);
+      self.check_oversubscription(&mut oversubscribed);
+
+      #[cfg(feature = "registry")]
+      self.check_task_runtime();
+
+      #[cfg(all(unix, feature = "signal-dump"))]
+      dump::check();
+
+      #[cfg(feature = "metrics-log")]
+      self.log_metrics(&mut logged_at);
+      self.promote_aged_tasks();
+      if self.rebalance {
+        self.rebalance();
+      }
+
+      // back off when no task is run since last check,
+      // mark_busy will wake us up when task is run again
+      self.sysmon_idle.store(true, Ordering::SeqCst);
+      if self.busy.swap(false, Ordering::SeqCst) {
+        self.sysmon_idle.store(false, Ordering::SeqCst);
+        interval = SYSMON_CHECK_INTERVAL;
+      } else {
+        interval = std::cmp::min(interval * 2, SYSMON_MAX_CHECK_INTERVAL);
+      }
+
+      // don't back off while waiting for the grace period
+      if self.check_idle_shutdown(&mut idle_since) {
+        interval = SYSMON_CHECK_INTERVAL;
+      }
+
+      // nor while tasks are queued but not run (e.g. paused), they are
+      // still aging, nor while the high watermark is signalled, so the low
+      // one is not late, see Builder::priority_aging and
+      // Builder::queue_watermarks
+      let queued = self
+        .processors
+        .iter()
+        .any(|p| p.queued.load(Ordering::Relaxed) > 0);
+      if queued || high {
+        interval = SYSMON_CHECK_INTERVAL;
+      }
+    }
+  }
+
+  // see Builder::shutdown_on_idle, only called from sysmon thread,
+  // return true while waiting for the grace period
+  fn check_idle_shutdown(&self, idle_since: &mut Option<Instant>) -> bool {
+    let grace = match self.shutdown_on_idle {
+      Some(grace) => grace,
+      None => return false,
+    };
+
+    if !self.spawned_any.load(Ordering::SeqCst) || self.alive_tasks.load(Ordering::SeqCst) > 0 {
+      *idle_since = None;
+      return false;
+    }
+
+    let since = *idle_since.get_or_insert_with(Instant::now);
+    if since.elapsed() < grace {
+      return true;
+    }
+    *idle_since = None;
+
+    // shutdown block until all processors are stopped, the sysmon must keep
+    // running meanwhile, the state make sure it is only started once
+    if self
+      .state
+      .compare_exchange(
+        RuntimeState::Running as u8,
+        RuntimeState::Draining as u8,
+        Ordering::SeqCst,
+        Ordering::SeqCst,
+      )
+      .is_ok()
+    {
+      #[cfg(feature = "tracing")]
+      trace!("executor is idle for {:?}, shutting down", grace);
+
+      thread::spawn(shutdown);
+    }
+    false
+  }
+
+  // redirect tasks from the most loaded processor to the least loaded one,
+  // the task is moved on its next yield, not in the middle of a poll
+  fn rebalance(&self) {
+    let queued = |p: &&Processor| p.queued_cost.load(Ordering::Relaxed);
+    let active = self.processors.iter().filter(|p| p.accepts_tasks());
+
+    let target = match (active.clone().max_by_key(queued), active.min_by_key(queued)) {
+      (Some(max), Some(min)) if queued(&max) > queued(&min) * 2 + REBALANCE_THRESHOLD => {
+        Some((max.id, min.id))
+      }
+      _ => None,
+    };
+
+    for p in &self.processors {
+      let redirect = match target {
+        Some((from, to)) if from == p.id => to,
+        _ => usize::MAX,
+      };
+      p.redirect.store(redirect, Ordering::Relaxed);
+    }
+  }
+
+  // non retired and non reserved processor that has the least queued tasks
+  // cost
+  fn least_loaded_processor(&self) -> Option<usize> {
+    self
+      .processors
+      .iter()
+      .filter(|p| p.accepts_tasks())
+      .min_by_key(|p| p.queued_cost.load(Ordering::Relaxed))
+      .map(|p| p.id)
+  }
+
+  // called before running a task, so the sysmon is not backing off
+  fn mark_busy(&self) {
+    if self.busy.load(Ordering::Relaxed) {
+      return;
+    }
+
+    self.busy.store(true, Ordering::SeqCst);
+    if self.sysmon_idle.load(Ordering::SeqCst) {
+      let _ = self.sysmon_wake.try_send(());
+    }
+  }
+
+  // move tasks that wait too long to higher priority queue,
+  // the effective priority is increased by one level for every aging_threshold
+  // the task is waiting, so low priority task is not starved
+  fn promote_aged_tasks(&self) {
+    let now = monotonic_us();
+
+    // from the higher level, so the promoted task is not checked twice
+    for level in (0..PRIORITY_LEVELS - 1).rev() {
+      // higher priority on any processor goes first, see Executor::pop,
+      // without them, the tasks are not held back, so don't touch them
+      // (the common case, all tasks has normal priority)
+      let queues = self.distinct_queues();
+      if queues
+        .iter()
+        .all(|p| p.injectors()[level + 1..].iter().all(GlobalQueue::is_empty))
+      {
+        continue;
+      }
+
+      for p in queues {
+        let queue = &p.injectors()[level];
+        while let Some(t) = queue
+          .take_head()
+          .or_else(|| self.steal_until_done(|| queue.injector.steal()))
+        {
+          let waited = now.saturating_sub(t.tag().enqueued_at.load(Ordering::Relaxed));
+          let target = std::cmp::min(
+            t.tag().priority as usize + (waited / self.aging_threshold) as usize,
+            PRIORITY_LEVELS - 1,
+          );
+
+          if target > level {
+            #[cfg(feature = "tracing")]
+            trace!(
+              "{} is promoted to level {} on {:?}",
+              TaskTag::string_rep(t.tag().id),
+              target,
+              p
+            );
+
+            p.push(t, target);
+          } else {
+            // the queue is fifo, the rest is younger than this one,
+            // keep it as the head, so it doesn't lose its position
+            queue.hold_head(t);
+            break;
+          }
+        }
+      }
+    }
+  }
+
+  fn sysmon_assist(&self) {
+    self.sysmon_check();
+  }
+
+  fn is_shutting_down(&self) -> bool {
+    self.shutting_down.load(Ordering::SeqCst)
+  }
+
+  fn is_paused(&self) -> bool {
+    self.paused.load(Ordering::SeqCst)
+  }
+
+  // the processor is not blocking while waiting here, the last run_task
+  // already mark it as non blocking
+  fn wait_resumed(&self) {
+    let mut lock = self.pause_lock.lock().unwrap();
+    while self.is_paused() && !self.is_shutting_down() {
+      lock = self.pause_cond.wait(lock).unwrap();
+    }
+  }
+
+  fn push(&self, t: Task) {
+    // the task will never run, drop it here,
+    // so its JoinHandle is resolved as cancelled,
+    // woken tasks are still run while draining, only spawn is rejected
+    let shutting_down = self.is_shutting_down();
+    if shutting_down && !self.shutdown_drain.load(Ordering::SeqCst) {
+      drop(t);
+      return;
+    }
+
+    let mut index = t.tag().schedule_hint.load(Ordering::Relaxed);
+
+    let reserved = |i: usize| {
+      self
+        .processors
+        .get(i)
+        .map(|p| p.is_reserved())
+        .unwrap_or(false)
+    };
+    match t.tag().pinned {
+      Some(pinned) if reserved(pinned) => index = pinned,
+
+      // if the task does not have prefered processor,
+      // or the prefered processor is out of range, retired or reserved,
+      // fallback to the normal rotation, as if there is no preference
+      _ => {
+        if index >= self.processors.len() || !self.processors[index].accepts_tasks() {
+          index = self.avoid_overloaded(self.next_push_index());
+        }
+      }
+    }
+
+    // the processor already finished draining, any other that is still
+    // draining can run it
+    if shutting_down && self.processors[index].is_stopped() {
+      match self.processors.iter().find(|p| !p.is_stopped()) {
+        Some(p) => index = p.id,
+        None => {
+          drop(t);
+          return;
+        }
+      }
+    }
+
+    // remember where the task is queued, for accounting when it is run
+    t.tag().queued_on.store(index, Ordering::Relaxed);
+
+    #[cfg(feature = "registry")]
+    t.tag().state.set_queued();
+
+    t.tag().enqueued_at.store(monotonic_us(), Ordering::Relaxed);
+    self
+      .queued_memory
+      .fetch_add(t.tag().size, Ordering::Relaxed);
+    self.processors[index]
+      .queued
+      .fetch_add(1, Ordering::Relaxed);
+    self.queued_cost.fetch_add(t.tag().cost, Ordering::Relaxed);
+    self.processors[index]
+      .queued_cost
+      .fetch_add(t.tag().cost, Ordering::Relaxed);
+
+    #[cfg(feature = "event-log")]
+    metrics::record_task_event(
+      t.tag().id,
+      metrics::TaskEventKind::Enqueued { processor: index },
+    );
+
+    let level = t.tag().priority as usize;
+    let p = &self.processors[index];
+    p.push(t, level);
+
+    // the processor is stopped while we push, no one will run it,
+    // drop it like Processor::stop does
+    if p.is_stopped() {
+      p.discard_queued(&Worker::new_fifo());
+      return;
+    }
+
+    self.request_machine(p);
+  }
+
+  // the processor is released by its idle machine, see Machine::exit_idle,
+  // let the sysmon create new machine for it, as if it is blocking
+  fn request_machine(&self, p: &Processor) {
+    if p.machine_id.load(Ordering::SeqCst) != NO_MACHINE {
+      return;
+    }
+
+    p.last_seen.store(0, Ordering::Relaxed);
+    self.check_next.store(0, Ordering::Relaxed);
+    let _ = self.sysmon_wake.try_send(());
+  }
+
+  // the rotation does not know the cost of queued tasks,
+  // if the picked processor is overloaded, pick the least loaded one instead
+  fn avoid_overloaded(&self, index: usize) -> usize {
+    let active = std::cmp::max(1, self.active_processors.load(Ordering::Relaxed));
+    let average = self.queued_cost.load(Ordering::Relaxed) / active;
+    if self.processors[index].queued_cost.load(Ordering::Relaxed) > average * 2 + 1 {
+      self.least_loaded_processor().unwrap_or(index)
+    } else {
+      index
+    }
+  }
+
+  fn next_push_index(&self) -> usize {
+    let len = self.processors.len();
+    let start = match self.push_rotation {
+      PushRotation::Global => self.processor_push_index_hint.load(Ordering::Relaxed),
+
+      // the shared hint is only used to spread the starting point of
+      // each thread
+      PushRotation::PerThread => PUSH_INDEX.with(|i| match i.get() {
+        usize::MAX => {
+          self
+            .processor_push_index_hint
+            .fetch_add(self.processor_push_stride, Ordering::Relaxed)
+            % len
+        }
+        index => index,
+      }),
+    };
+
+    // rotate the index, for fair load, skip retired and reserved processor
+    let index = (0..len)
+      .map(|i| (start + i) % len)
+      .find(|&i| self.processors[i].accepts_tasks())
+      .unwrap_or(start % len);
+
+    let next = (index + self.processor_push_stride) % len;
+    match self.push_rotation {
+      PushRotation::Global => self
+        .processor_push_index_hint
+        .store(next, Ordering::Relaxed),
+      PushRotation::PerThread => PUSH_INDEX.with(|i| i.set(next)),
+    }
+
+    index
+  }
+
+  fn mark_dequeued(&self, t: &Task) {
+    let index = t.tag().queued_on.load(Ordering::Relaxed);
+    self
+      .queued_memory
+      .fetch_sub(t.tag().size, Ordering::Relaxed);
+    self.processors[index]
+      .queued
+      .fetch_sub(1, Ordering::Relaxed);
+    self.queued_cost.fetch_sub(t.tag().cost, Ordering::Relaxed);
+    self.processors[index]
+      .queued_cost
+      .fetch_sub(t.tag().cost, Ordering::Relaxed);
+
+    #[cfg(feature = "metrics")]
+    metrics::QUEUE_LATENCY
+      .record(monotonic_us().saturating_sub(t.tag().enqueued_at.load(Ordering::Relaxed)));
+  }
+
+  fn pop(&self, index: usize, dest: &Worker<Task>) -> Option<Task> {
+    // reserved processor only run its own (pinned) tasks
+    if self.processors[index].is_reserved() {
+      let p = &self.processors[index];
+      return (0..PRIORITY_LEVELS)
+        .rev()
+        .find_map(|level| p.pop(dest, level));
+    }
+
+    // pop from global queue that dedicated to processor[index],
+    // if None, proceed to another global queue,
+    // higher priority on any processor goes first,
+    // the queues of reserved processors are not ours
+    let (l, r) = self.processors.split_at(index);
+    let len = self.distinct_queues().len();
+    (0..PRIORITY_LEVELS).rev().find_map(|level| {
+      r.iter()
+        .chain(l.iter())
+        .take(len)
+        .filter(|p| !p.is_reserved())
+        .find_map(|p| p.pop(dest, level))
+    })
+  }
+
+  // processors that have their own global queues,
+  // with shared topology, all processors use the same queues
+  fn distinct_queues(&self) -> &[Processor] {
+    match self.queue_topology {
+      QueueTopology::PerProcessor => &self.processors,
+      QueueTopology::Shared => &self.processors[..1],
+    }
+  }
+
+  fn steal(&self, thief: usize, dest: &Worker<Task>, rng: &mut Rng) -> Option<Task> {
+    // start from random machine, so thieves don't pile up on the same victim,
+    // pick the more loaded one of two random machines
+    let len = self.machines.len();
+    let (a, b) = (rng.below(len), rng.below(len));
+    let cost = |i: usize| self.processors[i].queued_cost.load(Ordering::Relaxed);
+    let m = if cost(b) > cost(a) { b } else { a };
+
+    // first pass for the thief's group, second pass for the others,
+    // without groups, all processors are in the first pass
+    let (near, passes) = match &self.steal_groups {
+      Some(groups) => (Some(groups), 2),
+      None => (None, 1),
+    };
+    let near = |i: usize| {
+      near
+        .map(|groups| groups[i] == groups[thief])
+        .unwrap_or(true)
+    };
+    (0..passes)
+      .flat_map(|pass| (0..len).map(move |i| (pass, (m + i) % len)))
+      .filter(|&(pass, i)| near(i) == (pass == 0))
+      .map(|(_, i)| i)
+      .filter(|&i| !self.processors[i].is_reserved())
+      .find_map(|i| {
+        let task = self.with_machine(i, |m| {
+          self.steal_until_done(|| m.stealer.steal_batch_and_pop(dest))
+        });
+
+        #[cfg(feature = "steal-trace")]
+        metrics::record_steal(i, task.is_some());
+
+        task
+      })
+  }
+
+  // run the task, and warn if it does not yield for too long
+  fn run(&self, t: Task) {
+    let threshold = match self.slow_poll_threshold {
+      Some(threshold) => threshold,
+      None => {
+        run_as_current(t);
+        return;
+      }
+    };
+
+    // the task may be dropped after run
+    let id = t.tag().id;
+    let location = t.tag().location;
+
+    let started = Instant::now();
+    run_as_current(t);
+    let elapsed = started.elapsed();
+
+    if elapsed > threshold {
+      eprintln!(
+        "lelet: task {} spawned at {} is running for {:?} without yielding (threshold is {:?})",
+        id, location, elapsed, threshold
+      );
+    }
+  }
+
+  // subtract completed tasks from the count, see pending_tasks
+  fn complete_tasks(&self, n: usize) {
+    if self.alive_tasks.fetch_sub(n, Ordering::SeqCst) == n && self.shutdown_on_idle.is_some() {
+      let _ = self.sysmon_wake.try_send(());
+    }
+  }
+
+  // wake up one sleeping processor, if any
+  fn wake_up_one(&self) {
+    let _ = self.wake_up.try_send(monotonic_us());
+  }
+
+  // wake up on new task, see WakeStrategy
+  fn wake_up_on_push(&self) {
+    match self.wake_strategy {
+      WakeStrategy::One => self.wake_up_one(),
+      WakeStrategy::All => {
+        let sleeping = self
+          .processors
+          .iter()
+          .filter(|p| p.sleeping.load(Ordering::Relaxed) && !p.is_reserved())
+          .count();
+
+        // at least one, the processor may be just about to sleep
+        for _ in 0..std::cmp::max(1, sleeping) {
+          if self.wake_up.try_send(monotonic_us()).is_err() {
+            break;
+          }
+        }
+      }
+    }
+  }
+
+  // call f with the machine that currently hold processor[index]
+  //
+  // the machine may be replaced by the sysmon meanwhile, and its thread may
+  // exit, but the Arc of the replaced machine is only dropped after every
+  // thread that is pinned here is unpinned, so the reference stay valid
+  // until f return
+  fn with_machine<R>(&self, index: usize, f: impl FnOnce(&Machine) -> R) -> R {
+    let _guard = epoch::pin();
+    f(unsafe { &*self.machines[index].load(Ordering::Acquire) })
+  }
+
+  // steal until success or empty,
+  // but give up after steal_retry_limit retries, so we don't spin forever
+  // under heavy contention
+  fn steal_until_done(&self, steal: impl FnMut() -> Steal<Task>) -> Option<Task> {
+    std::iter::repeat_with(steal)
+      .take(self.steal_retry_limit.saturating_add(1))
+      .find(|s| !s.is_retry())
+      .and_then(|s| s.success())
+  }
+}
+
+impl Processor {
+  // return false if not woken up after the machine idle timeout
+  fn sleep(&self) -> bool {
+    // no task to run, so no completion for a while
+    COMPLETED.with(Completions::flush);
+
+    // the previous wake up found no task
+    #[cfg(feature = "metrics")]
+    self.woken_at.store(0, Ordering::Relaxed);
+
+    let spin_limit = self.spin_limit.load(Ordering::Relaxed);
+
+    // reserved processor is only woken up by push to its own queue
+    let reserved = self.is_reserved();
+
+    let backoff = Backoff::new();
+    for _ in 0..spin_limit {
+      if self.wake_up_notif.try_recv().is_ok()
+        || (!reserved && EXECUTOR.wake_up_notif.try_recv().is_ok())
+      {
+        return true;
+      }
+      match EXECUTOR.sleep_backoff {
+        SleepBackoff::Snooze => backoff.snooze(),
+        SleepBackoff::Spin => backoff.spin(),
+        SleepBackoff::Yield => thread::yield_now(),
+      }
+    }
+
+    // give the idle time to the external event loop, see Builder::idle_hook
+    if let Some(hook) = &config::get().idle_hook {
+      if hook() {
+        return true;
+      }
+    }
+
+    #[cfg(feature = "tracing")]
+    trace!("{:?} entering sleep", self);
+
+    #[cfg(feature = "tracing")]
+    defer! {
+      trace!("{:?} leaving sleep", self);
+    }
+
+    let parked_at = Instant::now();
+    #[cfg(feature = "metrics")]
+    let parked_at_us = monotonic_us();
+    self.sleeping.store(true, Ordering::Relaxed);
+    defer! {
+      self.sleeping.store(false, Ordering::Relaxed);
+    }
+
+    let mut select = Select::new();
+    let own = select.recv(&self.wake_up_notif);
+    if !reserved {
+      select.recv(&EXECUTOR.wake_up_notif);
+    }
+    let op = match EXECUTOR.machine_idle_timeout {
+      Some(timeout) => match select.select_timeout(timeout) {
+        Ok(op) => op,
+        Err(_) => return false,
+      },
+      None => select.select(),
+    };
+    let sent_at = if op.index() == own {
+      op.recv(&self.wake_up_notif).unwrap()
+    } else {
+      let sent_at = op.recv(&EXECUTOR.wake_up_notif).unwrap();
+
+      // reserved while sleeping, the notification was meant for other
+      // processor, pass it on
+      if self.is_reserved() {
+        EXECUTOR.wake_up_one();
+      }
+      sent_at
+    };
+
+    // the notification may be sent long before we park (e.g. while all
+    // processors were busy), then we are not really parked
+    #[cfg(feature = "metrics")]
+    self.woken_at.store(
+      std::cmp::max(sent_at, parked_at_us).max(1),
+      Ordering::Relaxed,
+    );
+    #[cfg(not(feature = "metrics"))]
+    let _ = sent_at;
+
+    // adapt the spin limit to the wake up frequency,
+    // if we are woken up shortly after parking, spinning longer would have
+    // avoided the park/unpark cost, if we are parked for long time,
+    // spinning is just burning cpu
+    let (min, max) = EXECUTOR.sleep_spins;
+    let spin_limit = if parked_at.elapsed() < SHORT_PARK {
+      std::cmp::min(std::cmp::max(spin_limit.saturating_mul(2), 1), max)
+    } else {
+      std::cmp::max(spin_limit / 2, min)
+    };
+    self.spin_limit.store(spin_limit, Ordering::Relaxed);
+    true
+  }
+
+  fn is_retired(&self) -> bool {
+    self.retired.load(Ordering::Relaxed)
+  }
+
+  // not added yet, see add_processor
+  fn is_spare(&self) -> bool {
+    self.machine_id.load(Ordering::SeqCst) == SPARE_MACHINE
+  }
+
+  fn is_reserved(&self) -> bool {
+    self.reserved.load(Ordering::Relaxed)
+  }
+
+  // new tasks (that are not pinned) can be pushed here
+  fn accepts_tasks(&self) -> bool {
+    !self.is_retired() && !self.is_reserved()
+  }
+
+  fn wake_up(&self) {
+    let _ = self.wake_up.try_send(monotonic_us());
+  }
+
+  // move all tasks from this processor to other processors,
+  // then park until the processor is restored
+  fn park_retired(&self, worker: &Worker<Task>) {
+    #[cfg(feature = "tracing")]
+    trace!("{:?} is retired", self);
+
+    let migrate = |t: Task| {
+      EXECUTOR.mark_dequeued(&t);
+      t.tag().schedule_hint.store(usize::MAX, Ordering::Relaxed);
+      EXECUTOR.push(t);
+    };
+    while let Some(t) = worker.pop() {
+      migrate(t);
+    }
+    // shared queues are still popped by other processors
+    if EXECUTOR.queue_topology == QueueTopology::PerProcessor {
+      for injector in &self.injectors {
+        while let Some(t) = EXECUTOR.steal_until_done(|| injector.steal()) {
+          migrate(t);
+        }
+      }
+    }
+
+    // we may have consumed wake up notification that was meant for
+    // other processor, pass it on
+    EXECUTOR.wake_up_one();
+    COMPLETED.with(Completions::flush);
+
+    self.mark_nonblocking();
+    let mut lock = self.restore_lock.lock().unwrap();
+    while self.is_retired() && !EXECUTOR.is_shutting_down() {
+      lock = self.restore_cond.wait(lock).unwrap();
+    }
+
+    #[cfg(feature = "tracing")]
+    trace!("{:?} is restored", self);
+  }
+
+  // drop all queued tasks, the machine must exit after this
+  fn stop(&self, worker: &Worker<Task>) {
+    #[cfg(feature = "tracing")]
+    trace!("{:?} is stopped", self);
+
+    // set before the queues are dropped, so a concurrent push either see it,
+    // or its task is dropped here, see Executor::push
+    self.stopped.store(true, Ordering::SeqCst);
+    self.discard_queued(worker);
+
+    self.mark_nonblocking();
+
+    // pass the notification on, so all sleeping processors are stopped
+    EXECUTOR.wake_up_one();
+
+    let mut stopped = EXECUTOR.stopped.lock().unwrap();
+    *stopped += 1;
+    if *stopped == EXECUTOR.processors.len() {
+      EXECUTOR
+        .state
+        .store(RuntimeState::Stopped as u8, Ordering::SeqCst);
+    }
+    drop(stopped);
+    EXECUTOR.stopped_cond.notify_all();
+  }
+
+  // dropping the task run its destructor, that may wake (push) other task,
+  // or spawn new one, the spawn is rejected, and the woken task is pushed to
+  // processor that is still draining, or dropped, see Executor::push
+  fn discard_queued(&self, worker: &Worker<Task>) {
+    let discard = |t: Task| {
+      EXECUTOR.mark_dequeued(&t);
+      drop(t);
+    };
+    while let Some(t) = worker.pop() {
+      discard(t);
+    }
+    for injector in self.injectors() {
+      while let Some(t) = EXECUTOR.steal_until_done(|| injector.steal()) {
+        discard(t);
+      }
+    }
+  }
+
+  fn is_stopped(&self) -> bool {
+    self.stopped.load(Ordering::SeqCst)
+  }
 
   fn mark_blocking(&self) {
     self.last_seen.store(monotonic_ms(), Ordering::Relaxed);
   }
 
-  fn mark_nonblocking(&self) {
-    self.last_seen.store(u64::MAX, Ordering::Relaxed);
+  fn mark_nonblocking(&self) {
+    self.last_seen.store(u64::MAX, Ordering::Relaxed);
+  }
+
+  fn get_last_seen(&self) -> u64 {
+    self.last_seen.load(Ordering::Relaxed)
+  }
+
+  fn push(&self, t: Task, level: usize) {
+    self.injectors()[level].push(t);
+
+    // reserved processor is the only one that can run it
+    if self.is_reserved() {
+      self.wake_up();
+      return;
+    }
+
+    // wake up sleeping processor, see WakeStrategy,
+    // in case current processor is busy,
+    // others need to run (steal) it
+    EXECUTOR.wake_up_on_push();
+  }
+
+  fn pop(&self, dest: &Worker<Task>, level: usize) -> Option<Task> {
+    EXECUTOR.steal_until_done(|| self.injectors()[level].steal_batch_and_pop(dest))
+  }
+
+  // global queues that this processor push to and pop from
+  fn injectors(&self) -> &[GlobalQueue; PRIORITY_LEVELS] {
+    match EXECUTOR.queue_topology {
+      QueueTopology::PerProcessor => &self.injectors,
+      QueueTopology::Shared => &EXECUTOR.injectors,
+    }
+  }
+}
+
+impl std::fmt::Debug for Processor {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    f.write_str(&format!("P({})", self.id))
+  }
+}
+
+impl Machine {
+  fn new(id: usize, stealer: Stealer<Task>, inherit: Stealer<Task>) -> Arc<Machine> {
+    #[cfg(test)]
+    tests::LIVE_MACHINES.fetch_add(1, Ordering::SeqCst);
+
+    Arc::new(Machine {
+      id,
+      stealer,
+      inherit,
+    })
+  }
+
+  // return None if no thread is available for the new machine,
+  // in that case the processor is not touched
+  fn move_processor_to_new_machine(p: &Processor, inherit: Stealer<Task>) -> Option<Arc<Machine>> {
+    // reserve the thread first, so we don't take over the processor
+    // when we can't run it
+    let slot = thread_pool::reserve()?;
+
+    let id = MACHINE_ID_COUNTER.fetch_add(1, Ordering::Relaxed);
+
+    // take over the processor, the released processor may be taken back by
+    // its idle machine, or stopped by shutdown, at the same time
+    let current = p.machine_id.load(Ordering::SeqCst);
+    if current == STOPPED_MACHINE
+      || p
+        .machine_id
+        .compare_exchange(current, id, Ordering::SeqCst, Ordering::SeqCst)
+        .is_err()
+    {
+      return None;
+    }
+    p.mark_nonblocking();
+
+    // each machine has its own rng, derived from the configured seed,
+    // so the scheduling decision is reproducible when the seed is fixed
+    let rng = Rng::new(config::get().seed ^ id as u64);
+
+    let worker = Worker::new_fifo();
+    let machine = Machine::new(id, worker.stealer(), inherit);
+
+    #[cfg(feature = "tracing")]
+    trace!("{:?} is created", machine);
+
+    {
+      let machine = machine.clone();
+
+      // this is safe because processor is never get dropped after created,
+      // it can be assume that it has static lifetime
+      let processor: &'static Processor = unsafe { transmute(p) };
+
+      slot.run(Box::new(move || match config::get().machine_panic_policy {
+        MachinePanicPolicy::Abort => abort_on_panic(move || machine.main(worker, processor, rng)),
+        MachinePanicPolicy::Replace => {
+          let m = machine.clone();
+          let main = AssertUnwindSafe(move || machine.main(worker, processor, rng));
+          if panic::catch_unwind(main).is_err() {
+            m.recover(processor);
+          }
+        }
+      }));
+    }
+
+    Some(machine)
+  }
+
+  // the machine is panicked, its local queue is already dropped,
+  // let the sysmon replace it, as if it is blocking
+  fn recover(&self, processor: &Processor) {
+    eprintln!(
+      "lelet: {:?} is panicked while holding {:?}, it will be replaced",
+      self, processor
+    );
+
+    if processor.machine_id.load(Ordering::Relaxed) == self.id {
+      processor.last_seen.store(0, Ordering::Relaxed);
+
+      // make sure the sysmon is not backing off
+      EXECUTOR.mark_busy();
+    }
+  }
+
+  // the new machine only inherit our local queue, and it may already be
+  // sleeping, push the tasks that we inherited back to the global queue, so
+  // they are not lost when we exit
+  fn hand_over_inherited(&self, processor: &Processor) {
+    loop {
+      match self.inherit.steal() {
+        Steal::Success(t) => {
+          let level = t.tag().priority as usize;
+          processor.push(t, level);
+        }
+        Steal::Empty => break,
+        Steal::Retry => {}
+      }
+    }
+  }
+
+  // release the processor after sleeping too long,
+  // see Builder::machine_idle_timeout,
+  // return false if the machine should keep running
+  fn exit_idle(&self, processor: &Processor) -> bool {
+    // we are the only one that can take tasks inherited from old machine
+    if !self.inherit.is_empty() {
+      return false;
+    }
+
+    if processor
+      .machine_id
+      .compare_exchange(self.id, NO_MACHINE, Ordering::SeqCst, Ordering::SeqCst)
+      .is_err()
+    {
+      // other machine is holding the processor
+      return true;
+    }
+
+    // shutdown or push may happen before we release the processor, and they
+    // don't know that the processor need a machine, take it back
+    if EXECUTOR.is_shutting_down() || processor.injectors().iter().any(|i| !i.is_empty()) {
+      return processor
+        .machine_id
+        .compare_exchange(NO_MACHINE, self.id, Ordering::SeqCst, Ordering::SeqCst)
+        .is_err();
+    }
+
+    #[cfg(feature = "tracing")]
+    trace!("{:?} is idle, releasing {:?}", self, processor);
+
+    true
+  }
+
+  fn main(&self, worker: Worker<Task>, processor: &Processor, mut rng: Rng) {
+    #[cfg(feature = "tracing")]
+    trace!("{:?} is running on {:?}", processor, self);
+
+    ON_MACHINE.with(|m| m.set(true));
+    defer! {
+      ON_MACHINE.with(|m| m.set(false));
+
+      // the thread is returned to the pool, it may not exit for a while
+      COMPLETED.with(Completions::flush);
+    }
+
+    #[cfg(feature = "steal-trace")]
+    metrics::set_thief(self.id, processor.id);
+
+    // initial task from old machine,
+    // or take them later, see Builder::inherit_ratio
+    // and Builder::initial_inherit_batch
+    match (EXECUTOR.inherit_ratio, EXECUTOR.initial_inherit_batch) {
+      (Some(_), _) => {}
+      (None, None) => while let Steal::Retry = self.inherit.steal_batch(&worker) {},
+      (None, Some(batch)) => {
+        let mut taken = 0;
+        while taken < batch {
+          match self.inherit.steal() {
+            Steal::Success(t) => {
+              worker.push(t);
+              taken += 1;
+            }
+            Steal::Empty => break,
+            Steal::Retry => {}
+          }
+        }
+      }
+    }
+
+    // number of runs since the last inherited task is taken
+    let mut inherit_counter = 0;
+
+    #[cfg(feature = "overhead")]
+    let mut stopwatch = metrics::Stopwatch::new();
+
+    // Number of runs in a row before the global queue is inspected.
+    //
+    // woken up tasks are always pushed to the back of the global queue,
+    // there is no lifo slot, so a task that keep waking itself can't jump
+    // ahead of the queued tasks, this only bound how long the local batch
+    // can delay them
+    const MAX_RUNS: u64 = 64;
+
+    let mut run_counter = 0;
+
+    'main: loop {
+      macro_rules! run_task {
+        ($task:ident, $source:ident) => {{
+          EXECUTOR.mark_dequeued(&$task);
+
+          #[cfg(feature = "metrics")]
+          processor.sources.$source.fetch_add(1, Ordering::Relaxed);
+
+          // the first task after parking, see metrics::wake_latency
+          #[cfg(feature = "metrics")]
+          match processor.woken_at.swap(0, Ordering::Relaxed) {
+            0 => {}
+            woken_at => metrics::WAKE_LATENCY.record(monotonic_us().saturating_sub(woken_at)),
+          }
+
+          // update the tag, so this task will be push to this processor again,
+          // or to the processor that the sysmon redirect us to
+          let hint = match processor.redirect.load(Ordering::Relaxed) {
+            usize::MAX => processor.id,
+            index => index,
+          };
+          $task.tag().schedule_hint.store(hint, Ordering::Relaxed);
+
+          #[cfg(any(feature = "tracing", feature = "event-log"))]
+          let task_id = $task.tag().id;
+
+          #[cfg(feature = "event-log")]
+          metrics::record_task_event(
+            task_id,
+            metrics::TaskEventKind::Dequeued {
+              machine: self.id,
+              processor: processor.id,
+            },
+          );
+
+          #[cfg(feature = "tracing")]
+          trace!(
+            "{} is running on {:?} with trace id {}",
+            TaskTag::string_rep(task_id),
+            processor,
+            $task.tag().get_trace_id()
+          );
+
+          // help sysmon before doing real task
+          EXECUTOR.sysmon_assist();
+          EXECUTOR.mark_busy();
+
+          // always assume the task is blocking
+          processor.mark_blocking();
+          {
+            #[cfg(feature = "overhead")]
+            stopwatch.scheduled();
+
+            #[cfg(any(feature = "cpu-time", feature = "event-log"))]
+            let started = Instant::now();
+
+            EXECUTOR.run($task);
+
+            #[cfg(feature = "cpu-time")]
+            processor
+              .run_ns
+              .fetch_add(started.elapsed().as_nanos() as u64, Ordering::Relaxed);
+
+            #[cfg(feature = "event-log")]
+            metrics::record_task_event(
+              task_id,
+              metrics::TaskEventKind::Ran {
+                duration: started.elapsed(),
+              },
+            );
+
+            #[cfg(feature = "overhead")]
+            stopwatch.ran();
+
+            // it is very crucial that we must exit this machine now when other machine holding
+            // the processor, so we don't mess up with the processor state
+            if processor.machine_id.load(Ordering::Relaxed) != self.id {
+              #[cfg(feature = "tracing")]
+              trace!(
+                "{:?} is no longer holding {:?}, blocking when executing {}",
+                self,
+                processor,
+                TaskTag::string_rep(task_id),
+              );
+              self.hand_over_inherited(processor);
+              return;
+            }
+          }
+          processor.mark_nonblocking();
+
+          run_counter += 1;
+          inherit_counter += 1;
+          continue 'main;
+        }};
+      }
+
+      macro_rules! get_tasks {
+        () => {{
+          run_counter = 0;
+          if let Some(task) = EXECUTOR.pop(processor.id, &worker) {
+            // we got a batch, wake up another processor to steal from us
+            // or pop the global queue, so a large backlog is quickly spread
+            // to all idle processors instead of hoarded by this one,
+            // the woken processor will do the same, chaining the wake up
+            if !worker.is_empty() && !processor.is_reserved() {
+              EXECUTOR.wake_up_one();
+            }
+            run_task!(task, global);
+          }
+        }};
+      }
+
+      if EXECUTOR.is_shutting_down() {
+        // tasks inherited from old machine is ours to drop (or drain) too
+        while let Some(t) = EXECUTOR.steal_until_done(|| self.inherit.steal()) {
+          worker.push(t);
+        }
+
+        // drain mode, run our own tasks, then the global queues, until they
+        // are empty, don't steal and don't sleep
+        if EXECUTOR.shutdown_drain.load(Ordering::SeqCst) {
+          if let Some(task) = worker.pop() {
+            run_task!(task, local);
+          }
+          get_tasks!();
+        }
+
+        processor.stop(&worker);
+        return;
+      }
+
+      if processor.is_retired() {
+        #[cfg(feature = "overhead")]
+        stopwatch.scheduled();
+
+        processor.park_retired(&worker);
+
+        #[cfg(feature = "overhead")]
+        stopwatch.idled();
+
+        continue 'main;
+      }
+
+      if EXECUTOR.is_paused() {
+        #[cfg(feature = "overhead")]
+        stopwatch.scheduled();
+
+        EXECUTOR.wait_resumed();
+
+        #[cfg(feature = "overhead")]
+        stopwatch.idled();
+
+        continue 'main;
+      }
+
+      macro_rules! get_inherited_tasks {
+        () => {{
+          inherit_counter = 0;
+          let steal = match EXECUTOR.inherit_ratio {
+            Some(_) => self.inherit.steal(),
+            None => self.inherit.steal_batch_and_pop(&worker),
+          };
+          if let Steal::Success(task) = steal {
+            run_task!(task, inherited);
+          }
+        }};
+      }
+
+      // the counter include the last inherited task run
+      if let Some(ratio) = EXECUTOR.inherit_ratio {
+        if inherit_counter > ratio {
+          get_inherited_tasks!();
+        }
+      }
+
+      if run_counter > MAX_RUNS {
+        // periodically check the source that is not prioritized,
+        // so it is not starved
+        match EXECUTOR.steal_order {
+          StealOrder::InheritFirst => get_tasks!(),
+          StealOrder::GlobalFirst => {
+            run_counter = 0;
+            get_inherited_tasks!();
+          }
+        }
+      }
+
+      // run all task in the worker
+      if let Some(task) = worker.pop() {
+        run_task!(task, local);
+      }
+
+      // at this point, the worker is empty
+
+      // 1. steal from old machine (in case some one accidentally push to it)
+      // 2. pop from global queue
+      //
+      // with inherit_ratio, inherited tasks are already interleaved above,
+      // take them here only when the global queue is empty
+      match (EXECUTOR.steal_order, EXECUTOR.inherit_ratio) {
+        (StealOrder::InheritFirst, None) => {
+          get_inherited_tasks!();
+          get_tasks!();
+        }
+        _ => {
+          get_tasks!();
+          get_inherited_tasks!();
+        }
+      }
+
+      // 3. steal from others, reserved processor only run its own tasks
+      if !processor.is_reserved() {
+        if let Some(task) = EXECUTOR.steal(processor.id, &worker, &mut rng) {
+          if !worker.is_empty() {
+            EXECUTOR.wake_up_one();
+          }
+          run_task!(task, stolen);
+        }
+      }
+
+      // 4.a. no more task for now, just sleep until waked up
+      #[cfg(feature = "overhead")]
+      stopwatch.scheduled();
+
+      if !processor.sleep() && self.exit_idle(processor) {
+        return;
+      }
+
+      #[cfg(feature = "overhead")]
+      stopwatch.idled();
+
+      // 4.b. just waked up, pop from global queue
+      get_tasks!();
+    }
+  }
+}
+
+impl std::fmt::Debug for Machine {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    f.write_str(&format!("M({})", self.id))
+  }
+}
+
+#[cfg(any(feature = "tracing", test))]
+impl Drop for Machine {
+  fn drop(&mut self) {
+    #[cfg(feature = "tracing")]
+    trace!("{:?} is destroyed", self);
+
+    #[cfg(test)]
+    tests::LIVE_MACHINES.fetch_sub(1, Ordering::SeqCst);
+  }
+}
+
+// force the executor to be initialized
+pub(crate) fn start() {
+  Lazy::force(&EXECUTOR);
+}
+
+/// Handle to a spawned task.
+///
+/// Await it to get the output of the task. Dropping the handle will not cancel
+/// the task, the task is detached and keep running.
+pub struct JoinHandle<T> {
+  inner: async_task::JoinHandle<Result<T, JoinError>, TaskTag>,
+
+  // stop waiting at the inherited deadline, only for spawn_blocking
+  deadline: Option<Sleep>,
+}
+
+impl<T> JoinHandle<T> {
+  /// Id of the task, unique for the lifetime of the process.
+  pub fn task_id(&self) -> usize {
+    self.inner.tag().id
+  }
+
+  /// Index of the processor that most recently run the task
+  /// (or the processor it is migrated to, see [`migrate`]).
+  ///
+  /// Return `None` if the task is never run by the executor yet
+  /// (or it is spawned by [`spawn_blocking`]).
+  ///
+  /// This only reflect the most recent run, the task is likely (but not
+  /// guaranteed) to be run on the same processor on the next run.
+  ///
+  /// [`spawn_blocking`]: fn.spawn_blocking.html
+  /// [`migrate`]: #method.migrate
+  pub fn last_processor(&self) -> Option<usize> {
+    self.inner.tag().get_last_processor()
+  }
+
+  /// Total time spent running (polling) the task so far.
+  ///
+  /// Only available with `cpu-time` feature, see
+  /// [`metrics::processor_cpu_time`].
+  ///
+  /// [`metrics::processor_cpu_time`]: metrics/fn.processor_cpu_time.html
+  #[cfg(feature = "cpu-time")]
+  pub fn cpu_time(&self) -> Duration {
+    Duration::from_nanos(self.inner.tag().cpu_ns.load(Ordering::Relaxed))
+  }
+
+  /// Move the task to the least loaded processor, see [`migrate_current`].
+  ///
+  /// [`migrate_current`]: fn.migrate_current.html
+  pub fn migrate(&self) {
+    migrate(self.inner.tag());
+  }
+
+  /// Block the current thread until the task is complete, and return its
+  /// output.
+  ///
+  /// This is for synchronous code, inside a task, await the handle instead.
+  ///
+  /// Panic if called from the executor thread (see [`in_executor`]), because
+  /// it would block the processor (and the tasks queued on it) until the
+  /// sysmon notice it.
+  ///
+  /// [`in_executor`]: fn.in_executor.html
+  pub fn join(self) -> Result<T, JoinError> {
+    assert!(
+      !in_executor(),
+      "JoinHandle::join is called from the executor thread, await the handle instead"
+    );
+    block_on(self)
+  }
+}
+
+impl<T> Future for JoinHandle<T> {
+  type Output = Result<T, JoinError>;
+
+  fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+    let this = &mut *self;
+    match Pin::new(&mut this.inner).poll(cx) {
+      Poll::Ready(Some(output)) => return Poll::Ready(output),
+      Poll::Ready(None) => {
+        let tag = this.inner.tag();
+        return Poll::Ready(Err(JoinError::cancelled(tag.id, tag.location)));
+      }
+      Poll::Pending => {}
+    }
+
+    match &mut this.deadline {
+      Some(sleep) => match Pin::new(sleep).poll(cx) {
+        Poll::Ready(()) => {
+          let tag = this.inner.tag();
+          Poll::Ready(Err(JoinError::cancelled(tag.id, tag.location)))
+        }
+        Poll::Pending => Poll::Pending,
+      },
+      None => Poll::Pending,
+    }
+  }
+}
+
+impl<T> std::fmt::Debug for JoinHandle<T> {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    f.write_str("JoinHandle")
+  }
+}
+
+/// Run the task.
+///
+/// It's okay to do blocking operation in the task, the executor will detect
+/// this and scale the pool.
+#[track_caller]
+pub fn spawn<F, T>(f: F) -> JoinHandle<T>
+where
+  F: Future<Output = T> + Send + 'static,
+  T: Send + 'static,
+{
+  let tag = TaskTag::new(task_size::<F>(), Location::caller(), inherited_priority());
+  spawn_tag(f, tag)
+}
+
+/// Same as [`spawn`], but with the given priority.
+///
+/// Queued task with higher priority is run first, but a task that wait too
+/// long is promoted, see [`Builder::priority_aging`].
+///
+/// Tasks spawned from inside the task (by [`spawn`] and the other spawn
+/// functions that don't take a priority) inherit this priority, so the whole
+/// fan-out of a high priority task stay high priority, see
+/// [`Builder::inherit_priority`]. Spawning with this function again override
+/// the inherited priority.
+///
+/// [`spawn`]: fn.spawn.html
+/// [`Builder::priority_aging`]: struct.Builder.html#method.priority_aging
+/// [`Builder::inherit_priority`]: struct.Builder.html#method.inherit_priority
+#[track_caller]
+pub fn spawn_with_priority<F, T>(priority: Priority, f: F) -> JoinHandle<T>
+where
+  F: Future<Output = T> + Send + 'static,
+  T: Send + 'static,
+{
+  let tag = TaskTag::new(task_size::<F>(), Location::caller(), priority);
+  spawn_tag(f, tag)
+}
+
+/// Same as [`spawn`], but with a hint of how expensive the task is, relative
+/// to a normal task (that has cost 1).
+///
+/// The executor sum the cost of queued tasks on each processor, and use it
+/// instead of the number of tasks when placing new task, stealing, and
+/// rebalancing (see [`Builder::rebalance`]), so one expensive task is not
+/// treated the same as one cheap task.
+///
+/// This is only a hint for load balancing, it is not a hard schedule, the
+/// task is not run earlier or later because of its cost.
+///
+/// [`spawn`]: fn.spawn.html
+/// [`Builder::rebalance`]: struct.Builder.html#method.rebalance
+#[track_caller]
+pub fn spawn_with_cost<F, T>(cost: usize, f: F) -> JoinHandle<T>
+where
+  F: Future<Output = T> + Send + 'static,
+  T: Send + 'static,
+{
+  let mut tag = TaskTag::new(task_size::<F>(), Location::caller(), inherited_priority());
+  tag.cost = cost;
+  spawn_tag(f, tag)
+}
+
+/// Same as [`spawn`], but the task is cancelled when `deadline` passed.
+///
+/// The deadline is propagated through the task tree:
+/// - tasks spawned from inside the task inherit the deadline, so they are
+///   cancelled at the same time, a child can only have earlier deadline (by
+///   using this function again), never later
+/// - [`timeout`] created inside the task give up at the deadline at the latest,
+///   so the task can still handle [`Elapsed`] and complete with its own result
+///   in the same poll, otherwise it is cancelled
+/// - [`sleep`] is not shortened, the task is just cancelled while sleeping
+///
+/// Cancelled task is dropped at its yield point, not in the middle of a poll,
+/// and its [`JoinHandle`] resolve to [`JoinError`] with
+/// [`is_cancelled`] returning true. Function of [`spawn_blocking`] can't be
+/// interrupted, but it is not started after the deadline, and its handle
+/// stop waiting at the deadline.
+///
+/// Use [`current_deadline`] to get the deadline of the current task.
+///
+/// [`spawn`]: fn.spawn.html
+/// [`timeout`]: fn.timeout.html
+/// [`Elapsed`]: struct.Elapsed.html
+/// [`sleep`]: fn.sleep.html
+/// [`JoinHandle`]: struct.JoinHandle.html
+/// [`JoinError`]: struct.JoinError.html
+/// [`is_cancelled`]: struct.JoinError.html#method.is_cancelled
+/// [`spawn_blocking`]: fn.spawn_blocking.html
+/// [`current_deadline`]: fn.current_deadline.html
+#[track_caller]
+pub fn spawn_with_deadline<F, T>(deadline: Instant, f: F) -> JoinHandle<T>
+where
+  F: Future<Output = T> + Send + 'static,
+  T: Send + 'static,
+{
+  let mut tag = TaskTag::new(task_size::<F>(), Location::caller(), inherited_priority());
+  tag.deadline = Some(match tag.deadline {
+    Some(inherited) => std::cmp::min(inherited, deadline),
+    None => deadline,
+  });
+  spawn_tag(f, tag)
+}
+
+/// Same as [`spawn`], but [`shutdown`] wait until the task is done before
+/// stopping the executor.
+///
+/// Use this for work that must not be lost, e.g. flushing a write-ahead log.
+/// Tasks spawned by the critical task are not critical, unless they are also
+/// spawned with this. The task still can be cancelled by other means (e.g.
+/// [`spawn_with_deadline`]), [`shutdown`] only wait until it is dropped.
+///
+/// Spawning critical task after [`shutdown`] is started is rejected like
+/// [`spawn`]. Calling [`shutdown`] from inside a critical task wait forever.
+///
+/// [`spawn`]: fn.spawn.html
+/// [`shutdown`]: fn.shutdown.html
+/// [`spawn_with_deadline`]: fn.spawn_with_deadline.html
+#[track_caller]
+pub fn spawn_critical<F, T>(f: F) -> JoinHandle<T>
+where
+  F: Future<Output = T> + Send + 'static,
+  T: Send + 'static,
+{
+  // hold the count until the future is dropped (completed or cancelled)
+  struct Guard;
+
+  impl Drop for Guard {
+    fn drop(&mut self) {
+      let mut critical = EXECUTOR.critical.lock().unwrap();
+      *critical -= 1;
+      if *critical == 0 {
+        EXECUTOR.critical_cond.notify_all();
+      }
+    }
+  }
+
+  *EXECUTOR.critical.lock().unwrap() += 1;
+  let guard = Guard;
+
+  let tag = TaskTag::new(task_size::<F>(), Location::caller(), inherited_priority());
+  spawn_tag(
+    async move {
+      let _guard = guard;
+      f.await
+    },
+    tag,
+  )
+}
+
+/// Same as [`spawn`], but attach `meta` to the task.
+///
+/// The metadata can be retrieved from inside the task with
+/// [`current_task_meta`], and it is shown in [`task_dump`] (with `registry`
+/// feature). It is not inherited by the tasks spawned by this task.
+///
+/// [`spawn`]: fn.spawn.html
+/// [`current_task_meta`]: fn.current_task_meta.html
+/// [`task_dump`]: fn.task_dump.html
+#[track_caller]
+pub fn spawn_tagged<M, F, T>(meta: M, f: F) -> JoinHandle<T>
+where
+  M: Any + Send + Sync + std::fmt::Debug,
+  F: Future<Output = T> + Send + 'static,
+  T: Send + 'static,
+{
+  let mut tag = TaskTag::new(task_size::<F>(), Location::caller(), inherited_priority());
+  tag.meta = Some(Arc::new(meta));
+  spawn_tag(f, tag)
+}
+
+/// Metadata of the current task, attached by [`spawn_tagged`].
+///
+/// Return `None` if it is not called inside a task, the task has no
+/// metadata, or the metadata is not of type `M`.
+///
+/// [`spawn_tagged`]: fn.spawn_tagged.html
+pub fn current_task_meta<M: Any + Send + Sync>() -> Option<Arc<M>> {
+  with_current(|t| t.and_then(|t| t.meta.clone()))
+    .and_then(|meta| meta.into_any().downcast::<M>().ok())
+}
+
+/// Same as [`spawn`], but not generic over the future type.
+///
+/// Every call to generic [`spawn`] with different future type will generate
+/// its own copy of the task code, use this if that is a concern for the
+/// binary size, at the cost of extra allocation.
+///
+/// [`spawn`]: fn.spawn.html
+#[track_caller]
+pub fn spawn_boxed(f: Pin<Box<dyn Future<Output = ()> + Send + 'static>>) -> JoinHandle<()> {
+  // count the boxed future too, not just the box pointer
+  let size = task_size::<()>() + std::mem::size_of_val(&*f);
+  spawn_tag(
+    f,
+    TaskTag::new(size, Location::caller(), inherited_priority()),
+  )
+}
+
+pub(crate) fn spawn_tag<F, T>(f: F, tag: TaskTag) -> JoinHandle<T>
+where
+  F: Future<Output = T> + Send + 'static,
+  T: Send + 'static,
+{
+  let alive = begin_spawn();
+  let f = async move {
+    let _alive = alive;
+    f.await
+  };
+  let (task, handle) = spawn_raw(f, tag, push_task);
+  start_task(task);
+  handle
+}
+
+// count the task until its future is dropped, see pending_tasks
+struct Alive;
+
+impl Drop for Alive {
+  fn drop(&mut self) {
+    complete_counted();
+  }
+}
+
+// the non generic parts of spawn_tag are kept out of line, so they are
+// compiled once, not for every future type that is spawned
+#[inline(never)]
+fn begin_spawn() -> Alive {
+  spawn_counted();
+  if EXECUTOR.shutdown_on_idle.is_some() {
+    EXECUTOR.spawned_any.store(true, Ordering::SeqCst);
+  }
+  Alive
+}
+
+#[inline(never)]
+fn start_task(task: Task) {
+  if EXECUTOR.is_shutting_down() {
+    reject(task);
+    return;
+  }
+
+  // register after spawn, the tag is not moved anymore
+  #[cfg(feature = "registry")]
+  registry::register(task.tag());
+
+  #[cfg(feature = "event-log")]
+  metrics::record_task_event(task.tag().id, metrics::TaskEventKind::Created);
+
+  task.schedule();
+}
+
+// fn item instead of closure, so it is the same type for all futures
+fn push_task(task: Task) {
+  EXECUTOR.push(task);
+}
+
+// wrap the future and create the task, without scheduling it,
+// schedule is called every time the task is woken
+pub(crate) fn spawn_raw<F, T, S>(f: F, tag: TaskTag, schedule: S) -> (Task, JoinHandle<T>)
+where
+  F: Future<Output = T> + Send + 'static,
+  T: Send + 'static,
+  S: Fn(Task) + Send + Sync + 'static,
+{
+  // separate branch, so there is no boxing without a wrapper
+  let (task, handle) = match &config::get().task_wrapper {
+    Some(wrapper) => {
+      let f = wrap(wrap_with(f, wrapper), &tag);
+      async_task::spawn(f, schedule, tag)
+    }
+    None => {
+      let f = wrap(f, &tag);
+      async_task::spawn(f, schedule, tag)
+    }
+  };
+  (
+    task,
+    JoinHandle {
+      inner: handle,
+      deadline: None,
+    },
+  )
+}
+
+// run the future through the user wrapper, see Builder::task_wrapper,
+// the wrapper only see `()`, so the output is passed through a slot
+fn wrap_with<F, T>(f: F, wrapper: &config::TaskWrapper) -> impl Future<Output = T>
+where
+  F: Future<Output = T> + Send + 'static,
+  T: Send + 'static,
+{
+  let slot = Arc::new(Mutex::new(None));
+  let inner = {
+    let slot = slot.clone();
+    Box::pin(async move {
+      let output = f.await;
+      *slot.lock().unwrap() = Some(output);
+    })
+  };
+  let wrapped = wrapper(inner);
+
+  async move {
+    wrapped.await;
+    let output = slot.lock().unwrap().take();
+    output.expect("task wrapper completed without running the task to completion")
+  }
+}
+
+// same as spawn_raw, but the future doesn't need to be Send, the task must be
+// run and dropped on the current thread only, see spawn_dedicated
+pub(crate) fn spawn_raw_local<F, T, S>(f: F, tag: TaskTag, schedule: S) -> (Task, JoinHandle<T>)
+where
+  F: Future<Output = T> + 'static,
+  T: Send + 'static,
+  S: Fn(Task) + Send + Sync + 'static,
+{
+  let f = wrap(f, &tag);
+  let (task, handle) = async_task::spawn_local(f, schedule, tag);
+  (
+    task,
+    JoinHandle {
+      inner: handle,
+      deadline: None,
+    },
+  )
+}
+
+fn wrap<F: Future>(f: F, tag: &TaskTag) -> impl Future<Output = Result<F::Output, JoinError>> {
+  // the task is live until its future is dropped, see block_on_join
+  let scope = tag.scope.as_ref().map(|s| s.enter());
+  let f = async move {
+    let _scope = scope;
+    f.await
+  };
+
+  // count only the wakers used by the future, not the ones used by the
+  // executor itself, like the deadline timer
+  #[cfg(feature = "waker-stats")]
+  let f = CountWakes::new(f, tag.wake_counts.clone());
+
+  #[cfg(feature = "cpu-time")]
+  let f = metrics::CpuTimed::new(f, tag.cpu_ns.clone(), tag.meta.clone());
+
+  let catch = config::get().panic_policy == PanicPolicy::Catch;
+  let f = CatchUnwind::new(f, tag.id, tag.location, catch);
+
+  #[cfg(feature = "registry")]
+  let f = registry::Cancellable::new(f, tag.cancel.clone(), tag.id, tag.location);
+
+  let f = WithDeadline {
+    future: f,
+    deadline: tag.deadline.map(sleep_until),
+    task_id: tag.id,
+    location: tag.location,
+  };
+
+  // outermost, so deadline cancellation count as completion too
+  #[cfg(feature = "order-trace")]
+  let f = metrics::Completion::new(f, tag.id, tag.location);
+
+  #[cfg(feature = "event-log")]
+  let f = metrics::Logged::new(f, tag.id);
+
+  f
+}
+
+// cancel the task when the deadline passed, see spawn_with_deadline
+struct WithDeadline<F> {
+  future: F,
+  deadline: Option<Sleep>,
+  task_id: usize,
+  location: &'static Location<'static>,
+}
+
+impl<F, T> Future for WithDeadline<F>
+where
+  F: Future<Output = Result<T, JoinError>>,
+{
+  type Output = Result<T, JoinError>;
+
+  fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+    // this is safe because we never move future out of self
+    let this = unsafe { self.get_unchecked_mut() };
+    let future = unsafe { Pin::new_unchecked(&mut this.future) };
+
+    // poll the future first, so it can still complete on the same poll
+    // that its (clamped) timeout return Elapsed
+    if let Poll::Ready(output) = future.poll(cx) {
+      return Poll::Ready(output);
+    }
+
+    match &mut this.deadline {
+      Some(sleep) => match Pin::new(sleep).poll(cx) {
+        Poll::Ready(()) => Poll::Ready(Err(JoinError::cancelled(this.task_id, this.location))),
+        Poll::Pending => Poll::Pending,
+      },
+      None => Poll::Pending,
+    }
+  }
+}
+
+/// Run the blocking function in the thread pool, outside of the executor.
+///
+/// Normally it is okay to do blocking operation inside a task, but this is
+/// useful to run long blocking operation without disturbing the processor.
+///
+/// Panic inside the function is always caught and reported as [`JoinError`],
+/// regardless of [`PanicPolicy`].
+///
+/// When called from a task with deadline (see [`spawn_with_deadline`]), the
+/// function is not run if the deadline passed before a thread pick it up,
+/// and the [`JoinHandle`] give cancelled [`JoinError`] at the deadline, so the
+/// awaiting task can still handle it in the same poll, before it is cancelled
+/// itself. The running function can't be interrupted, it keep running in the
+/// thread pool until it return by itself, only the awaiter is freed.
+///
+/// [`JoinError`]: struct.JoinError.html
+/// [`PanicPolicy`]: enum.PanicPolicy.html
+/// [`spawn_with_deadline`]: fn.spawn_with_deadline.html
+/// [`JoinHandle`]: struct.JoinHandle.html
+#[track_caller]
+pub fn spawn_blocking<F, T>(f: F) -> JoinHandle<T>
+where
+  F: FnOnce() -> T + Send + 'static,
+  T: Send + 'static,
+{
+  let tag = TaskTag::new(task_size::<F>(), Location::caller(), Priority::Normal);
+  let deadline = tag.deadline;
+  let f = CatchUnwind::new(async move { f() }, tag.id, tag.location, true);
+  let (task, handle) = async_task::spawn(
+    f,
+    |t| {
+      thread_pool::spawn_box(Box::new(move || {
+        // the deadline passed while waiting for a thread, just cancel it
+        if t
+          .tag()
+          .deadline
+          .map(|d| Instant::now() >= d)
+          .unwrap_or(false)
+        {
+          drop(t);
+          return;
+        }
+        run_as_current(t);
+      }))
+    },
+    tag,
+  );
+  let handle = JoinHandle {
+    inner: handle,
+    deadline: deadline.map(sleep_until),
+  };
+
+  if EXECUTOR.is_shutting_down() {
+    reject(task);
+    return handle;
+  }
+
+  #[cfg(feature = "registry")]
+  registry::register(task.tag());
+
+  task.schedule();
+  handle
+}
+
+// spawn after shutdown (e.g. from destructor of a task that is dropped by
+// shutdown) is not run, the JoinHandle is resolved as cancelled
+pub(crate) fn reject(task: Task) {
+  eprintln!(
+    "lelet: task spawned at {} is rejected, the executor is shutting down",
+    task.tag().location
+  );
+  drop(task);
+}
+
+/// Same as [`spawn_blocking`], but give up waiting after `duration`.
+///
+/// Arbitrary blocking function can't be interrupted safely, so when the
+/// timeout is elapsed, the function is still running in the thread pool
+/// until it return by itself (or forever), only the awaiting task is freed.
+/// Like [`timeout`], it give [`Elapsed`] at the deadline of the current task
+/// at the latest.
+///
+/// [`spawn_blocking`]: fn.spawn_blocking.html
+/// [`timeout`]: fn.timeout.html
+/// [`Elapsed`]: struct.Elapsed.html
+#[track_caller]
+pub fn spawn_blocking_timeout<F, T>(duration: Duration, f: F) -> Timeout<JoinHandle<T>>
+where
+  F: FnOnce() -> T + Send + 'static,
+  T: Send + 'static,
+{
+  // the timeout already stop at the deadline, and give Elapsed instead
+  let mut handle = spawn_blocking(f);
+  handle.deadline = None;
+  timeout(duration, handle)
+}
+
+/// Run the blocking function in the thread pool, and wait for its result.
+///
+/// This is the building block to wrap any blocking call (e.g. DNS resolution,
+/// synchronous database driver) as a future, without occupying a processor,
+/// e.g. `unblock(move || addr.to_socket_addrs()).await`.
+///
+/// Unlike [`spawn_blocking`], that return a [`JoinHandle`] to be awaited
+/// (or detached) later, this return the function result directly, and panic
+/// inside the function is propagated to the awaiting task. The function is
+/// started immediately, not when the future is first polled, and dropping the
+/// future doesn't stop it, see [`spawn_blocking_timeout`].
+///
+/// # Panics
+///
+/// Awaiting the future panics if the function is never run because it is
+/// called after [`shutdown`], use [`spawn_blocking`] to handle that case
+/// as cancelled [`JoinError`] instead.
+///
+/// [`spawn_blocking`]: fn.spawn_blocking.html
+/// [`JoinHandle`]: struct.JoinHandle.html
+/// [`spawn_blocking_timeout`]: fn.spawn_blocking_timeout.html
+/// [`shutdown`]: fn.shutdown.html
+/// [`JoinError`]: struct.JoinError.html
+#[track_caller]
+pub fn unblock<F, T>(f: F) -> Unblock<T>
+where
+  F: FnOnce() -> T + Send + 'static,
+  T: Send + 'static,
+{
+  Unblock {
+    handle: spawn_blocking(f),
+  }
+}
+
+/// Future returned by [`unblock`].
+///
+/// # Panics
+///
+/// Polling it panics if the function is rejected by [`shutdown`], see
+/// [`unblock`].
+///
+/// [`unblock`]: fn.unblock.html
+/// [`shutdown`]: fn.shutdown.html
+pub struct Unblock<T> {
+  handle: JoinHandle<T>,
+}
+
+impl<T> Future for Unblock<T> {
+  type Output = T;
+
+  fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<T> {
+    match Pin::new(&mut self.handle).poll(cx) {
+      Poll::Ready(Ok(output)) => Poll::Ready(output),
+      Poll::Ready(Err(err)) if err.is_panic() => panic::resume_unwind(err.into_panic()),
+      // the deadline passed, this task is cancelled in the same poll,
+      // see spawn_blocking
+      Poll::Ready(Err(_))
+        if current_deadline()
+          .map(|d| Instant::now() >= d)
+          .unwrap_or(false) =>
+      {
+        Poll::Pending
+      }
+      // only rejected by shutdown, there is no output to give
+      Poll::Ready(Err(err)) => panic!("unblock: {}", err),
+      Poll::Pending => Poll::Pending,
+    }
+  }
+}
+
+impl<T> std::fmt::Debug for Unblock<T> {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    f.write_str("Unblock")
+  }
+}
+
+/// Run the task, unless the queued tasks memory limit is reached, or the
+/// executor is shutting down (see [`runtime_state`]).
+///
+/// See [`Builder::max_queued_memory`].
+///
+/// [`Builder::max_queued_memory`]: struct.Builder.html#method.max_queued_memory
+/// [`runtime_state`]: fn.runtime_state.html
+#[track_caller]
+pub fn try_spawn<F, T>(f: F) -> Result<JoinHandle<T>, String>
+where
+  F: Future<Output = T> + Send + 'static,
+  T: Send + 'static,
+{
+  if runtime_state() != RuntimeState::Running {
+    return Err("executor is shutting down".to_string());
+  }
+
+  let size = task_size::<F>();
+  let queued = EXECUTOR.queued_memory.load(Ordering::Relaxed);
+  if queued.saturating_add(size) > EXECUTOR.max_queued_memory {
+    return Err(format!(
+      "queued tasks memory limit reached ({} bytes)",
+      EXECUTOR.max_queued_memory
+    ));
+  }
+
+  let tag = TaskTag::new(size, Location::caller(), inherited_priority());
+  Ok(spawn_tag(f, tag))
+}
+
+/// Number of tasks that are queued (waiting to run) on each processor.
+pub fn queued_tasks() -> Vec<usize> {
+  EXECUTOR
+    .processors
+    .iter()
+    .map(|p| p.queued.load(Ordering::Relaxed))
+    .collect()
+}
+
+/// Number of tasks that are spawned and not completed yet, including the
+/// queued, running and waiting (e.g. for a timer) ones.
+///
+/// A task is counted until it is completed or cancelled (its future is
+/// dropped). Tasks of [`spawn_blocking`] and [`spawn_dedicated`] are not
+/// counted.
+///
+/// With [`TaskCount::Batched`] (the default), this can be higher than the
+/// actual number while the machines are busy, see [`Builder::task_count`].
+///
+/// [`spawn_blocking`]: fn.spawn_blocking.html
+/// [`spawn_dedicated`]: fn.spawn_dedicated.html
+/// [`TaskCount::Batched`]: enum.TaskCount.html#variant.Batched
+/// [`Builder::task_count`]: struct.Builder.html#method.task_count
+pub fn pending_tasks() -> usize {
+  EXECUTOR.alive_tasks.load(Ordering::SeqCst)
+}
+
+/// How long each processor has been running its current task (poll), in
+/// milliseconds, `0` if it is not running a task.
+///
+/// A value that keep growing means the task is blocking, once it pass the
+/// blocking threshold, the sysmon hand the processor to a new machine (see
+/// [`Builder::blocking_threshold`]). A processor waiting for such a
+/// replacement (e.g. its idle machine released it while it still have tasks)
+/// show the time since the executor started.
+///
+/// [`Builder::blocking_threshold`]: struct.Builder.html#method.blocking_threshold
+pub fn processor_last_seen_ms() -> Vec<u64> {
+  let now = monotonic_ms();
+  EXECUTOR
+    .processors
+    .iter()
+    .map(|p| match p.get_last_seen() {
+      u64::MAX => 0,
+      last_seen => now.saturating_sub(last_seen),
+    })
+    .collect()
+}
+
+/// Snapshot of a processor, see [`topology`].
+///
+/// [`topology`]: fn.topology.html
+#[derive(Clone, Debug)]
+pub struct ProcessorInfo {
+  pub id: usize,
+
+  /// Machine (thread) that currently hold the processor, `None` if it is
+  /// released by its idle machine, see [`Builder::machine_idle_timeout`].
+  ///
+  /// [`Builder::machine_idle_timeout`]: struct.Builder.html#method.machine_idle_timeout
+  pub machine_id: Option<usize>,
+
+  /// The machine is parked, waiting for new tasks.
+  pub sleeping: bool,
+
+  /// Number of tasks queued on the processor, same as [`queued_tasks`].
+  ///
+  /// [`queued_tasks`]: fn.queued_tasks.html
+  pub queued: usize,
+
+  /// See [`retire_processor`].
+  ///
+  /// [`retire_processor`]: fn.retire_processor.html
+  pub retired: bool,
+
+  /// See [`reserve_processor`].
+  ///
+  /// [`reserve_processor`]: fn.reserve_processor.html
+  pub reserved: bool,
+
+  /// How many times the processor currently spin before parking, adapted
+  /// within [`Builder::sleep_spins`].
+  ///
+  /// [`Builder::sleep_spins`]: struct.Builder.html#method.sleep_spins
+  pub spin_limit: usize,
+}
+
+/// Snapshot of which machine hold each processor, for diagnostic.
+///
+/// The fields are read one by one without synchronization, so the snapshot
+/// may show transient state, e.g. while a blocking machine is being replaced.
+pub fn topology() -> Vec<ProcessorInfo> {
+  EXECUTOR
+    .processors
+    .iter()
+    .map(|p| ProcessorInfo {
+      id: p.id,
+      machine_id: match p.machine_id.load(Ordering::Relaxed) {
+        NO_MACHINE | STOPPED_MACHINE | SPARE_MACHINE => None,
+        id => Some(id),
+      },
+      sleeping: p.sleeping.load(Ordering::Relaxed),
+      queued: p.queued.load(Ordering::Relaxed),
+      retired: p.is_retired(),
+      reserved: p.is_reserved(),
+      spin_limit: p.spin_limit.load(Ordering::Relaxed),
+    })
+    .collect()
+}
+
+// total time spent running tasks on each processor, in nanoseconds
+#[cfg(feature = "cpu-time")]
+pub(crate) fn processor_run_ns() -> Vec<u64> {
+  EXECUTOR
+    .processors
+    .iter()
+    .map(|p| p.run_ns.load(Ordering::Relaxed))
+    .collect()
+}
+
+// where the tasks run by each processor come from
+#[cfg(feature = "metrics")]
+pub(crate) fn processor_task_sources() -> Vec<metrics::TaskSources> {
+  EXECUTOR
+    .processors
+    .iter()
+    .map(|p| p.sources.snapshot())
+    .collect()
+}
+
+/// Estimated memory held by queued tasks, in bytes.
+///
+/// The estimation only count the size of the future itself, memory allocated
+/// by the future (e.g. a `Vec` inside it) is not counted.
+pub fn queued_memory() -> usize {
+  EXECUTOR.queued_memory.load(Ordering::Relaxed)
+}
+
+/// Retire the processor, reducing the parallelism of the executor.
+///
+/// No new task will be pushed to retired processor, tasks that prefer it
+/// will be moved to other processors. The machine holding it will move its
+/// queued tasks to other processors and park until [`restore_processor`]
+/// is called. Task that is currently running on it is not interrupted.
+///
+/// Return error if the index is invalid, the processor is already retired
+/// or reserved (see [`reserve_processor`]), or it is the last processor that
+/// is not retired or reserved.
+///
+/// [`restore_processor`]: fn.restore_processor.html
+/// [`reserve_processor`]: fn.reserve_processor.html
+pub fn retire_processor(index: usize) -> Result<(), String> {
+  let p = EXECUTOR
+    .processors
+    .get(index)
+    .ok_or_else(|| format!("invalid processor index {}", index))?;
+
+  if p.is_reserved() {
+    return Err(format!("processor {} is reserved", index));
+  }
+  if !EXECUTOR
+    .processors
+    .iter()
+    .any(|q| q.id != index && q.accepts_tasks())
+  {
+    return Err("cannot retire the last processor that is not reserved".to_string());
+  }
+
+  if p
+    .retired
+    .compare_exchange(false, true, Ordering::Relaxed, Ordering::Relaxed)
+    .is_err()
+  {
+    return Err(format!("processor {} is already retired", index));
+  }
+
+  let last = EXECUTOR
+    .active_processors
+    .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| {
+      if n > 1 {
+        Some(n - 1)
+      } else {
+        None
+      }
+    })
+    .is_err();
+  if last {
+    p.retired.store(false, Ordering::Relaxed);
+    return Err("cannot retire the last processor".to_string());
+  }
+
+  Ok(())
+}
+
+/// Return the retired processor to the executor.
+///
+/// Return error if the index is invalid, the processor is not retired, or it
+/// is a spare processor that is not added yet (see [`add_processor`]).
+///
+/// [`add_processor`]: fn.add_processor.html
+pub fn restore_processor(index: usize) -> Result<(), String> {
+  let p = EXECUTOR
+    .processors
+    .get(index)
+    .ok_or_else(|| format!("invalid processor index {}", index))?;
+
+  if p.is_spare() {
+    return Err(format!("processor {} is spare, see add_processor", index));
+  }
+
+  {
+    let _lock = p.restore_lock.lock().unwrap();
+    if !p.retired.swap(false, Ordering::Relaxed) {
+      return Err(format!("processor {} is not retired", index));
+    }
+    EXECUTOR.active_processors.fetch_add(1, Ordering::Relaxed);
+  }
+  p.restore_cond.notify_all();
+
+  Ok(())
+}
+
+/// Add a processor to the executor, increasing its parallelism, e.g. when
+/// the container CPU quota is increased.
+///
+/// This restore the first retired processor, either one retired by
+/// [`retire_processor`], or a spare processor (see
+/// [`Builder::max_processors`]), which get its machine thread here. Return
+/// its index.
+///
+/// Return error if there is no retired processor, the processor count can't
+/// grow past [`Builder::max_processors`], or no thread is available for the
+/// spare processor (see [`Builder::max_threads`]).
+///
+/// [`retire_processor`]: fn.retire_processor.html
+/// [`Builder::max_processors`]: struct.Builder.html#method.max_processors
+/// [`Builder::max_threads`]: struct.Builder.html#method.max_threads
+pub fn add_processor() -> Result<usize, String> {
+  // retry if it is restored or added concurrently
+  loop {
+    let p = EXECUTOR
+      .processors
+      .iter()
+      .find(|p| p.is_retired())
+      .ok_or_else(|| {
+        format!(
+          "all {} processors are in use, see Builder::max_processors",
+          EXECUTOR.processors.len()
+        )
+      })?;
+
+    if p.is_spare() && !start_spare_processor(p)? {
+      continue;
+    }
+    if restore_processor(p.id).is_ok() {
+      return Ok(p.id);
+    }
+  }
+}
+
+// give the spare processor its machine, it start as retired, return false if
+// other thread already did it
+fn start_spare_processor(p: &Processor) -> Result<bool, String> {
+  // the machine is swapped the same way as the sysmon replacement, don't run
+  // at the same time with it
+  while EXECUTOR
+    .check_running
+    .compare_exchange(false, true, Ordering::Relaxed, Ordering::Relaxed)
+    .is_err()
+  {
+    thread::yield_now();
+  }
+  defer! {
+    EXECUTOR.check_running.store(false, Ordering::Relaxed);
   }
 
-  fn get_last_seen(&self) -> u64 {
-    self.last_seen.load(Ordering::Relaxed)
+  // the placeholder has nothing to inherit
+  let inherit = EXECUTOR.with_machine(p.id, |m| m.stealer.clone());
+  let new = match Machine::move_processor_to_new_machine(p, inherit) {
+    Some(new) => new,
+    None if p.is_spare() => {
+      return Err(format!(
+        "no thread available for processor {}, see Builder::max_threads",
+        p.id
+      ))
+    }
+    None => return Ok(false),
+  };
+
+  let new = Arc::into_raw(new) as *mut Machine;
+  let old = EXECUTOR.machines[p.id].swap(new, Ordering::AcqRel);
+  let old = unsafe { Arc::from_raw(old) };
+  let guard = epoch::pin();
+  guard.defer(move || drop(old));
+  guard.flush();
+
+  Ok(true)
+}
+
+/// Reserve the processor exclusively for the tasks spawned with
+/// [`ReservedProcessor::spawn`], e.g. a latency critical task.
+///
+/// Other tasks are never pushed to reserved processor, and it doesn't steal
+/// from (or is stolen by) the other processors. Tasks that are already queued
+/// on it are moved to other processors. The processor is returned to the
+/// executor when the guard is dropped.
+///
+/// Return error if the index is invalid, the processor is already retired or
+/// reserved, it is the last processor that is not retired or reserved, or the
+/// queue topology is [`QueueTopology::Shared`] (the queue can't be dedicated).
+///
+/// [`ReservedProcessor::spawn`]: struct.ReservedProcessor.html#method.spawn
+/// [`QueueTopology::Shared`]: enum.QueueTopology.html#variant.Shared
+pub fn reserve_processor(index: usize) -> Result<ReservedProcessor, String> {
+  let p = EXECUTOR
+    .processors
+    .get(index)
+    .ok_or_else(|| format!("invalid processor index {}", index))?;
+
+  if EXECUTOR.queue_topology == QueueTopology::Shared {
+    return Err("cannot reserve processor with shared queue topology".to_string());
+  }
+  if p.is_retired() {
+    return Err(format!("processor {} is retired", index));
+  }
+  if !EXECUTOR
+    .processors
+    .iter()
+    .any(|q| q.id != index && q.accepts_tasks())
+  {
+    return Err("cannot reserve the last processor that is not reserved".to_string());
+  }
+  if p
+    .reserved
+    .compare_exchange(false, true, Ordering::Relaxed, Ordering::Relaxed)
+    .is_err()
+  {
+    return Err(format!("processor {} is already reserved", index));
   }
 
-  fn push(&self, t: Task) {
-    self.injector.push(t);
+  // it may be sleeping on the shared notification, wake it up so it sleep
+  // on its own
+  p.wake_up();
 
-    // wake up all processor,
-    // in case current processor is busy,
-    // others need to run (steal) it
-    let _ = EXECUTOR.wake_up.try_send(());
+  // no one else pop or steal from it anymore, move the queued tasks out
+  let migrate = |t: Task| {
+    EXECUTOR.mark_dequeued(&t);
+    t.tag().schedule_hint.store(usize::MAX, Ordering::Relaxed);
+    EXECUTOR.push(t);
+  };
+  for injector in &p.injectors {
+    while let Some(t) = EXECUTOR.steal_until_done(|| injector.steal()) {
+      migrate(t);
+    }
+  }
+  EXECUTOR.with_machine(index, |m| {
+    while let Some(t) = EXECUTOR.steal_until_done(|| m.stealer.steal()) {
+      migrate(t);
+    }
+  });
+
+  Ok(ReservedProcessor { index })
+}
+
+/// Guard of a reserved processor, see [`reserve_processor`].
+///
+/// [`reserve_processor`]: fn.reserve_processor.html
+#[derive(Debug)]
+pub struct ReservedProcessor {
+  index: usize,
+}
+
+impl ReservedProcessor {
+  /// Index of the reserved processor.
+  pub fn index(&self) -> usize {
+    self.index
   }
 
-  fn pop(&self, dest: &Worker<Task>) -> Option<Task> {
-    // steal until success or empty
-    std::iter::repeat_with(|| self.injector.steal_batch_and_pop(dest))
-      .filter(|s| !matches!(s, Steal::Retry)) // not Steal::Retry (*)
-      .map(|s| match s {
-        Steal::Success(task) => Some(task),
-        Steal::Empty => None,
-        Steal::Retry => unsafe { unreachable_unchecked() }, // (*)
-      })
-      .nth(0)
-      .unwrap()
+  /// Run the task on the reserved processor.
+  ///
+  /// After the guard is dropped, the task is treated like any other task,
+  /// and can be run by any processor.
+  #[track_caller]
+  pub fn spawn<F, T>(&self, f: F) -> JoinHandle<T>
+  where
+    F: Future<Output = T> + Send + 'static,
+    T: Send + 'static,
+  {
+    let mut tag = TaskTag::new(task_size::<F>(), Location::caller(), inherited_priority());
+    tag.pinned = Some(self.index);
+    spawn_tag(f, tag)
   }
 }
 
-impl std::fmt::Debug for Processor {
-  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-    f.write_str(&format!("P({})", self.id))
+impl Drop for ReservedProcessor {
+  fn drop(&mut self) {
+    let p = &EXECUTOR.processors[self.index];
+    p.reserved.store(false, Ordering::Relaxed);
+
+    // it is sleeping on its own notification, wake it up so it sleep on the
+    // shared one again
+    p.wake_up();
   }
 }
 
-impl Machine {
-  fn move_processor_to_new_machine(p: &Processor, inherit: Stealer<Task>) -> Arc<Machine> {
-    let id = MACHINE_ID_COUNTER.fetch_add(1, Ordering::Relaxed);
+/// Stop all machines from running new tasks, until [`resume`] is called.
+///
+/// Running tasks are not interrupted, their machines stop after the current
+/// poll return. Tasks can still be spawned and woken up, they are queued.
+/// Use this to take a consistent snapshot, e.g. [`topology`] and
+/// [`queued_tasks`], while the queues are not changing (except by new pushes).
+///
+/// When called from inside a task, the task itself is paused too after its
+/// current poll, so [`resume`] must be called from outside the executor.
+///
+/// [`resume`]: fn.resume.html
+/// [`topology`]: fn.topology.html
+/// [`queued_tasks`]: fn.queued_tasks.html
+pub fn pause() {
+  EXECUTOR.paused.store(true, Ordering::SeqCst);
+}
 
-    // take over the processor
-    p.machine_id.store(id, Ordering::Relaxed);
-    p.mark_nonblocking();
+/// Let the machines run tasks again, see [`pause`].
+///
+/// [`pause`]: fn.pause.html
+pub fn resume() {
+  let _lock = EXECUTOR.pause_lock.lock().unwrap();
+  EXECUTOR.paused.store(false, Ordering::SeqCst);
+  EXECUTOR.pause_cond.notify_all();
+}
 
-    let worker = Worker::new_fifo();
-    let stealer = worker.stealer();
-    let machine = Arc::new(Machine {
-      id,
-      stealer: stealer,
-      inherit,
-    });
+/// Return true between [`pause`] and [`resume`].
+///
+/// [`pause`]: fn.pause.html
+/// [`resume`]: fn.resume.html
+pub fn is_paused() -> bool {
+  EXECUTOR.is_paused()
+}
 
-    #[cfg(feature = "tracing")]
-    trace!("{:?} is created", machine);
+/// Lifecycle of the executor, see [`runtime_state`].
+///
+/// [`runtime_state`]: fn.runtime_state.html
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RuntimeState {
+  /// Accepting and running tasks.
+  Running = 0,
 
-    {
-      let machine = machine.clone();
+  /// [`shutdown`] is called, waiting for critical tasks, then stopping the
+  /// processors. [`try_spawn`] return error.
+  ///
+  /// [`shutdown`]: fn.shutdown.html
+  /// [`try_spawn`]: fn.try_spawn.html
+  Draining = 1,
 
-      // this is safe because processor is never get dropped after created,
-      // it can be assume that it has static lifetime
-      let processor: &'static Processor = unsafe { transmute(p) };
+  /// All processors are stopped, no task will be run anymore.
+  Stopped = 2,
+}
 
-      thread_pool::spawn_box(Box::new(move || {
-        abort_on_panic(move || machine.main(worker, processor))
-      }));
+/// Current lifecycle state of the executor.
+///
+/// Use this in health checks, e.g. to report unavailable while draining,
+/// so the load balancer stop sending requests before the executor is stopped.
+pub fn runtime_state() -> RuntimeState {
+  match EXECUTOR.state.load(Ordering::SeqCst) {
+    0 => RuntimeState::Running,
+    1 => RuntimeState::Draining,
+    _ => RuntimeState::Stopped,
+  }
+}
+
+/// How the queued tasks are handled on shutdown, see [`shutdown_with_mode`].
+///
+/// [`shutdown_with_mode`]: fn.shutdown_with_mode.html
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ShutdownMode {
+  /// Run the queued tasks, and the tasks woken up while draining, the
+  /// processors stop when their queues are empty, same as
+  /// [`Builder::shutdown_drain`] enabled.
+  ///
+  /// [`Builder::shutdown_drain`]: struct.Builder.html#method.shutdown_drain
+  Drain,
+
+  /// Drop the queued tasks without running them, the processors stop right
+  /// after their current task.
+  Immediate,
+}
+
+/// Same as [`shutdown`], but with the given mode, instead of the one set by
+/// [`Builder::shutdown_drain`].
+///
+/// Calling this with [`ShutdownMode::Immediate`] while the executor is
+/// already draining stop the draining, the processors stop after their
+/// current task and drop the rest. [`ShutdownMode::Drain`] after shutdown
+/// is started has no effect, the dropped tasks are already gone.
+///
+/// [`shutdown`]: fn.shutdown.html
+/// [`Builder::shutdown_drain`]: struct.Builder.html#method.shutdown_drain
+/// [`ShutdownMode::Immediate`]: enum.ShutdownMode.html#variant.Immediate
+/// [`ShutdownMode::Drain`]: enum.ShutdownMode.html#variant.Drain
+pub fn shutdown_with_mode(mode: ShutdownMode) {
+  match mode {
+    ShutdownMode::Immediate => EXECUTOR.shutdown_drain.store(false, Ordering::SeqCst),
+    ShutdownMode::Drain if !EXECUTOR.is_shutting_down() => {
+      EXECUTOR.shutdown_drain.store(true, Ordering::SeqCst)
     }
+    ShutdownMode::Drain => {}
+  }
+  shutdown();
+}
+
+/// Stop the executor, and wait until all processors are stopped.
+///
+/// Queued tasks and tasks that are woken up after this are dropped without
+/// being run (their destructors are run on the executor threads), awaiting
+/// their [`JoinHandle`] give cancelled [`JoinError`]. Running tasks are not
+/// interrupted, this wait until their current poll return. With
+/// [`Builder::shutdown_drain`], the queued tasks, and the tasks woken up while
+/// draining, are run first, see also [`shutdown_with_mode`].
+///
+/// After this, [`spawn`] (including spawn from inside destructor of the
+/// dropped tasks) is rejected: the task is dropped without being run, its
+/// handle give cancelled [`JoinError`], and error is printed to stderr.
+/// [`try_spawn`] return error. The executor can't be restarted.
+///
+/// Before stopping, this wait until all tasks spawned by [`spawn_critical`]
+/// are done, the executor keep running normally while waiting, but
+/// [`try_spawn`] already return error, see [`runtime_state`].
+///
+/// Calling this from inside a task works, but it has to wait until the
+/// sysmon replace the machine of the calling task.
+///
+/// [`JoinHandle`]: struct.JoinHandle.html
+/// [`JoinError`]: struct.JoinError.html
+/// [`spawn`]: fn.spawn.html
+/// [`try_spawn`]: fn.try_spawn.html
+/// [`spawn_critical`]: fn.spawn_critical.html
+/// [`runtime_state`]: fn.runtime_state.html
+/// [`Builder::shutdown_drain`]: struct.Builder.html#method.shutdown_drain
+/// [`shutdown_with_mode`]: fn.shutdown_with_mode.html
+pub fn shutdown() {
+  let _ = EXECUTOR.state.compare_exchange(
+    RuntimeState::Running as u8,
+    RuntimeState::Draining as u8,
+    Ordering::SeqCst,
+    Ordering::SeqCst,
+  );
 
-    machine
+  let mut critical = EXECUTOR.critical.lock().unwrap();
+  while *critical > 0 {
+    critical = EXECUTOR.critical_cond.wait(critical).unwrap();
   }
+  drop(critical);
 
-  fn main(&self, worker: Worker<Task>, processor: &Processor) {
+  if EXECUTOR.shutting_down.swap(true, Ordering::SeqCst) {
+    // already requested, just wait
+  } else {
     #[cfg(feature = "tracing")]
-    trace!("{:?} is running on {:?}", processor, self);
+    trace!("executor is shutting down");
 
-    // initial task from old machine
-    loop {
-      match self.inherit.steal_batch(&worker) {
-        Steal::Retry => continue,
-        _ => break,
+    // wake up paused machines, retired and reserved processors, and one
+    // sleeping processor, the sleeping one will wake up the others,
+    // see Processor::stop
+    {
+      let _lock = EXECUTOR.pause_lock.lock().unwrap();
+      EXECUTOR.pause_cond.notify_all();
+    }
+    for p in &EXECUTOR.processors {
+      let _lock = p.restore_lock.lock().unwrap();
+      p.restore_cond.notify_all();
+      p.wake_up();
+    }
+    EXECUTOR.wake_up_one();
+
+    // processors released by idle machines and spare processors has no one
+    // to stop them
+    for p in &EXECUTOR.processors {
+      let stop = |current| {
+        p.machine_id
+          .compare_exchange(current, STOPPED_MACHINE, Ordering::SeqCst, Ordering::SeqCst)
+          .is_ok()
+      };
+      if stop(NO_MACHINE) || stop(SPARE_MACHINE) {
+        p.stop(&Worker::new_fifo());
       }
     }
+  }
 
-    // Number of runs in a row before the global queue is inspected.
-    const MAX_RUNS: u64 = 64;
+  wait_shutdown();
+}
 
-    let mut run_counter = 0;
+/// Block until the executor is stopped, by [`shutdown`] or by
+/// [`Builder::shutdown_on_idle`].
+///
+/// Must not be called from inside a task, the task would wait for itself.
+///
+/// [`shutdown`]: fn.shutdown.html
+/// [`Builder::shutdown_on_idle`]: struct.Builder.html#method.shutdown_on_idle
+pub fn wait_shutdown() {
+  let mut stopped = EXECUTOR.stopped.lock().unwrap();
+  while *stopped < EXECUTOR.processors.len() {
+    stopped = EXECUTOR.stopped_cond.wait(stopped).unwrap();
+  }
+}
 
-    'main: loop {
-      macro_rules! run_task {
-        ($task:ident) => {{
-          // update the tag, so this task will be push to this processor again
-          $task
-            .tag()
-            .schedule_hint
-            .store(processor.id, Ordering::Relaxed);
+/// Return true if called from the executor thread (a thread that is running
+/// a processor), e.g. inside a task spawned by [`spawn`].
+///
+/// Blocking function that wait for other task (e.g. [`block_on`]) should not
+/// be called from executor thread.
+///
+/// [`spawn`]: fn.spawn.html
+/// [`block_on`]: fn.block_on.html
+pub fn in_executor() -> bool {
+  ON_MACHINE.with(|m| m.get())
+}
 
-          #[cfg(feature = "tracing")]
-          let task_id = $task.tag().id;
+/// Correlation id of the current task.
+///
+/// Task inherit the id from the task that spawn it, so the id can be used to
+/// follow a request across the task tree.
+///
+/// Return `None` if not called from inside a task, or the id is never set.
+pub fn current_trace_id() -> Option<u64> {
+  with_current(|t| match t.map(|t| t.get_trace_id()) {
+    Some(0) | None => None,
+    id => id,
+  })
+}
 
-          #[cfg(feature = "tracing")]
-          trace!(
-            "{} is running on {:?}",
-            TaskTag::string_rep(task_id),
-            processor
-          );
+/// Deadline of the current task, see [`spawn_with_deadline`].
+///
+/// Return `None` if not called from inside a task, or the task has no
+/// deadline.
+///
+/// [`spawn_with_deadline`]: fn.spawn_with_deadline.html
+pub fn current_deadline() -> Option<Instant> {
+  with_current(|t| t.and_then(|t| t.deadline))
+}
 
-          // help sysmon before doing real task
-          EXECUTOR.sysmon_assist();
+// the current task is cancelled by its deadline or by cancel_matching, but
+// it is not stopped yet, see sync::check_cancelled
+pub(crate) fn current_cancel_requested() -> bool {
+  with_current(|t| {
+    t.map(|t| {
+      #[cfg(feature = "registry")]
+      if t.cancel.is_requested() {
+        return true;
+      }
+      t.deadline.map(|d| Instant::now() >= d).unwrap_or(false)
+    })
+    .unwrap_or(false)
+  })
+}
 
-          // always assume the task is blocking
-          processor.mark_blocking();
-          {
-            $task.run();
+/// Set correlation id of the current task.
+///
+/// Only task spawned after this call will inherit the new id.
+/// Id 0 is reserved to mean none.
+///
+/// Return error if not called from inside a task.
+pub fn set_current_trace_id(id: u64) -> Result<(), String> {
+  with_current(|t| match t {
+    Some(t) => {
+      t.trace_id.store(id, Ordering::Relaxed);
+      Ok(())
+    }
+    None => Err("not called from inside a task".to_string()),
+  })
+}
 
-            // it is very crucial that we must exit this machine now when other machine holding
-            // the processor, so we don't mess up with the processor state
-            if processor.machine_id.load(Ordering::Relaxed) != self.id {
-              #[cfg(feature = "tracing")]
-              trace!(
-                "{:?} is no longer holding {:?}, blocking when executing {}",
-                self,
-                processor,
-                TaskTag::string_rep(task_id),
-              );
-              return;
-            }
-          }
-          processor.mark_nonblocking();
+/// Move the current task to the least loaded processor.
+///
+/// The task is only moved on its next yield point (when it is woken up and
+/// pushed to the queue again), never in the middle of a poll. After that, the
+/// task stay on the new processor, until it is moved again.
+///
+/// See also [`Builder::rebalance`] to let the sysmon do this automatically.
+///
+/// Return error if not called from inside a task.
+///
+/// [`Builder::rebalance`]: struct.Builder.html#method.rebalance
+pub fn migrate_current() -> Result<(), String> {
+  with_current(|t| match t {
+    Some(t) => {
+      migrate(t);
+      Ok(())
+    }
+    None => Err("not called from inside a task".to_string()),
+  })
+}
 
-          run_counter += 1;
-          continue 'main;
-        }};
-      }
+fn migrate(tag: &TaskTag) {
+  if let Some(index) = EXECUTOR.least_loaded_processor() {
+    tag.schedule_hint.store(index, Ordering::Relaxed);
+  }
+}
 
-      macro_rules! get_tasks {
-        () => {{
-          run_counter = 0;
-          match EXECUTOR.pop(processor.id, &worker) {
-            Some(task) => run_task!(task),
-            None => {}
-          }
-        }};
-      }
+pub(crate) fn is_shutting_down() -> bool {
+  EXECUTOR.is_shutting_down()
+}
 
-      if run_counter > MAX_RUNS {
-        get_tasks!();
-      }
+pub(crate) fn task_size<F>() -> usize {
+  std::mem::size_of::<F>() + std::mem::size_of::<TaskTag>()
+}
 
-      // run all task in the worker
-      if let Some(task) = worker.pop() {
-        run_task!(task);
-      }
+/// Task taken out of the executor queues, see [`raw`] module.
+///
+/// It must be either run or injected back, dropping it cancel the task.
+///
+/// [`raw`]: raw/index.html
+#[cfg(feature = "raw")]
+pub struct RawTask {
+  // always Some, until it is consumed
+  task: Option<Task>,
+}
 
-      // at this point, the worker is empty
+#[cfg(feature = "raw")]
+impl RawTask {
+  fn new(task: Task) -> RawTask {
+    RawTask { task: Some(task) }
+  }
 
-      // 1. steal from old machine (in case some one accidentally push to it)
-      match self.inherit.steal_batch_and_pop(&worker) {
-        Steal::Success(task) => run_task!(task),
-        _ => {}
-      }
+  fn take(mut self) -> Task {
+    let task = self.task.take().unwrap();
+    EXECUTOR.mark_dequeued(&task);
+    task
+  }
 
-      // 2. pop from global queue
-      get_tasks!();
+  pub fn task_id(&self) -> usize {
+    self.task.as_ref().unwrap().tag().id
+  }
 
-      // 3. steal from others
-      match EXECUTOR.steal(&worker) {
-        Some(task) => run_task!(task),
-        None => {}
-      }
+  /// Run (poll) the task on the current thread.
+  ///
+  /// The task is marked as current task while it is running, but blocking
+  /// detection is not applied, because it is not run by the processor.
+  pub fn run(self) {
+    run_as_current(self.take());
+  }
 
-      // 4.a. no more task for now, just sleep until waked up
-      processor.sleep();
+  /// Push the task back to the global queue of the processor at `index`,
+  /// or to any processor if `index` is invalid or the processor is retired.
+  pub fn inject(self, index: usize) {
+    let task = self.take();
+    task.tag().schedule_hint.store(index, Ordering::Relaxed);
+    EXECUTOR.push(task);
+  }
+}
 
-      // 4.b. just waked up, pop from global queue
-      get_tasks!();
+#[cfg(feature = "raw")]
+impl Drop for RawTask {
+  fn drop(&mut self) {
+    if let Some(task) = self.task.take() {
+      EXECUTOR.mark_dequeued(&task);
     }
   }
 }
 
-impl std::fmt::Debug for Machine {
+#[cfg(feature = "raw")]
+impl std::fmt::Debug for RawTask {
   fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-    f.write_str(&format!("M({})", self.id))
+    f.debug_struct("RawTask")
+      .field("task_id", &self.task_id())
+      .finish()
   }
 }
 
-#[cfg(feature = "tracing")]
-impl Drop for Machine {
-  fn drop(&mut self) {
-    trace!("{:?} is destroyed", self);
+/// Steal one task from the local queue of the machine that currently hold
+/// the processor at `index`.
+#[cfg(feature = "raw")]
+pub fn steal_from_machine(index: usize) -> Option<RawTask> {
+  if index >= EXECUTOR.machines.len() {
+    return None;
   }
+  EXECUTOR
+    .with_machine(index, |m| EXECUTOR.steal_until_done(|| m.stealer.steal()))
+    .map(RawTask::new)
 }
 
-/// Run the task.
+/// Steal one task from the global queue of the processor at `index`,
+/// higher priority first.
+#[cfg(feature = "raw")]
+pub fn steal_from_processor(index: usize) -> Option<RawTask> {
+  let p = EXECUTOR.processors.get(index)?;
+  p.injectors()
+    .iter()
+    .rev()
+    .find_map(|injector| EXECUTOR.steal_until_done(|| injector.steal()))
+    .map(RawTask::new)
+}
+
+/// Take all queued tasks out of the executor, sorted by task id.
 ///
-/// It's okay to do blocking operation in the task, the executor will detect
-/// this and scale the pool.
-pub fn spawn<F: Future<Output = ()> + Send + 'static>(f: F) {
-  let (task, _) = async_task::spawn(f, |t| EXECUTOR.push(t), TaskTag::new());
-  task.schedule();
+/// This include tasks in the global queues of all processors (or the shared
+/// queues, see [`QueueTopology`]) and in the local queues of all machines,
+/// including the tasks a machine inherited from the blocking machine it
+/// replaced and didn't take yet.
+/// Tasks that are running, or pushed while draining, are not included.
+/// Stealing is atomic, so each task is taken either by this or by a machine,
+/// never both.
+///
+/// Useful for shutdown and testing, e.g. to run the pending tasks
+/// deterministically, or to drop (cancel) them.
+///
+/// [`QueueTopology`]: ../enum.QueueTopology.html
+#[cfg(feature = "raw")]
+pub fn drain_all() -> Vec<RawTask> {
+  let mut tasks = Vec::new();
+  for index in 0..EXECUTOR.processors.len() {
+    while let Some(t) = steal_from_processor(index) {
+      tasks.push(t);
+    }
+    EXECUTOR.with_machine(index, |m| {
+      for stealer in [&m.stealer, &m.inherit] {
+        while let Some(t) = EXECUTOR.steal_until_done(|| stealer.steal()) {
+          tasks.push(RawTask::new(t));
+        }
+      }
+    });
+  }
+  tasks.sort_by_key(|t| t.task_id());
+  tasks
+}
+
+/// Same as [`spawn`], but push the task to the global queue of the processor
+/// at `index`, or to any processor if `index` is invalid or the processor is
+/// retired.
+///
+/// [`spawn`]: ../fn.spawn.html
+#[cfg(feature = "raw")]
+#[track_caller]
+pub fn spawn_to<F, T>(index: usize, f: F) -> JoinHandle<T>
+where
+  F: Future<Output = T> + Send + 'static,
+  T: Send + 'static,
+{
+  let tag = TaskTag::new(task_size::<F>(), Location::caller(), inherited_priority());
+  tag.schedule_hint.store(index, Ordering::Relaxed);
+  spawn_tag(f, tag)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  // number of Machine that is not dropped yet
+  pub(super) static LIVE_MACHINES: AtomicUsize = AtomicUsize::new(0);
+
+  // the initial machines (and the spare placeholders) inherit from the worker
+  // that is dropped at init, the stealer stays valid, but always empty
+  #[test]
+  fn initial_machines_never_inherit() {
+    let handles: Vec<_> = (0..100).map(|i| spawn(async move { i })).collect();
+    for h in handles {
+      block_on(h).unwrap();
+    }
+
+    // ids are given in order at init, the ones not replaced yet are still
+    // there
+    for index in 0..EXECUTOR.machines.len() {
+      EXECUTOR.with_machine(index, |m| {
+        if m.id < EXECUTOR.processors.len() {
+          assert!(m.inherit.is_empty());
+          assert!(m.inherit.steal().is_empty());
+        }
+      });
+    }
+
+    // same as init, the worker is dropped while its stealers are in use
+    let empty_worker = Worker::<Task>::new_fifo();
+    let stealers: Vec<_> = (0..4).map(|_| empty_worker.stealer()).collect();
+    drop(empty_worker);
+    let stealers: Vec<_> = stealers
+      .into_iter()
+      .map(|stealer| {
+        thread::spawn(move || {
+          for _ in 0..1000 {
+            assert!(stealer.steal().is_empty());
+          }
+        })
+      })
+      .collect();
+    for s in stealers {
+      s.join().unwrap();
+    }
+  }
+
+  // pinned to processor that is out of range, or not reserved anymore, fall
+  // back to the rotation
+  #[test]
+  fn invalid_pinned_processor_fallback() {
+    for pinned in [EXECUTOR.processors.len() + 10, usize::MAX, 0] {
+      let mut tag = TaskTag::new(0, Location::caller(), Priority::Normal);
+      tag.pinned = Some(pinned);
+      tag.schedule_hint.store(pinned, Ordering::Relaxed);
+      assert_eq!(
+        block_on(spawn_tag(async move { pinned }, tag)).unwrap(),
+        pinned
+      );
+    }
+  }
+
+  // base priority of the current task
+  fn current_priority() -> Priority {
+    with_current(|t| t.unwrap().priority)
+  }
+
+  // children of high (or low) priority task get the same priority, also
+  // their children, unless the priority is given
+  #[test]
+  fn spawn_inherit_priority() {
+    for parent in [Priority::High, Priority::Low] {
+      let fan_out = spawn_with_priority(parent, async move {
+        let children: Vec<_> = (0..10)
+          .map(|_| spawn(async { (current_priority(), spawn(async { current_priority() })) }))
+          .collect();
+        let mut priorities = Vec::new();
+        for child in children {
+          let (priority, grandchild) = child.await.unwrap();
+          priorities.push(priority);
+          priorities.push(grandchild.await.unwrap());
+        }
+
+        let given = spawn_with_priority(Priority::Normal, async { current_priority() });
+        (priorities, given.await.unwrap())
+      });
+
+      let (priorities, given) = block_on(fan_out).unwrap();
+      assert_eq!(priorities, vec![parent; 20]);
+      assert_eq!(given, Priority::Normal);
+    }
+
+    // outside of task, and with Builder::inherit_priority(false)
+    assert_eq!(inherited_priority(), Priority::Normal);
+    let opt_out = spawn_with_priority(Priority::High, async {
+      (parent_priority(true), parent_priority(false))
+    });
+    assert_eq!(
+      block_on(opt_out).unwrap(),
+      (Priority::High, Priority::Normal)
+    );
+  }
+
+  // steal that keep losing the race give up after steal_retry_limit retries,
+  // instead of spinning until the contention is gone
+  #[test]
+  fn steal_retry_is_bounded() {
+    let started = Instant::now();
+    let mut tries = 0;
+    let stolen = EXECUTOR.steal_until_done(|| {
+      tries += 1;
+      Steal::Retry
+    });
+    assert!(stolen.is_none());
+    assert_eq!(tries, EXECUTOR.steal_retry_limit + 1);
+
+    // many threads hitting the same queue, the stealers that lose keep
+    // returning, the queue is still emptied by the others
+    let queue = Arc::new(GlobalQueue::new());
+    for _ in 0..1000 {
+      let (task, handle) = spawn_raw(
+        async {},
+        TaskTag::new(0, Location::caller(), Priority::Normal),
+        drop,
+      );
+      drop(handle);
+      queue.push(task);
+    }
+    let stealers: Vec<_> = (0..8)
+      .map(|_| {
+        let queue = queue.clone();
+        thread::spawn(move || {
+          let mut stolen = 0;
+          while !queue.is_empty() {
+            if EXECUTOR.steal_until_done(|| queue.steal()).is_some() {
+              stolen += 1;
+            }
+          }
+          stolen
+        })
+      })
+      .collect();
+    let stolen: usize = stealers.into_iter().map(|s| s.join().unwrap()).sum();
+    assert_eq!(stolen, 1000);
+    assert!(started.elapsed() < Duration::from_secs(5));
+  }
+
+  // replace machines while other threads keep using them, every replaced
+  // machine must be dropped exactly once, after its thread exit
+  #[test]
+  fn concurrent_machine_replacement() {
+    let done = Arc::new(AtomicBool::new(false));
+    let readers: Vec<_> = (0..2)
+      .map(|_| {
+        let done = done.clone();
+        thread::spawn(move || {
+          let mut seen = 0;
+          while !done.load(Ordering::Relaxed) {
+            for index in 0..EXECUTOR.machines.len() {
+              seen += EXECUTOR.with_machine(index, |m| m.stealer.is_empty() as usize);
+            }
+          }
+          seen
+        })
+      })
+      .collect();
+
+    let replaced = EXECUTOR.replacements.load(Ordering::SeqCst);
+    for _ in 0..10 {
+      let handles: Vec<_> = (0..8)
+        .map(|i| {
+          spawn(async move {
+            if i % 2 == 0 {
+              thread::sleep(Duration::from_millis(30));
+            }
+            i
+          })
+        })
+        .collect();
+      let sum: usize = handles.into_iter().map(|h| block_on(h).unwrap()).sum();
+      assert_eq!(sum, 28);
+    }
+    done.store(true, Ordering::Relaxed);
+    for r in readers {
+      r.join().unwrap();
+    }
+    assert!(EXECUTOR.replacements.load(Ordering::SeqCst) > replaced);
+
+    // replaced machines exit after their blocking task, and the executor's
+    // Arc is dropped once the epoch advance
+    let started = Instant::now();
+    loop {
+      epoch::pin().flush();
+      let live = LIVE_MACHINES.load(Ordering::SeqCst);
+      if live == EXECUTOR.processors.len() {
+        break;
+      }
+      assert!(
+        started.elapsed() < Duration::from_secs(5),
+        "{} machines alive",
+        live
+      );
+      thread::sleep(Duration::from_millis(10));
+    }
+  }
 }
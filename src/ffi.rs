@@ -0,0 +1,131 @@
+//! Spawning from C.
+//!
+//! C program can't await a [`JoinHandle`], instead [`spawn_with_callback`]
+//! call a C function pointer with a `void *` context when the task is done.
+//! The future itself still need to be written in Rust, this module is for the
+//! glue layer that expose it to C.
+//!
+//! Only available with `ffi` feature.
+//!
+//! [`JoinHandle`]: ../struct.JoinHandle.html
+//! [`spawn_with_callback`]: fn.spawn_with_callback.html
+
+use std::ffi::c_void;
+use std::future::Future;
+use std::thread;
+
+use crate::executor;
+
+/// How the task is done, passed to the [`Callback`].
+///
+/// [`Callback`]: type.Callback.html
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Status {
+  /// The future is completed, the output is written.
+  Completed = 0,
+
+  /// The future panicked (with [`PanicPolicy::Catch`]), the output is not
+  /// written.
+  ///
+  /// [`PanicPolicy::Catch`]: ../enum.PanicPolicy.html#variant.Catch
+  Panicked = 1,
+
+  /// The task is dropped before it is completed, e.g. its deadline passed,
+  /// or it is woken up after the executor is shut down, the output is not
+  /// written.
+  Cancelled = 2,
+}
+
+/// C function called when the task is done, with the context given to
+/// [`spawn_with_callback`].
+///
+/// [`spawn_with_callback`]: fn.spawn_with_callback.html
+pub type Callback = unsafe extern "C" fn(context: *mut c_void, status: Status);
+
+/// Spawn the task, and call `callback` when it is done, instead of returning
+/// [`JoinHandle`].
+///
+/// When the future is completed, its output is written to `output` (with
+/// `ptr::write`, the old value is not dropped), then `callback` is called
+/// with `context` and [`Status::Completed`]. If `output` is null, the output
+/// is dropped. For output that C can't read directly, write it in the glue
+/// layer, e.g. `async move { make_c_struct(f.await) }`.
+///
+/// `callback` is always called exactly once, also when the task panicked or
+/// cancelled, see [`Status`]. It is called on the executor thread, or on the
+/// thread that drop the task (e.g. the thread that call [`shutdown`]), so it
+/// must be quick and thread safe, and it must not unwind.
+///
+/// # Safety
+///
+/// `output` must be null, or valid for write and properly aligned until the
+/// callback is called. `context` is passed to `callback` as is, it must stay
+/// valid until then, and it is sent to another thread.
+///
+/// [`JoinHandle`]: ../struct.JoinHandle.html
+/// [`Status`]: enum.Status.html
+/// [`Status::Completed`]: enum.Status.html#variant.Completed
+/// [`shutdown`]: ../fn.shutdown.html
+#[track_caller]
+pub unsafe fn spawn_with_callback<F, T>(
+  f: F,
+  output: *mut T,
+  callback: Callback,
+  context: *mut c_void,
+) where
+  F: Future<Output = T> + Send + 'static,
+  T: Send + 'static,
+{
+  // outside of the async block, so the callback is called even if the task
+  // is dropped before its first poll
+  let mut notify = Notify {
+    output,
+    callback,
+    context,
+    done: false,
+  };
+
+  // the handle is not needed, dropping it detach the task
+  executor::spawn(async move {
+    let value = f.await;
+    notify.complete(value);
+  });
+}
+
+struct Notify<T> {
+  output: *mut T,
+  callback: Callback,
+  context: *mut c_void,
+  done: bool,
+}
+
+// the caller of spawn_with_callback guarantee the pointers can be sent
+unsafe impl<T: Send> Send for Notify<T> {}
+
+impl<T> Notify<T> {
+  fn complete(&mut self, value: T) {
+    if self.output.is_null() {
+      drop(value);
+    } else {
+      unsafe { self.output.write(value) };
+    }
+    self.done = true;
+    unsafe { (self.callback)(self.context, Status::Completed) };
+  }
+}
+
+impl<T> Drop for Notify<T> {
+  fn drop(&mut self) {
+    if self.done {
+      return;
+    }
+
+    // dropped while unwinding from the future
+    let status = match thread::panicking() {
+      true => Status::Panicked,
+      false => Status::Cancelled,
+    };
+    unsafe { (self.callback)(self.context, status) };
+  }
+}
@@ -8,7 +8,57 @@
 #[macro_use]
 mod utils;
 
+mod block_on;
+mod config;
+mod dedicated;
+#[cfg(all(unix, feature = "signal-dump"))]
+mod dump;
 mod executor;
+mod ordered;
+mod panic;
+mod par;
+mod quota;
+#[cfg(feature = "registry")]
+mod registry;
+mod retry;
+mod step;
 mod thread_pool;
+mod timer;
+#[cfg(feature = "waker-stats")]
+mod wakers;
 
-pub use executor::spawn;
+pub mod compat;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+#[cfg(feature = "metrics")]
+pub mod metrics;
+#[cfg(feature = "raw")]
+pub mod raw;
+pub mod sync;
+#[cfg(feature = "registry")]
+pub mod testing;
+
+pub use block_on::{block_on, block_on_join};
+pub use config::{
+  Builder, InitMode, MachinePanicPolicy, PanicPolicy, PushRotation, QueueTopology, SleepBackoff,
+  StallPolicy, StealOrder, TaskCount, WakeStrategy, Watermark,
+};
+pub use dedicated::{spawn_dedicated, spawn_local};
+pub use executor::{
+  add_processor, current_deadline, current_task_meta, current_trace_id, in_executor, is_paused,
+  migrate_current, pause, pending_tasks, processor_last_seen_ms, queued_memory, queued_tasks,
+  reserve_processor, restore_processor, resume, retire_processor, runtime_state,
+  set_current_trace_id, shutdown, shutdown_with_mode, spawn, spawn_blocking,
+  spawn_blocking_timeout, spawn_boxed, spawn_critical, spawn_tagged, spawn_with_cost,
+  spawn_with_deadline, spawn_with_priority, topology, try_spawn, unblock, wait_shutdown,
+  JoinHandle, Priority, ProcessorInfo, ReservedProcessor, RuntimeState, ShutdownMode, Unblock,
+};
+pub use ordered::OrderedSpawner;
+pub use panic::JoinError;
+pub use par::par_for_each;
+pub use quota::TenantQuota;
+#[cfg(feature = "registry")]
+pub use registry::{cancel_matching, task_dump, TaskInfo, TaskState};
+pub use retry::{retry, retry_if, Backoff, RetryPolicy};
+pub use step::StepExecutor;
+pub use timer::{sleep, sleep_until, timeout, Elapsed, Sleep, Timeout};
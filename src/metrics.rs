@@ -0,0 +1,821 @@
+//! Executor metrics.
+//!
+//! Only available with `metrics` feature, there is no overhead when it is disabled.
+
+#[cfg(feature = "steal-trace")]
+use std::cell::Cell;
+#[cfg(any(
+  feature = "steal-trace",
+  feature = "order-trace",
+  feature = "event-log"
+))]
+use std::collections::VecDeque;
+use std::future::Future;
+#[cfg(feature = "order-trace")]
+use std::panic::Location;
+use std::pin::Pin;
+#[cfg(feature = "order-trace")]
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::{AtomicU64, Ordering};
+#[cfg(feature = "cpu-time")]
+use std::sync::Arc;
+#[cfg(any(
+  feature = "steal-trace",
+  feature = "order-trace",
+  feature = "event-log"
+))]
+use std::sync::Mutex;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+#[cfg(any(
+  feature = "steal-trace",
+  feature = "order-trace",
+  feature = "event-log"
+))]
+use once_cell::sync::Lazy;
+
+#[cfg(any(feature = "cpu-time", feature = "event-log"))]
+use crate::config;
+#[cfg(feature = "cpu-time")]
+use crate::executor::Meta;
+use crate::executor::{self, RuntimeState};
+#[cfg(feature = "order-trace")]
+use crate::panic::JoinError;
+use crate::thread_pool;
+use crate::timer::{self, Sleep};
+
+const BUCKETS: usize = 32;
+
+/// Snapshot of histogram, with power of two buckets.
+///
+/// Bucket 0 count value 0, bucket `i` count value in range `[2^(i-1), 2^i)`,
+/// the last bucket also count everything above it.
+#[derive(Clone, Debug)]
+pub struct Histogram {
+  buckets: [u64; BUCKETS],
+}
+
+impl Histogram {
+  /// Iterate over `(upper bound (exclusive), count)` of each bucket.
+  pub fn buckets(&self) -> impl Iterator<Item = (u64, u64)> + '_ {
+    self
+      .buckets
+      .iter()
+      .enumerate()
+      .map(|(i, &count)| (1 << i, count))
+  }
+
+  /// Total number of recorded value.
+  pub fn count(&self) -> u64 {
+    self.buckets.iter().sum()
+  }
+
+  /// Upper bound of the bucket that contain the `q` quantile (0.0 to 1.0).
+  pub fn quantile(&self, q: f64) -> u64 {
+    let target = (self.count() as f64 * q).ceil() as u64;
+    let mut seen = 0;
+    for (upper, count) in self.buckets() {
+      seen += count;
+      if seen >= target && seen > 0 {
+        return upper;
+      }
+    }
+    0
+  }
+}
+
+pub(crate) struct AtomicHistogram {
+  buckets: [AtomicU64; BUCKETS],
+}
+
+impl AtomicHistogram {
+  pub(crate) const fn new() -> AtomicHistogram {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const ZERO: AtomicU64 = AtomicU64::new(0);
+    AtomicHistogram {
+      buckets: [ZERO; BUCKETS],
+    }
+  }
+
+  pub(crate) fn record(&self, value: u64) {
+    let index = std::cmp::min(64 - value.leading_zeros() as usize, BUCKETS - 1);
+    self.buckets[index].fetch_add(1, Ordering::Relaxed);
+  }
+
+  fn snapshot(&self) -> Histogram {
+    let mut buckets = [0; BUCKETS];
+    for (b, a) in buckets.iter_mut().zip(self.buckets.iter()) {
+      *b = a.load(Ordering::Relaxed);
+    }
+    Histogram { buckets }
+  }
+}
+
+// time between task is pushed to the queue and it is run, in microseconds
+pub(crate) static QUEUE_LATENCY: AtomicHistogram = AtomicHistogram::new();
+
+/// How long tasks wait in the queue before they are run, in microseconds.
+pub fn queue_latency() -> Histogram {
+  QUEUE_LATENCY.snapshot()
+}
+
+// time between parked processor is woken up and it run a task, in microseconds
+pub(crate) static WAKE_LATENCY: AtomicHistogram = AtomicHistogram::new();
+
+/// How long a parked processor take to run its first task after it is woken
+/// up, in microseconds.
+///
+/// It is measured from when the wake up is sent (e.g. a task is pushed while
+/// the processor is sleeping), so it include the thread unpark latency and
+/// the time to find the task. Wake ups that find no task (another processor
+/// already took it) are not counted, and neither are processors that find a
+/// task while spinning before parking, see [`Builder::sleep_spins`].
+///
+/// [`Builder::sleep_spins`]: ../struct.Builder.html#method.sleep_spins
+pub fn wake_latency() -> Histogram {
+  WAKE_LATENCY.snapshot()
+}
+
+/// Snapshot of the executor load, see [`snapshot`].
+///
+/// [`snapshot`]: fn.snapshot.html
+#[derive(Clone, Debug)]
+pub struct Metrics {
+  /// When the snapshot is taken.
+  pub at: Instant,
+
+  /// Number of tasks queued on each processor, indexed by processor index.
+  pub queued: Vec<usize>,
+
+  /// Number of processors that are not sleeping, retired processors are not
+  /// counted.
+  pub busy_processors: usize,
+
+  /// Number of processors that are not retired.
+  pub active_processors: usize,
+
+  /// Number of threads in the pool, idle or busy, including the replaced
+  /// (blocking) machines.
+  pub threads: usize,
+}
+
+impl Metrics {
+  /// Total number of queued tasks, in all processors.
+  pub fn total_queued(&self) -> usize {
+    self.queued.iter().sum()
+  }
+
+  /// Fraction of active processors that are busy, 0.0 to 1.0.
+  pub fn utilization(&self) -> f64 {
+    if self.active_processors == 0 {
+      return 0.0;
+    }
+    self.busy_processors as f64 / self.active_processors as f64
+  }
+}
+
+/// Take a snapshot of the current executor load.
+///
+/// The numbers are read without synchronization between processors, so they
+/// are only approximation, good enough for adaptive logic, e.g. lowering the
+/// concurrency limit when the queues keep growing.
+pub fn snapshot() -> Metrics {
+  let topology = executor::topology();
+  let active = topology.iter().filter(|p| !p.retired);
+  Metrics {
+    at: Instant::now(),
+    queued: topology.iter().map(|p| p.queued).collect(),
+    busy_processors: active.clone().filter(|p| !p.sleeping).count(),
+    active_processors: active.count(),
+    threads: thread_pool::threads(),
+  }
+}
+
+/// Stream of [`Metrics`] snapshots, taken every `interval`.
+///
+/// The first snapshot is given immediately, the next ones are given every
+/// `interval` after that, backed by the executor timer. If the consumer is
+/// slower than `interval`, the missed ticks are skipped, not bursted.
+///
+/// The stream end (give `None`) when the executor is stopped, see
+/// [`shutdown`]. Zero `interval` is treated as 1 millisecond.
+///
+/// [`Metrics`]: struct.Metrics.html
+/// [`shutdown`]: ../fn.shutdown.html
+pub fn metrics_stream(interval: Duration) -> MetricsStream {
+  MetricsStream {
+    interval: std::cmp::max(interval, Duration::from_millis(1)),
+    sleep: None,
+  }
+}
+
+/// Stream returned by [`metrics_stream`].
+///
+/// It has the same shape as `futures::Stream`, use [`poll_next`] from manual
+/// poll implementation, or [`tick`] from async code.
+///
+/// [`metrics_stream`]: fn.metrics_stream.html
+/// [`poll_next`]: #method.poll_next
+/// [`tick`]: #method.tick
+#[derive(Debug)]
+pub struct MetricsStream {
+  interval: Duration,
+
+  // None before the first snapshot
+  sleep: Option<Sleep>,
+}
+
+impl MetricsStream {
+  /// Poll for the next snapshot, `None` if the executor is stopped.
+  pub fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Metrics>> {
+    if executor::runtime_state() == RuntimeState::Stopped {
+      return Poll::Ready(None);
+    }
+
+    let interval = self.interval;
+    let next = match &mut self.sleep {
+      Some(sleep) => {
+        if Pin::new(&mut *sleep).poll(cx).is_pending() {
+          return Poll::Pending;
+        }
+
+        // skip the missed ticks
+        let now = Instant::now();
+        match sleep.deadline().and_then(|d| d.checked_add(interval)) {
+          Some(next) if next > now => timer::sleep_until(next),
+          _ => timer::sleep(interval),
+        }
+      }
+      None => timer::sleep(interval),
+    };
+    self.sleep = Some(next);
+
+    Poll::Ready(Some(snapshot()))
+  }
+
+  /// Wait for the next snapshot, `None` if the executor is stopped.
+  pub fn tick(&mut self) -> MetricsTick<'_> {
+    MetricsTick { stream: self }
+  }
+}
+
+/// Future returned by [`MetricsStream::tick`].
+///
+/// [`MetricsStream::tick`]: struct.MetricsStream.html#method.tick
+#[derive(Debug)]
+pub struct MetricsTick<'a> {
+  stream: &'a mut MetricsStream,
+}
+
+impl Future for MetricsTick<'_> {
+  type Output = Option<Metrics>;
+
+  fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Metrics>> {
+    Pin::new(&mut *self.stream).poll_next(cx)
+  }
+}
+
+/// Where the tasks run by a processor come from, see [`task_sources`].
+///
+/// Tasks are taken in batch from the global queues, from other processors,
+/// and from the replaced (blocking) machine, into the machine's local queue,
+/// only the first task of the batch is counted to its source, the rest are
+/// counted as local when they are run later.
+///
+/// [`task_sources`]: fn.task_sources.html
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TaskSources {
+  /// Run from the machine's local queue.
+  pub local: u64,
+
+  /// Run from the global queues, the processor's own queue first, then the
+  /// queues of the other processors.
+  pub global: u64,
+
+  /// Stolen from the local queue of other processor's machine.
+  pub stolen: u64,
+
+  /// Inherited from the machine that is replaced because it is blocking.
+  pub inherited: u64,
+}
+
+impl TaskSources {
+  /// Total number of run tasks.
+  pub fn total(&self) -> u64 {
+    self.local + self.global + self.stolen + self.inherited
+  }
+
+  /// Fraction of run tasks that are stolen, 0.0 to 1.0.
+  pub fn steal_ratio(&self) -> f64 {
+    match self.total() {
+      0 => 0.0,
+      total => self.stolen as f64 / total as f64,
+    }
+  }
+}
+
+pub(crate) struct SourceCounters {
+  pub(crate) local: AtomicU64,
+  pub(crate) global: AtomicU64,
+  pub(crate) stolen: AtomicU64,
+  pub(crate) inherited: AtomicU64,
+}
+
+impl SourceCounters {
+  pub(crate) const fn new() -> SourceCounters {
+    SourceCounters {
+      local: AtomicU64::new(0),
+      global: AtomicU64::new(0),
+      stolen: AtomicU64::new(0),
+      inherited: AtomicU64::new(0),
+    }
+  }
+
+  pub(crate) fn snapshot(&self) -> TaskSources {
+    TaskSources {
+      local: self.local.load(Ordering::Relaxed),
+      global: self.global.load(Ordering::Relaxed),
+      stolen: self.stolen.load(Ordering::Relaxed),
+      inherited: self.inherited.load(Ordering::Relaxed),
+    }
+  }
+}
+
+/// Where the tasks run by each processor come from, since the executor is
+/// started, indexed by processor index.
+///
+/// Mostly local and global means good locality, the processors are busy with
+/// their own tasks. High [`steal_ratio`] means the load is unbalanced, the
+/// tasks are spawned or woken up on few processors, and the others have to
+/// steal them.
+///
+/// [`steal_ratio`]: struct.TaskSources.html#method.steal_ratio
+pub fn task_sources() -> Vec<TaskSources> {
+  executor::processor_task_sources()
+}
+
+// time spent by machines, in nanoseconds, see Stopwatch
+#[cfg(feature = "overhead")]
+static SCHEDULING_NS: AtomicU64 = AtomicU64::new(0);
+#[cfg(feature = "overhead")]
+static RUNNING_NS: AtomicU64 = AtomicU64::new(0);
+
+// per machine, split the time since the last mark into scheduling (push, pop,
+// steal, bookkeeping) and running tasks, time spent sleeping is not counted
+#[cfg(feature = "overhead")]
+pub(crate) struct Stopwatch {
+  mark: Instant,
+}
+
+#[cfg(feature = "overhead")]
+impl Stopwatch {
+  pub(crate) fn new() -> Stopwatch {
+    Stopwatch {
+      mark: Instant::now(),
+    }
+  }
+
+  fn lap(&mut self) -> u64 {
+    let now = Instant::now();
+    let elapsed = now.duration_since(self.mark).as_nanos() as u64;
+    self.mark = now;
+    elapsed
+  }
+
+  pub(crate) fn scheduled(&mut self) {
+    SCHEDULING_NS.fetch_add(self.lap(), Ordering::Relaxed);
+  }
+
+  pub(crate) fn ran(&mut self) {
+    RUNNING_NS.fetch_add(self.lap(), Ordering::Relaxed);
+  }
+
+  pub(crate) fn idled(&mut self) {
+    self.lap();
+  }
+}
+
+/// Cumulative time spent by all machines, see [`scheduler_overhead`].
+///
+/// [`scheduler_overhead`]: fn.scheduler_overhead.html
+#[cfg(feature = "overhead")]
+#[derive(Clone, Copy, Debug)]
+pub struct Overhead {
+  /// Time spent finding the next task to run (pop, steal, bookkeeping).
+  pub scheduling: Duration,
+
+  /// Time spent running (polling) tasks.
+  pub running: Duration,
+}
+
+#[cfg(feature = "overhead")]
+impl Overhead {
+  /// Fraction of busy time spent in scheduling, 0.0 to 1.0.
+  pub fn ratio(&self) -> f64 {
+    let total = self.scheduling + self.running;
+    if total == Duration::from_secs(0) {
+      return 0.0;
+    }
+    self.scheduling.as_secs_f64() / total.as_secs_f64()
+  }
+}
+
+/// Time spent by machines in scheduling vs running tasks, since the executor
+/// is started. Time spent sleeping (no task to run) is not counted.
+///
+/// Only available with `overhead` feature, because it take timestamps around
+/// every task run.
+#[cfg(feature = "overhead")]
+pub fn scheduler_overhead() -> Overhead {
+  Overhead {
+    scheduling: Duration::from_nanos(SCHEDULING_NS.load(Ordering::Relaxed)),
+    running: Duration::from_nanos(RUNNING_NS.load(Ordering::Relaxed)),
+  }
+}
+
+/// Time spent running (polling) tasks on each processor, since the executor
+/// is started, indexed by processor index.
+///
+/// Use [`JoinHandle::cpu_time`] for a single task, and
+/// [`Builder::cpu_time_handler`] to attribute the time to task metadata
+/// (e.g. tenant id).
+///
+/// This is wall clock time of the polls, including time blocked inside a
+/// poll, not the time scheduled by the OS. Only available with `cpu-time`
+/// feature, because it take two timestamps around every poll, on the machine
+/// and inside the task, on top of the handler call.
+///
+/// [`JoinHandle::cpu_time`]: ../struct.JoinHandle.html#method.cpu_time
+/// [`Builder::cpu_time_handler`]: ../struct.Builder.html#method.cpu_time_handler
+#[cfg(feature = "cpu-time")]
+pub fn processor_cpu_time() -> Vec<Duration> {
+  executor::processor_run_ns()
+    .into_iter()
+    .map(Duration::from_nanos)
+    .collect()
+}
+
+// time every poll of the task, see processor_cpu_time
+#[cfg(feature = "cpu-time")]
+pub(crate) struct CpuTimed<F> {
+  future: F,
+  cpu_ns: Arc<AtomicU64>,
+  meta: Option<Arc<dyn Meta>>,
+}
+
+#[cfg(feature = "cpu-time")]
+impl<F> CpuTimed<F> {
+  pub(crate) fn new(future: F, cpu_ns: Arc<AtomicU64>, meta: Option<Arc<dyn Meta>>) -> CpuTimed<F> {
+    CpuTimed {
+      future,
+      cpu_ns,
+      meta,
+    }
+  }
+}
+
+#[cfg(feature = "cpu-time")]
+impl<F: Future> Future for CpuTimed<F> {
+  type Output = F::Output;
+
+  fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<F::Output> {
+    // this is safe because we never move future out of self
+    let this = unsafe { self.get_unchecked_mut() };
+    let future = unsafe { Pin::new_unchecked(&mut this.future) };
+
+    let started = Instant::now();
+    let poll = future.poll(cx);
+    let elapsed = started.elapsed();
+
+    this
+      .cpu_ns
+      .fetch_add(elapsed.as_nanos() as u64, Ordering::Relaxed);
+    if let Some(handler) = &config::get().cpu_time_handler {
+      // deref the Arc, it is Meta too
+      handler(this.meta.as_ref().map(|m| (**m).as_any()), elapsed);
+    }
+
+    poll
+  }
+}
+
+/// One steal attempt of a machine from another processor's machine, see
+/// [`take_steal_events`].
+///
+/// [`take_steal_events`]: fn.take_steal_events.html
+#[cfg(feature = "steal-trace")]
+#[derive(Clone, Copy, Debug)]
+pub struct StealEvent {
+  pub at: Instant,
+
+  /// Id of the machine that steal.
+  pub thief_machine: usize,
+
+  /// Index of the processor that is held by the thief.
+  pub thief_processor: usize,
+
+  /// Index of the processor whose machine is stolen from.
+  pub victim_processor: usize,
+
+  /// At least one task is stolen.
+  pub success: bool,
+}
+
+// the last recorded steal attempts, see take_steal_events
+#[cfg(feature = "steal-trace")]
+const STEAL_EVENTS_CAPACITY: usize = 4096;
+
+#[cfg(feature = "steal-trace")]
+static STEAL_EVENTS: Lazy<Mutex<VecDeque<StealEvent>>> =
+  Lazy::new(|| Mutex::new(VecDeque::with_capacity(STEAL_EVENTS_CAPACITY)));
+
+#[cfg(feature = "steal-trace")]
+thread_local! {
+  // (machine id, processor index) of the machine running on this thread
+  static THIEF: Cell<(usize, usize)> = const { Cell::new((usize::MAX, usize::MAX)) };
+}
+
+// called when the machine start, on its own thread
+#[cfg(feature = "steal-trace")]
+pub(crate) fn set_thief(machine: usize, processor: usize) {
+  THIEF.with(|t| t.set((machine, processor)));
+}
+
+#[cfg(feature = "steal-trace")]
+pub(crate) fn record_steal(victim: usize, success: bool) {
+  let (thief_machine, thief_processor) = THIEF.with(|t| t.get());
+  let event = StealEvent {
+    at: Instant::now(),
+    thief_machine,
+    thief_processor,
+    victim_processor: victim,
+    success,
+  };
+
+  let mut events = STEAL_EVENTS.lock().unwrap();
+  if events.len() == STEAL_EVENTS_CAPACITY {
+    events.pop_front();
+  }
+  events.push_back(event);
+}
+
+/// Take the recorded steal attempts, oldest first, the buffer is emptied.
+///
+/// Only the last 4096 attempts are kept, older ones are dropped, take them
+/// often enough for a complete trace. Every steal attempt take a global lock
+/// to record, so this is for research and tuning, not for production.
+///
+/// Only available with `steal-trace` feature.
+#[cfg(feature = "steal-trace")]
+pub fn take_steal_events() -> Vec<StealEvent> {
+  STEAL_EVENTS.lock().unwrap().drain(..).collect()
+}
+
+/// Completion of a task, see [`take_completion_events`].
+///
+/// [`take_completion_events`]: fn.take_completion_events.html
+#[cfg(feature = "order-trace")]
+#[derive(Clone, Copy, Debug)]
+pub struct CompletionEvent {
+  pub at: Instant,
+
+  /// Id of the task, it is assigned in spawn order, so it is also the
+  /// submission sequence number.
+  pub task_id: usize,
+
+  /// Where the task is spawned.
+  pub location: &'static Location<'static>,
+
+  /// Completion sequence number, 0 for the first task completed since the
+  /// executor is started.
+  pub completion_seq: usize,
+
+  /// The task return its output, not panicked or cancelled.
+  pub ok: bool,
+}
+
+// the last completed tasks, see take_completion_events
+#[cfg(feature = "order-trace")]
+const COMPLETION_EVENTS_CAPACITY: usize = 4096;
+
+#[cfg(feature = "order-trace")]
+static COMPLETION_EVENTS: Lazy<Mutex<VecDeque<CompletionEvent>>> =
+  Lazy::new(|| Mutex::new(VecDeque::with_capacity(COMPLETION_EVENTS_CAPACITY)));
+
+#[cfg(feature = "order-trace")]
+static COMPLETION_SEQ: AtomicUsize = AtomicUsize::new(0);
+
+// record the completion of the task, see take_completion_events
+#[cfg(feature = "order-trace")]
+pub(crate) struct Completion<F> {
+  future: F,
+  task_id: usize,
+  location: &'static Location<'static>,
+}
+
+#[cfg(feature = "order-trace")]
+impl<F> Completion<F> {
+  pub(crate) fn new(
+    future: F,
+    task_id: usize,
+    location: &'static Location<'static>,
+  ) -> Completion<F> {
+    Completion {
+      future,
+      task_id,
+      location,
+    }
+  }
+}
+
+#[cfg(feature = "order-trace")]
+impl<F, T> Future for Completion<F>
+where
+  F: Future<Output = Result<T, JoinError>>,
+{
+  type Output = Result<T, JoinError>;
+
+  fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+    // this is safe because we never move future out of self
+    let this = unsafe { self.get_unchecked_mut() };
+    let future = unsafe { Pin::new_unchecked(&mut this.future) };
+
+    let output = match future.poll(cx) {
+      Poll::Ready(output) => output,
+      Poll::Pending => return Poll::Pending,
+    };
+
+    // take the sequence number under the lock,
+    // so the buffer is ordered by completion
+    let mut events = COMPLETION_EVENTS.lock().unwrap();
+    if events.len() == COMPLETION_EVENTS_CAPACITY {
+      events.pop_front();
+    }
+    events.push_back(CompletionEvent {
+      at: Instant::now(),
+      task_id: this.task_id,
+      location: this.location,
+      completion_seq: COMPLETION_SEQ.fetch_add(1, Ordering::Relaxed),
+      ok: output.is_ok(),
+    });
+    drop(events);
+
+    Poll::Ready(output)
+  }
+}
+
+/// Take the recorded task completions, in completion order, the buffer is
+/// emptied.
+///
+/// Compare [`task_id`] (submission order) with [`completion_seq`] to see how
+/// much the tasks are reordered, e.g. by work stealing or priorities. The
+/// executor doesn't guarantee any order, even for tasks spawned from the same
+/// task, use [`OrderedSpawner`] if the order matter.
+///
+/// Only the last 4096 completions are kept, older ones are dropped, take
+/// them often enough for a complete trace. Every completion take a global
+/// lock to record, so this is for debugging, not for production. Tasks that
+/// are dropped without completing (e.g. on shutdown) are not recorded,
+/// neither are [`spawn_blocking`] tasks.
+///
+/// Only available with `order-trace` feature.
+///
+/// [`task_id`]: struct.CompletionEvent.html#structfield.task_id
+/// [`completion_seq`]: struct.CompletionEvent.html#structfield.completion_seq
+/// [`OrderedSpawner`]: ../struct.OrderedSpawner.html
+/// [`spawn_blocking`]: ../fn.spawn_blocking.html
+#[cfg(feature = "order-trace")]
+pub fn take_completion_events() -> Vec<CompletionEvent> {
+  COMPLETION_EVENTS.lock().unwrap().drain(..).collect()
+}
+
+/// Lifecycle event of a task, see [`take_task_events`].
+///
+/// [`take_task_events`]: fn.take_task_events.html
+#[cfg(feature = "event-log")]
+#[derive(Clone, Copy, Debug)]
+pub struct TaskEvent {
+  pub at: Instant,
+
+  /// Id of the task, see [`JoinHandle::task_id`].
+  ///
+  /// [`JoinHandle::task_id`]: ../struct.JoinHandle.html#method.task_id
+  pub task_id: usize,
+
+  pub kind: TaskEventKind,
+}
+
+/// What happened to the task, see [`TaskEvent`].
+///
+/// A task is created once, then for every time it is woken up: enqueued,
+/// dequeued, and ran. Completed is recorded inside its last run, so it come
+/// before the ran event of that run.
+///
+/// [`TaskEvent`]: struct.TaskEvent.html
+#[cfg(feature = "event-log")]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TaskEventKind {
+  /// The task is spawned.
+  Created,
+
+  /// The task is pushed to the global queue of the processor.
+  Enqueued { processor: usize },
+
+  /// The task is taken by the machine (thread) that hold the processor, to
+  /// be run.
+  Dequeued { machine: usize, processor: usize },
+
+  /// The task is run (polled) once, for this long.
+  Ran { duration: Duration },
+
+  /// The task is completed (returned its output, panicked, or cancelled
+  /// while running).
+  Completed,
+}
+
+// the last recorded task events, see take_task_events
+#[cfg(feature = "event-log")]
+const TASK_EVENTS_CAPACITY: usize = 4096;
+
+#[cfg(feature = "event-log")]
+static TASK_EVENTS: Lazy<Mutex<VecDeque<TaskEvent>>> =
+  Lazy::new(|| Mutex::new(VecDeque::with_capacity(TASK_EVENTS_CAPACITY)));
+
+#[cfg(feature = "event-log")]
+pub(crate) fn record_task_event(task_id: usize, kind: TaskEventKind) {
+  let event = TaskEvent {
+    at: Instant::now(),
+    task_id,
+    kind,
+  };
+
+  if let Some(sink) = &config::get().task_event_sink {
+    sink(&event);
+    return;
+  }
+
+  let mut events = TASK_EVENTS.lock().unwrap();
+  if events.len() == TASK_EVENTS_CAPACITY {
+    events.pop_front();
+  }
+  events.push_back(event);
+}
+
+// record the completion of the task, see TaskEventKind::Completed
+#[cfg(feature = "event-log")]
+pub(crate) struct Logged<F> {
+  future: F,
+  task_id: usize,
+}
+
+#[cfg(feature = "event-log")]
+impl<F> Logged<F> {
+  pub(crate) fn new(future: F, task_id: usize) -> Logged<F> {
+    Logged { future, task_id }
+  }
+}
+
+#[cfg(feature = "event-log")]
+impl<F: Future> Future for Logged<F> {
+  type Output = F::Output;
+
+  fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<F::Output> {
+    // this is safe because we never move future out of self
+    let this = unsafe { self.get_unchecked_mut() };
+    let future = unsafe { Pin::new_unchecked(&mut this.future) };
+
+    let poll = future.poll(cx);
+    if poll.is_ready() {
+      record_task_event(this.task_id, TaskEventKind::Completed);
+    }
+    poll
+  }
+}
+
+/// Take the recorded task lifecycle events, oldest first, the buffer is
+/// emptied.
+///
+/// Together with [`take_steal_events`], this is enough to draw a timeline of
+/// which task run where and when, e.g. group the events by processor or by
+/// task. Events of different threads are recorded under a global lock, so
+/// they are in order. Tasks of [`spawn_blocking`] are not recorded, and
+/// tasks that are not run by the processors (e.g. [`spawn_dedicated`] and
+/// [`StepExecutor`]) only record their completion.
+///
+/// Only the last 4096 events are kept, older ones are dropped, take them
+/// often enough for a complete log, or use [`Builder::task_event_sink`].
+/// Every event take a global lock to record, so this is for research and
+/// visualization, not for production.
+///
+/// Only available with `event-log` feature.
+///
+/// [`take_steal_events`]: fn.take_steal_events.html
+/// [`spawn_blocking`]: ../fn.spawn_blocking.html
+/// [`spawn_dedicated`]: ../fn.spawn_dedicated.html
+/// [`StepExecutor`]: ../struct.StepExecutor.html
+/// [`Builder::task_event_sink`]: ../struct.Builder.html#method.task_event_sink
+#[cfg(feature = "event-log")]
+pub fn take_task_events() -> Vec<TaskEvent> {
+  TASK_EVENTS.lock().unwrap().drain(..).collect()
+}
@@ -0,0 +1,169 @@
+use std::collections::{BTreeSet, HashMap};
+use std::future::Future;
+use std::panic::Location;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+
+use crate::executor::{self, JoinHandle, TaskTag};
+
+/// Spawner that start its tasks in submission order.
+///
+/// Tasks spawned with [`spawn`] have no order, the task may be stolen by
+/// other processor and run before the tasks that is spawned earlier. Tasks
+/// spawned with the same spawner (or its clones) are run by the machines as
+/// usual, but the guarantee is:
+///
+/// - a task is first polled only after the first poll of all tasks spawned
+///   before it (with the same spawner) is returned, or they are dropped
+///   without being polled (e.g. cancelled, or rejected while shutting down).
+///
+/// So the tasks run in submission order until their first `.await` that is
+/// not ready, after that the tasks run concurrently, and a task that yield
+/// may complete after the tasks spawned later. For strictly one at a time
+/// processing, process the items in a single task instead.
+///
+/// Task that is ready, but waiting for its turn, doesn't occupy a processor,
+/// it is woken when the previous task yield or complete.
+///
+/// [`spawn`]: fn.spawn.html
+#[derive(Clone, Default)]
+pub struct OrderedSpawner {
+  state: Arc<Mutex<State>>,
+}
+
+#[derive(Default)]
+struct State {
+  // ticket number for the next spawned task
+  issued: u64,
+
+  // ticket that is allowed to start
+  next: u64,
+
+  // tickets that is dropped before their turn
+  skipped: BTreeSet<u64>,
+
+  // tickets waiting for their turn
+  waiters: HashMap<u64, Waker>,
+}
+
+impl State {
+  // the current ticket is started or dropped, let the next one start
+  fn advance(&mut self) -> Option<Waker> {
+    self.next += 1;
+    while self.skipped.remove(&self.next) {
+      self.next += 1;
+    }
+    self.waiters.remove(&self.next)
+  }
+}
+
+impl OrderedSpawner {
+  pub fn new() -> OrderedSpawner {
+    OrderedSpawner::default()
+  }
+
+  /// Same as [`spawn`], but the task is started after the tasks spawned
+  /// before it.
+  ///
+  /// [`spawn`]: fn.spawn.html
+  #[track_caller]
+  pub fn spawn<F, T>(&self, f: F) -> JoinHandle<T>
+  where
+    F: Future<Output = T> + Send + 'static,
+    T: Send + 'static,
+  {
+    let number = {
+      let mut state = self.state.lock().unwrap();
+      let number = state.issued;
+      state.issued += 1;
+      number
+    };
+
+    let f = InOrder {
+      future: f,
+      state: self.state.clone(),
+      number,
+      started: false,
+    };
+
+    let tag = TaskTag::new(
+      executor::task_size::<F>(),
+      Location::caller(),
+      executor::inherited_priority(),
+    );
+    executor::spawn_tag(f, tag)
+  }
+}
+
+impl std::fmt::Debug for OrderedSpawner {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    let state = self.state.lock().unwrap();
+    f.debug_struct("OrderedSpawner")
+      .field("spawned", &state.issued)
+      .field("started", &state.next)
+      .finish()
+  }
+}
+
+// wait for the turn of the task, the turn is passed after the first poll,
+// or when it is dropped
+struct InOrder<F> {
+  future: F,
+  state: Arc<Mutex<State>>,
+  number: u64,
+  started: bool,
+}
+
+impl<F: Future> Future for InOrder<F> {
+  type Output = F::Output;
+
+  fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<F::Output> {
+    // this is safe because we never move future out of self
+    let this = unsafe { self.get_unchecked_mut() };
+    let future = unsafe { Pin::new_unchecked(&mut this.future) };
+
+    if this.started {
+      return future.poll(cx);
+    }
+
+    {
+      let mut state = this.state.lock().unwrap();
+      if state.next != this.number {
+        state.waiters.insert(this.number, cx.waker().clone());
+        return Poll::Pending;
+      }
+    }
+
+    // if this panic, the turn is passed in drop
+    let output = future.poll(cx);
+    this.started = true;
+
+    let next = this.state.lock().unwrap().advance();
+    if let Some(waker) = next {
+      waker.wake();
+    }
+    output
+  }
+}
+
+impl<F> Drop for InOrder<F> {
+  fn drop(&mut self) {
+    if self.started {
+      return;
+    }
+
+    let mut state = self.state.lock().unwrap();
+    state.waiters.remove(&self.number);
+    if state.next != self.number {
+      state.skipped.insert(self.number);
+      return;
+    }
+
+    let next = state.advance();
+    drop(state);
+    if let Some(waker) = next {
+      waker.wake();
+    }
+  }
+}
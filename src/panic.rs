@@ -0,0 +1,304 @@
+use std::any::Any;
+use std::backtrace::Backtrace;
+use std::cell::{Cell, RefCell};
+use std::future::Future;
+use std::panic::{self, AssertUnwindSafe, Location};
+use std::pin::Pin;
+use std::sync::Once;
+use std::task::{Context, Poll};
+
+use crate::config;
+
+thread_local! {
+  // set while polling a task that catch its panic,
+  // so the panic hook know when to capture the backtrace
+  static CATCHING: Cell<bool> = const { Cell::new(false) };
+
+  // backtrace captured by the panic hook
+  static BACKTRACE: RefCell<Option<Backtrace>> = const { RefCell::new(None) };
+}
+
+// chain our hook to the existing one, to capture the backtrace of panicking
+// task, it must be done in the hook, because the stack is already unwound
+// when catch_unwind return
+fn install_hook() {
+  static INSTALL: Once = Once::new();
+  INSTALL.call_once(|| {
+    let prev = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+      if CATCHING.with(|c| c.get()) {
+        BACKTRACE.with(|b| *b.borrow_mut() = Some(Backtrace::capture()));
+      }
+      prev(info);
+    }));
+  });
+}
+
+/// Error returned when awaiting [`JoinHandle`] of a task that did not complete.
+///
+/// [`JoinHandle`]: struct.JoinHandle.html
+pub struct JoinError {
+  task_id: usize,
+  location: &'static Location<'static>,
+  repr: Repr,
+}
+
+enum Repr {
+  Panicked {
+    payload: Box<dyn Any + Send>,
+    backtrace: Option<Backtrace>,
+  },
+  Cancelled,
+  #[cfg(feature = "registry")]
+  TimedOut,
+}
+
+impl JoinError {
+  pub(crate) fn cancelled(task_id: usize, location: &'static Location<'static>) -> JoinError {
+    JoinError {
+      task_id,
+      location,
+      repr: Repr::Cancelled,
+    }
+  }
+
+  #[cfg(feature = "registry")]
+  pub(crate) fn timed_out(task_id: usize, location: &'static Location<'static>) -> JoinError {
+    JoinError {
+      task_id,
+      location,
+      repr: Repr::TimedOut,
+    }
+  }
+
+  /// The task is panicked.
+  ///
+  /// Only possible with [`PanicPolicy::Catch`], or for [`spawn_blocking`].
+  ///
+  /// [`PanicPolicy::Catch`]: enum.PanicPolicy.html#variant.Catch
+  /// [`spawn_blocking`]: fn.spawn_blocking.html
+  pub fn is_panic(&self) -> bool {
+    matches!(self.repr, Repr::Panicked { .. })
+  }
+
+  /// The task is cancelled before it complete.
+  pub fn is_cancelled(&self) -> bool {
+    matches!(self.repr, Repr::Cancelled)
+  }
+
+  /// The task is cancelled because it is alive longer than
+  /// [`Builder::max_task_runtime`].
+  ///
+  /// Only available with `registry` feature.
+  ///
+  /// [`Builder::max_task_runtime`]: struct.Builder.html#method.max_task_runtime
+  #[cfg(feature = "registry")]
+  pub fn is_timeout(&self) -> bool {
+    matches!(self.repr, Repr::TimedOut)
+  }
+
+  /// Id of the task, unique for the lifetime of the process.
+  pub fn task_id(&self) -> usize {
+    self.task_id
+  }
+
+  /// Where the task was spawned.
+  pub fn location(&self) -> &'static Location<'static> {
+    self.location
+  }
+
+  /// Backtrace of the panic.
+  ///
+  /// The backtrace is captured according to `RUST_BACKTRACE` and
+  /// `RUST_LIB_BACKTRACE` environment variable, see [`Backtrace::capture`].
+  ///
+  /// [`Backtrace::capture`]: https://doc.rust-lang.org/std/backtrace/struct.Backtrace.html#method.capture
+  pub fn backtrace(&self) -> Option<&Backtrace> {
+    match &self.repr {
+      Repr::Panicked { backtrace, .. } => backtrace.as_ref(),
+      Repr::Cancelled => None,
+      #[cfg(feature = "registry")]
+      Repr::TimedOut => None,
+    }
+  }
+
+  /// Panic message, if the payload is a string.
+  pub fn panic_message(&self) -> Option<&str> {
+    match &self.repr {
+      Repr::Panicked { payload, .. } => payload
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| payload.downcast_ref::<String>().map(|s| s.as_str())),
+      Repr::Cancelled => None,
+      #[cfg(feature = "registry")]
+      Repr::TimedOut => None,
+    }
+  }
+
+  /// Panic payload, to be downcasted, see [`panic_message`] for the common
+  /// string payload.
+  ///
+  /// Return `None` if the task is not panicked.
+  ///
+  /// [`panic_message`]: #method.panic_message
+  pub fn panic_payload(&self) -> Option<&(dyn Any + Send)> {
+    match &self.repr {
+      Repr::Panicked { payload, .. } => Some(&**payload),
+      Repr::Cancelled => None,
+      #[cfg(feature = "registry")]
+      Repr::TimedOut => None,
+    }
+  }
+
+  /// Consume the error, return the panic payload.
+  ///
+  /// Can be used with `std::panic::resume_unwind` to propagate the panic.
+  ///
+  /// Panic if the task is not panicked.
+  pub fn into_panic(self) -> Box<dyn Any + Send> {
+    match self.repr {
+      Repr::Panicked { payload, .. } => payload,
+      Repr::Cancelled => panic!("the task is not panicked"),
+      #[cfg(feature = "registry")]
+      Repr::TimedOut => panic!("the task is not panicked"),
+    }
+  }
+}
+
+impl std::fmt::Display for JoinError {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match &self.repr {
+      Repr::Panicked { .. } => write!(
+        f,
+        "task {} spawned at {} panicked: {}",
+        self.task_id,
+        self.location,
+        self.panic_message().unwrap_or("Box<dyn Any>")
+      ),
+      Repr::Cancelled => write!(
+        f,
+        "task {} spawned at {} is cancelled",
+        self.task_id, self.location
+      ),
+      #[cfg(feature = "registry")]
+      Repr::TimedOut => write!(
+        f,
+        "task {} spawned at {} is cancelled, it run longer than the maximum runtime",
+        self.task_id, self.location
+      ),
+    }
+  }
+}
+
+impl std::fmt::Debug for JoinError {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    f.debug_struct("JoinError")
+      .field("task_id", &self.task_id)
+      .field("location", &self.location)
+      .field("panic", &self.is_panic())
+      .field("message", &self.panic_message())
+      .finish()
+  }
+}
+
+impl std::error::Error for JoinError {}
+
+// wrap the task future, to catch its panic and turn it into JoinError
+pub(crate) struct CatchUnwind<F> {
+  future: F,
+  task_id: usize,
+  location: &'static Location<'static>,
+
+  // when false, the panic is propagated
+  catch: bool,
+}
+
+impl<F> CatchUnwind<F> {
+  pub(crate) fn new(
+    future: F,
+    task_id: usize,
+    location: &'static Location<'static>,
+    catch: bool,
+  ) -> CatchUnwind<F> {
+    if catch {
+      install_hook();
+    }
+    CatchUnwind {
+      future,
+      task_id,
+      location,
+      catch,
+    }
+  }
+}
+
+impl<F: Future> Future for CatchUnwind<F> {
+  type Output = Result<F::Output, JoinError>;
+
+  fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+    // this is safe because we never move future out of self
+    let this = unsafe { self.get_unchecked_mut() };
+    let future = unsafe { Pin::new_unchecked(&mut this.future) };
+
+    let catch = this.catch;
+    let prev = CATCHING.with(|c| c.replace(catch));
+    let result = panic::catch_unwind(AssertUnwindSafe(|| future.poll(cx)));
+    CATCHING.with(|c| c.set(prev));
+
+    #[cfg(feature = "registry")]
+    crate::executor::mark_current_polled(!matches!(result, Ok(Poll::Pending)));
+
+    match result {
+      Ok(Poll::Ready(output)) => Poll::Ready(Ok(output)),
+      Ok(Poll::Pending) => Poll::Pending,
+      Err(payload) => {
+        // the panic message is already printed by the panic hook,
+        // abort here, so the panic never reach the machine,
+        // see MachinePanicPolicy
+        if !catch {
+          std::process::abort();
+        }
+
+        let err = JoinError {
+          task_id: this.task_id,
+          location: this.location,
+          repr: Repr::Panicked {
+            payload,
+            backtrace: BACKTRACE.with(|b| b.borrow_mut().take()),
+          },
+        };
+
+        #[cfg(feature = "panic-log")]
+        log::error!(
+          "{}",
+          match &config::get().panic_log_format {
+            Some(format) => format(&err),
+            None => default_log_format(&err),
+          }
+        );
+
+        if let Some(handler) = &config::get().panic_handler {
+          handler(&err);
+        }
+
+        Poll::Ready(Err(err))
+      }
+    }
+  }
+}
+
+#[cfg(feature = "panic-log")]
+fn default_log_format(err: &JoinError) -> String {
+  let thread = std::thread::current();
+  let mut message = match thread.name() {
+    Some(name) => format!("lelet: {} on thread {}", err, name),
+    None => format!("lelet: {} on thread {:?}", err, thread.id()),
+  };
+
+  if let Some(backtrace) = err.backtrace() {
+    if backtrace.status() == std::backtrace::BacktraceStatus::Captured {
+      message.push_str(&format!("\n{}", backtrace));
+    }
+  }
+  message
+}
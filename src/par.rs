@@ -0,0 +1,79 @@
+use std::future::Future;
+use std::panic;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+use crate::executor::spawn;
+
+/// Run `f` for every item, with at most `concurrency` of them running at the
+/// same time, and wait until all of them are done.
+///
+/// Each item is run in its own task (`concurrency` tasks are spawned, each
+/// take the next item when it is done with the previous one). On the first
+/// error, no new item is started, the running ones are still waited, and the
+/// error is returned.
+///
+/// Panic inside `f` is propagated to the caller, if it is caught
+/// (see [`PanicPolicy`]).
+///
+/// [`PanicPolicy`]: enum.PanicPolicy.html
+pub async fn par_for_each<I, F, Fut, E>(items: I, concurrency: usize, f: F) -> Result<(), E>
+where
+  I: IntoIterator,
+  I::IntoIter: Send + 'static,
+  I::Item: Send + 'static,
+  F: Fn(I::Item) -> Fut + Send + Sync + 'static,
+  Fut: Future<Output = Result<(), E>> + Send + 'static,
+  E: Send + 'static,
+{
+  let items = Arc::new(Mutex::new(items.into_iter()));
+  let failed = Arc::new(AtomicBool::new(false));
+  let f = Arc::new(f);
+
+  let handles: Vec<_> = (0..std::cmp::max(1, concurrency))
+    .map(|_| {
+      let items = items.clone();
+      let failed = failed.clone();
+      let f = f.clone();
+      spawn(async move {
+        loop {
+          if failed.load(Ordering::Relaxed) {
+            return Ok(());
+          }
+
+          // do not hold the lock while running f
+          let item = items.lock().unwrap().next();
+          let item = match item {
+            Some(item) => item,
+            None => return Ok(()),
+          };
+
+          if let Err(err) = f(item).await {
+            failed.store(true, Ordering::Relaxed);
+            return Err(err);
+          }
+        }
+      })
+    })
+    .collect();
+
+  let mut result = Ok(());
+  for handle in handles {
+    match handle.await {
+      Ok(Ok(())) => {}
+      Ok(Err(err)) => {
+        // keep the first error, but still wait the rest
+        if result.is_ok() {
+          result = Err(err);
+        }
+      }
+      Err(err) => {
+        if err.is_panic() {
+          panic::resume_unwind(err.into_panic());
+        }
+      }
+    }
+  }
+
+  result
+}
@@ -0,0 +1,161 @@
+use std::any::Any;
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::future::Future;
+use std::hash::Hash;
+use std::sync::{Arc, Condvar, Mutex};
+
+use crate::executor::{self, JoinHandle};
+
+/// Limit how many tasks of each tenant can be alive at once.
+///
+/// Tasks are spawned like [`spawn_tagged`], with the tenant as the metadata,
+/// so it is available with [`current_task_meta`] and shown in
+/// [`task_dump`]. The task count toward its tenant quota until it is
+/// completed or cancelled (its future is dropped), awaiting the
+/// [`JoinHandle`] is not needed.
+///
+/// Past the quota, [`spawn`] block until a task of the tenant is done, and
+/// [`try_spawn`] return error. Other tenants are not affected, so one tenant
+/// can't flood the executor.
+///
+/// Clones share the same counters.
+///
+/// [`spawn_tagged`]: fn.spawn_tagged.html
+/// [`current_task_meta`]: fn.current_task_meta.html
+/// [`task_dump`]: fn.task_dump.html
+/// [`JoinHandle`]: struct.JoinHandle.html
+/// [`spawn`]: #method.spawn
+/// [`try_spawn`]: #method.try_spawn
+pub struct TenantQuota<K> {
+  inner: Arc<Inner<K>>,
+}
+
+struct Inner<K> {
+  limit: usize,
+
+  // alive tasks of each tenant, tenant with no alive task is removed
+  alive: Mutex<HashMap<K, usize>>,
+  cond: Condvar,
+}
+
+impl<K> TenantQuota<K>
+where
+  K: Hash + Eq + Clone + Any + Send + Sync + Debug,
+{
+  /// Allow `limit` alive tasks per tenant, zero is treated as 1.
+  pub fn new(limit: usize) -> TenantQuota<K> {
+    TenantQuota {
+      inner: Arc::new(Inner {
+        limit: std::cmp::max(1, limit),
+        alive: Mutex::new(HashMap::new()),
+        cond: Condvar::new(),
+      }),
+    }
+  }
+
+  /// Spawn the task for `tenant`, block until the tenant is under quota.
+  ///
+  /// Blocking inside a task is fine, the executor detect it, as with any
+  /// other blocking operation, but the task is not cancellable meanwhile.
+  #[track_caller]
+  pub fn spawn<F, T>(&self, tenant: K, f: F) -> JoinHandle<T>
+  where
+    F: Future<Output = T> + Send + 'static,
+    T: Send + 'static,
+  {
+    let inner = &self.inner;
+    let mut alive = inner.alive.lock().unwrap();
+    while alive.get(&tenant).copied().unwrap_or(0) >= inner.limit {
+      alive = inner.cond.wait(alive).unwrap();
+    }
+    *alive.entry(tenant.clone()).or_insert(0) += 1;
+    drop(alive);
+
+    self.spawn_counted(tenant, f)
+  }
+
+  /// Spawn the task for `tenant`, return error if the tenant is at quota.
+  #[track_caller]
+  pub fn try_spawn<F, T>(&self, tenant: K, f: F) -> Result<JoinHandle<T>, String>
+  where
+    F: Future<Output = T> + Send + 'static,
+    T: Send + 'static,
+  {
+    let inner = &self.inner;
+    let mut alive = inner.alive.lock().unwrap();
+    let count = alive.entry(tenant.clone()).or_insert(0);
+    if *count >= inner.limit {
+      return Err(format!(
+        "tenant {:?} has reached its quota ({} tasks)",
+        tenant, inner.limit
+      ));
+    }
+    *count += 1;
+    drop(alive);
+
+    Ok(self.spawn_counted(tenant, f))
+  }
+
+  /// Number of alive tasks of `tenant`.
+  pub fn alive(&self, tenant: &K) -> usize {
+    let alive = self.inner.alive.lock().unwrap();
+    alive.get(tenant).copied().unwrap_or(0)
+  }
+
+  // the slot is already taken, release it when the future is dropped
+  #[track_caller]
+  fn spawn_counted<F, T>(&self, tenant: K, f: F) -> JoinHandle<T>
+  where
+    F: Future<Output = T> + Send + 'static,
+    T: Send + 'static,
+  {
+    let release = Release {
+      inner: self.inner.clone(),
+      tenant: tenant.clone(),
+    };
+    executor::spawn_tagged(tenant, async move {
+      let _release = release;
+      f.await
+    })
+  }
+}
+
+impl<K> Clone for TenantQuota<K> {
+  fn clone(&self) -> TenantQuota<K> {
+    TenantQuota {
+      inner: self.inner.clone(),
+    }
+  }
+}
+
+impl<K: Debug> Debug for TenantQuota<K> {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    f.debug_struct("TenantQuota")
+      .field("limit", &self.inner.limit)
+      .field("alive", &*self.inner.alive.lock().unwrap())
+      .finish()
+  }
+}
+
+// outside of the async block, so it is dropped even if never polled
+struct Release<K: Hash + Eq> {
+  inner: Arc<Inner<K>>,
+  tenant: K,
+}
+
+impl<K: Hash + Eq> Drop for Release<K> {
+  fn drop(&mut self) {
+    let mut alive = self.inner.alive.lock().unwrap();
+    if let Some(count) = alive.get_mut(&self.tenant) {
+      *count -= 1;
+      if *count == 0 {
+        alive.remove(&self.tenant);
+      }
+    }
+    drop(alive);
+
+    // waiters may wait for different tenants
+    self.inner.cond.notify_all();
+  }
+}
@@ -0,0 +1,20 @@
+//! Scheduler internals, for custom scheduling experiments.
+//!
+//! This bypass the normal task placement, use it only if you know what you
+//! are doing. The invariants are still kept by the API:
+//!
+//! * A [`RawTask`] is owned, so it can't be run and queued at the same time,
+//!   and it must be either run or injected back, dropping it cancel the task.
+//! * Queued tasks accounting (see [`queued_tasks`]) is updated when the task
+//!   is taken out by [`RawTask::run`] or [`RawTask::inject`].
+//! * The executor keep using its own placement and stealing, a task that is
+//!   injected to a processor may still be stolen by other processor.
+//!
+//! Only available with `raw` feature.
+//!
+//! [`RawTask`]: struct.RawTask.html
+//! [`RawTask::run`]: struct.RawTask.html#method.run
+//! [`RawTask::inject`]: struct.RawTask.html#method.inject
+//! [`queued_tasks`]: ../fn.queued_tasks.html
+
+pub use crate::executor::{drain_all, spawn_to, steal_from_machine, steal_from_processor, RawTask};
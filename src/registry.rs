@@ -0,0 +1,349 @@
+use std::any::Any;
+use std::collections::HashMap;
+use std::future::Future;
+use std::panic::Location;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU8, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::time::Duration;
+
+use once_cell::sync::Lazy;
+
+use crate::executor::TaskTag;
+use crate::panic::JoinError;
+
+// singleton: REGISTRY, all live tasks, keyed by task id
+static REGISTRY: Lazy<Mutex<HashMap<usize, TagPtr>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+// this is safe because the tag is removed from the registry (while holding
+// the lock) before it is dropped, so the pointer is valid while it is in the
+// registry
+struct TagPtr(*const TaskTag);
+unsafe impl Send for TagPtr {}
+
+const QUEUED: u8 = 0;
+const RUNNING: u8 = 1;
+const IDLE: u8 = 2;
+const COMPLETED: u8 = 3;
+
+// state of the task, stored in the task tag
+pub(crate) struct State(AtomicU8);
+
+impl State {
+  pub(crate) fn new() -> State {
+    State(AtomicU8::new(IDLE))
+  }
+
+  pub(crate) fn set_queued(&self) {
+    self.0.store(QUEUED, Ordering::Relaxed);
+  }
+
+  pub(crate) fn set_running(&self) {
+    self.0.store(RUNNING, Ordering::Relaxed);
+  }
+
+  // if the task is rescheduled while running, it is queued, not idle
+  pub(crate) fn set_idle(&self) {
+    let _ = self
+      .0
+      .compare_exchange(RUNNING, IDLE, Ordering::Relaxed, Ordering::Relaxed);
+  }
+
+  pub(crate) fn set_completed(&self) {
+    self.0.store(COMPLETED, Ordering::Relaxed);
+  }
+
+  fn get(&self) -> TaskState {
+    match self.0.load(Ordering::Relaxed) {
+      QUEUED => TaskState::Queued,
+      RUNNING => TaskState::Running,
+      COMPLETED => TaskState::Completed,
+      _ => TaskState::Idle,
+    }
+  }
+}
+
+// reason of Cancel
+const NOT_CANCELLED: u8 = 0;
+const CANCELLED: u8 = 1;
+const TIMED_OUT: u8 = 2;
+
+// cancellation request, stored in the task tag, see cancel_matching and
+// Builder::max_task_runtime
+#[derive(Default)]
+pub(crate) struct Cancel {
+  reason: AtomicU8,
+
+  // the task's waker, from the last poll
+  waker: Mutex<Option<Waker>>,
+}
+
+impl Cancel {
+  // cancel is called, the task may not be stopped yet
+  pub(crate) fn is_requested(&self) -> bool {
+    self.reason.load(Ordering::SeqCst) != NOT_CANCELLED
+  }
+
+  // return false if it is already cancelled
+  fn cancel(&self, reason: u8) -> bool {
+    if self
+      .reason
+      .compare_exchange(NOT_CANCELLED, reason, Ordering::SeqCst, Ordering::SeqCst)
+      .is_err()
+    {
+      return false;
+    }
+
+    // set the reason before taking the waker, see Cancellable::poll
+    let waker = self.waker.lock().unwrap().take();
+    if let Some(waker) = waker {
+      waker.wake();
+    }
+    true
+  }
+}
+
+// complete the task with cancelled error, on the next poll after Cancel::cancel
+pub(crate) struct Cancellable<F> {
+  future: F,
+  cancel: Arc<Cancel>,
+  task_id: usize,
+  location: &'static Location<'static>,
+}
+
+impl<F> Cancellable<F> {
+  pub(crate) fn new(
+    future: F,
+    cancel: Arc<Cancel>,
+    task_id: usize,
+    location: &'static Location<'static>,
+  ) -> Cancellable<F> {
+    Cancellable {
+      future,
+      cancel,
+      task_id,
+      location,
+    }
+  }
+}
+
+impl<F, T> Future for Cancellable<F>
+where
+  F: Future<Output = Result<T, JoinError>>,
+{
+  type Output = Result<T, JoinError>;
+
+  fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+    // this is safe because we never move future out of self
+    let this = unsafe { self.get_unchecked_mut() };
+
+    // store the waker before checking the reason, so cancel can't be missed
+    {
+      let mut waker = this.cancel.waker.lock().unwrap();
+      match &*waker {
+        Some(w) if w.will_wake(cx.waker()) => {}
+        _ => *waker = Some(cx.waker().clone()),
+      }
+    }
+
+    match this.cancel.reason.load(Ordering::SeqCst) {
+      NOT_CANCELLED => {}
+      TIMED_OUT => return Poll::Ready(Err(JoinError::timed_out(this.task_id, this.location))),
+      _ => return Poll::Ready(Err(JoinError::cancelled(this.task_id, this.location))),
+    }
+
+    let future = unsafe { Pin::new_unchecked(&mut this.future) };
+    future.poll(cx)
+  }
+}
+
+pub(crate) fn register(tag: &TaskTag) {
+  REGISTRY
+    .lock()
+    .unwrap()
+    .insert(tag.id, TagPtr(tag as *const TaskTag));
+}
+
+pub(crate) fn unregister(tag: &TaskTag) {
+  let mut registry = REGISTRY.lock().unwrap();
+  registry.remove(&tag.id);
+
+  // task that is dropped before it is completed, see testing module
+  if tag.state.0.load(Ordering::Relaxed) != COMPLETED && WATCHERS.load(Ordering::Relaxed) > 0 {
+    DROPPED.lock().unwrap().push(tag.id);
+  }
+}
+
+// cancel tasks that is alive longer than max, see Builder::max_task_runtime
+pub(crate) fn cancel_expired(max: Duration) {
+  let expired: Vec<Arc<Cancel>> = REGISTRY
+    .lock()
+    .unwrap()
+    .values()
+    .map(|TagPtr(tag)| unsafe { &**tag })
+    .filter(|tag| tag.state.get() != TaskState::Completed && tag.spawned_at.elapsed() > max)
+    .map(|tag| tag.cancel.clone())
+    .collect();
+
+  for cancel in expired {
+    cancel.cancel(TIMED_OUT);
+  }
+}
+
+// number of live testing::NoLeaks guard
+pub(crate) static WATCHERS: AtomicUsize = AtomicUsize::new(0);
+
+// id of tasks dropped before completed, only recorded while WATCHERS > 0
+pub(crate) static DROPPED: Lazy<Mutex<Vec<usize>>> = Lazy::new(|| Mutex::new(Vec::new()));
+
+// ids of live tasks, that is greater or equal than `since`
+pub(crate) fn live_since(since: usize) -> Vec<usize> {
+  let mut ids: Vec<usize> = REGISTRY
+    .lock()
+    .unwrap()
+    .keys()
+    .copied()
+    .filter(|&id| id >= since)
+    .collect();
+  ids.sort_unstable();
+  ids
+}
+
+/// State of the task, see [`TaskInfo`].
+///
+/// [`TaskInfo`]: struct.TaskInfo.html
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TaskState {
+  /// Waiting in the queue to be run.
+  Queued,
+
+  /// Currently running on a processor.
+  Running,
+
+  /// Waiting to be woken up.
+  Idle,
+
+  /// Completed, but the [`JoinHandle`] is still alive.
+  ///
+  /// [`JoinHandle`]: struct.JoinHandle.html
+  Completed,
+}
+
+/// Information about live task, see [`task_dump`].
+///
+/// [`task_dump`]: fn.task_dump.html
+#[derive(Clone, Debug)]
+pub struct TaskInfo {
+  pub id: usize,
+  pub location: &'static Location<'static>,
+  pub state: TaskState,
+
+  /// How many times the task is run (polled).
+  pub runs: usize,
+
+  pub trace_id: Option<u64>,
+  pub last_processor: Option<usize>,
+
+  /// Debug representation of the metadata, see [`spawn_tagged`].
+  ///
+  /// [`spawn_tagged`]: fn.spawn_tagged.html
+  pub meta: Option<String>,
+
+  /// How many times the task's waker is cloned by the future.
+  ///
+  /// Future that clone its waker on every poll, even when the waker is not
+  /// changed, is usually a performance smell.
+  ///
+  /// Only available with `waker-stats` feature.
+  #[cfg(feature = "waker-stats")]
+  pub waker_clones: usize,
+
+  /// How many times the task's waker is woken, including redundant wake
+  /// while the task is already queued.
+  ///
+  /// Only available with `waker-stats` feature.
+  #[cfg(feature = "waker-stats")]
+  pub waker_wakes: usize,
+}
+
+/// Snapshot of all live tasks, sorted by id.
+///
+/// Task is live until it is completed and its [`JoinHandle`] is dropped.
+///
+/// Only available with `registry` feature, because maintaining the registry
+/// add overhead to every spawn.
+///
+/// [`JoinHandle`]: struct.JoinHandle.html
+pub fn task_dump() -> Vec<TaskInfo> {
+  let registry = REGISTRY.lock().unwrap();
+  let mut tasks: Vec<TaskInfo> = registry
+    .values()
+    .map(|TagPtr(tag)| {
+      let tag = unsafe { &**tag };
+      TaskInfo {
+        id: tag.id,
+        location: tag.location,
+        state: tag.state.get(),
+        runs: tag.runs.load(Ordering::Relaxed),
+        trace_id: match tag.get_trace_id() {
+          0 => None,
+          id => Some(id),
+        },
+        last_processor: tag.get_last_processor(),
+        meta: tag.meta.as_ref().map(|meta| format!("{:?}", meta)),
+        #[cfg(feature = "waker-stats")]
+        waker_clones: tag.wake_counts.clones(),
+        #[cfg(feature = "waker-stats")]
+        waker_wakes: tag.wake_counts.wakes(),
+      }
+    })
+    .collect();
+  drop(registry);
+
+  tasks.sort_by_key(|t| t.id);
+  tasks
+}
+
+/// Cancel all live tasks whose metadata is of type `M` and match the
+/// predicate, and return how many tasks is cancelled.
+///
+/// This is for bulk teardown, e.g. cancel all tasks of a disconnected client,
+/// without tracking every [`JoinHandle`]. Cancelled task is dropped on its
+/// next poll, a running task is cancelled after it yield, and awaiting its
+/// [`JoinHandle`] return [`JoinError`] with [`JoinError::is_cancelled`].
+/// Completed task and task without metadata (see [`spawn_tagged`]) are
+/// skipped.
+///
+/// The predicate is called outside of the registry lock, it can spawn.
+///
+/// Only available with `registry` feature.
+///
+/// [`JoinHandle`]: struct.JoinHandle.html
+/// [`JoinError`]: struct.JoinError.html
+/// [`JoinError::is_cancelled`]: struct.JoinError.html#method.is_cancelled
+/// [`spawn_tagged`]: fn.spawn_tagged.html
+pub fn cancel_matching<M, P>(mut predicate: P) -> usize
+where
+  M: Any + Send + Sync,
+  P: FnMut(&M) -> bool,
+{
+  let candidates: Vec<(Arc<M>, Arc<Cancel>)> = REGISTRY
+    .lock()
+    .unwrap()
+    .values()
+    .filter_map(|TagPtr(tag)| {
+      let tag = unsafe { &**tag };
+      if tag.state.get() == TaskState::Completed {
+        return None;
+      }
+      let meta = tag.meta.clone()?.into_any().downcast::<M>().ok()?;
+      Some((meta, tag.cancel.clone()))
+    })
+    .collect();
+
+  candidates
+    .into_iter()
+    .filter(|(meta, cancel)| predicate(meta) && cancel.cancel(CANCELLED))
+    .count()
+}
@@ -0,0 +1,118 @@
+use std::future::Future;
+use std::time::Duration;
+
+use crate::timer::sleep;
+use crate::utils::{random_seed, Rng};
+
+/// Delay between attempts, see [`RetryPolicy`].
+///
+/// [`RetryPolicy`]: struct.RetryPolicy.html
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Backoff {
+  /// Wait the same duration before every retry.
+  Constant(Duration),
+
+  /// Wait the duration before the first retry, doubled on every retry.
+  Exponential(Duration),
+
+  /// Same as `Exponential`, but wait a random duration between zero and the
+  /// exponential delay, so many clients failing at the same time don't retry
+  /// at the same time.
+  ExponentialJitter(Duration),
+}
+
+/// How many times and how long to wait, for [`retry`] and [`retry_if`].
+///
+/// [`retry`]: fn.retry.html
+/// [`retry_if`]: fn.retry_if.html
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+  max_attempts: usize,
+  backoff: Backoff,
+  max_delay: Duration,
+}
+
+impl RetryPolicy {
+  /// Run at most `max_attempts` times (including the first one), zero is
+  /// treated as 1.
+  pub fn new(max_attempts: usize, backoff: Backoff) -> RetryPolicy {
+    RetryPolicy {
+      max_attempts: std::cmp::max(1, max_attempts),
+      backoff,
+      max_delay: Duration::from_secs(60),
+    }
+  }
+
+  /// Upper bound of the delay between attempts.
+  ///
+  /// Default is 60 seconds.
+  pub fn max_delay(mut self, max_delay: Duration) -> RetryPolicy {
+    self.max_delay = max_delay;
+    self
+  }
+
+  // delay before the retry number `retry` (starting from 0)
+  fn delay(&self, retry: usize, rng: &mut Rng) -> Duration {
+    let exponential = |base: Duration| {
+      let factor = 1u32.checked_shl(retry as u32).unwrap_or(u32::MAX);
+      base.checked_mul(factor).unwrap_or(self.max_delay)
+    };
+
+    let delay = match self.backoff {
+      Backoff::Constant(delay) => delay,
+      Backoff::Exponential(base) => exponential(base),
+      Backoff::ExponentialJitter(base) => {
+        let max = std::cmp::min(exponential(base), self.max_delay);
+        let us = max.as_micros().min(usize::MAX as u128) as usize;
+        Duration::from_micros(rng.below(us.saturating_add(1)) as u64)
+      }
+    };
+
+    std::cmp::min(delay, self.max_delay)
+  }
+}
+
+/// Same as [`retry_if`], but all errors are retryable.
+///
+/// [`retry_if`]: fn.retry_if.html
+pub async fn retry<F, Fut, T, E>(policy: &RetryPolicy, f: F) -> Result<T, E>
+where
+  F: FnMut() -> Fut,
+  Fut: Future<Output = Result<T, E>>,
+{
+  retry_if(policy, |_| true, f).await
+}
+
+/// Run the future returned by `f`, and run it again (after a delay according
+/// to the `policy`) as long as it fail with error that is `retryable`.
+///
+/// Return the first success, the first error that is not retryable, or the
+/// last error when `policy` max attempts is reached.
+///
+/// The delay use the executor timer (see [`sleep`]), so it doesn't block the
+/// processor.
+///
+/// [`sleep`]: fn.sleep.html
+pub async fn retry_if<F, Fut, T, E, P>(
+  policy: &RetryPolicy,
+  mut retryable: P,
+  mut f: F,
+) -> Result<T, E>
+where
+  F: FnMut() -> Fut,
+  Fut: Future<Output = Result<T, E>>,
+  P: FnMut(&E) -> bool,
+{
+  let mut rng = Rng::new(random_seed());
+  let mut attempt = 1;
+  loop {
+    match f().await {
+      Ok(output) => return Ok(output),
+      Err(err) if attempt >= policy.max_attempts || !retryable(&err) => return Err(err),
+      Err(_) => {}
+    }
+
+    sleep(policy.delay(attempt - 1, &mut rng)).await;
+    attempt += 1;
+  }
+}
@@ -0,0 +1,116 @@
+use std::collections::VecDeque;
+use std::future::Future;
+use std::panic::Location;
+use std::sync::{Arc, Mutex, Weak};
+
+use crate::executor::{self, JoinHandle, Priority, Task, TaskTag};
+#[cfg(feature = "registry")]
+use crate::registry;
+
+/// Single threaded executor, that is driven by the caller.
+///
+/// Unlike [`spawn`], the tasks are not run by the machines, they are only run
+/// inside [`step`], on the thread that call it. This is for embedding in a host
+/// loop, for example, run some tasks on every frame of a game loop.
+///
+/// Tasks can be woken from any thread, they are queued until the next
+/// [`step`]. Tasks spawned from inside a stepped task with [`spawn`] are run
+/// by the machines as usual, use [`StepExecutor::spawn`] to keep them here.
+///
+/// Dropping the executor drop all of its tasks, awaiting their
+/// [`JoinHandle`] will return [`JoinError`].
+///
+/// Don't call [`JoinHandle::join`] on the thread that call [`step`], the task
+/// will never run.
+///
+/// [`spawn`]: fn.spawn.html
+/// [`step`]: #method.step
+/// [`StepExecutor::spawn`]: #method.spawn
+/// [`JoinHandle`]: struct.JoinHandle.html
+/// [`JoinHandle::join`]: struct.JoinHandle.html#method.join
+/// [`JoinError`]: struct.JoinError.html
+pub struct StepExecutor {
+  // ready tasks, in the order they are woken
+  queue: Arc<Mutex<VecDeque<Task>>>,
+}
+
+impl StepExecutor {
+  pub fn new() -> StepExecutor {
+    StepExecutor {
+      queue: Arc::new(Mutex::new(VecDeque::new())),
+    }
+  }
+
+  /// Queue the task, it is first run on the next [`step`].
+  ///
+  /// [`step`]: #method.step
+  #[track_caller]
+  pub fn spawn<F, T>(&self, f: F) -> JoinHandle<T>
+  where
+    F: Future<Output = T> + Send + 'static,
+    T: Send + 'static,
+  {
+    let tag = TaskTag::new(
+      executor::task_size::<F>(),
+      Location::caller(),
+      Priority::Normal,
+    );
+
+    // weak, so woken task doesn't keep the queue alive after the executor is
+    // dropped, the task is just dropped
+    let queue: Weak<Mutex<VecDeque<Task>>> = Arc::downgrade(&self.queue);
+    let (task, handle) = executor::spawn_raw(f, tag, move |t| {
+      if let Some(queue) = queue.upgrade() {
+        queue.lock().unwrap().push_back(t);
+      }
+    });
+
+    // register after spawn, the tag is not moved anymore
+    #[cfg(feature = "registry")]
+    registry::register(task.tag());
+
+    task.schedule();
+    handle
+  }
+
+  /// Run up to `max_tasks` ready tasks on the current thread, and return how
+  /// many tasks is run.
+  ///
+  /// Task that is woken while stepping is run in the same step, if `max_tasks`
+  /// is not reached yet, so a task that always wake itself can take the whole
+  /// step.
+  pub fn step(&self, max_tasks: usize) -> usize {
+    let mut ran = 0;
+    while ran < max_tasks {
+      // don't hold the lock while running, the task may wake itself
+      let t = match self.queue.lock().unwrap().pop_front() {
+        Some(t) => t,
+        None => break,
+      };
+      executor::run_as_current(t);
+      ran += 1;
+    }
+    ran
+  }
+
+  /// Number of tasks that is ready to run on the next [`step`].
+  ///
+  /// [`step`]: #method.step
+  pub fn ready_tasks(&self) -> usize {
+    self.queue.lock().unwrap().len()
+  }
+}
+
+impl Default for StepExecutor {
+  fn default() -> StepExecutor {
+    StepExecutor::new()
+  }
+}
+
+impl std::fmt::Debug for StepExecutor {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    f.debug_struct("StepExecutor")
+      .field("ready_tasks", &self.ready_tasks())
+      .finish()
+  }
+}
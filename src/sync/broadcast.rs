@@ -0,0 +1,341 @@
+//! Multi producer, multi consumer channel, every receiver get a copy of
+//! every message.
+//!
+//! The channel keep the last `capacity` messages, receiver that fall behind
+//! more than that miss the oldest messages, and get [`RecvError::Lagged`]
+//! with the number of missed messages, then continue from the oldest message
+//! that is still kept.
+//!
+//! [`RecvError::Lagged`]: enum.RecvError.html#variant.Lagged
+
+use std::collections::{HashMap, VecDeque};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+
+/// Create a channel that keep the last `capacity` messages.
+///
+/// Panic if `capacity` is zero.
+pub fn channel<T: Clone>(capacity: usize) -> (Sender<T>, Receiver<T>) {
+  assert!(capacity > 0, "broadcast channel capacity must be positive");
+
+  let shared = Arc::new(Shared {
+    state: Mutex::new(State {
+      buffer: VecDeque::with_capacity(capacity),
+      head: 0,
+      capacity,
+      senders: 1,
+      receivers: 1,
+      next_id: 1,
+      wakers: HashMap::new(),
+    }),
+  });
+
+  let receiver = Receiver {
+    shared: shared.clone(),
+    id: 0,
+    next: 0,
+  };
+  (Sender { shared }, receiver)
+}
+
+struct Shared<T> {
+  state: Mutex<State<T>>,
+}
+
+struct State<T> {
+  // the last `capacity` messages, buffer[0] is message number `head`
+  buffer: VecDeque<T>,
+  head: u64,
+  capacity: usize,
+
+  senders: usize,
+  receivers: usize,
+
+  // waiting receivers, keyed by receiver id
+  next_id: usize,
+  wakers: HashMap<usize, Waker>,
+}
+
+impl<T> State<T> {
+  // number of the next message to be sent
+  fn tail(&self) -> u64 {
+    self.head + self.buffer.len() as u64
+  }
+
+  fn new_receiver(&mut self) -> (usize, u64) {
+    let id = self.next_id;
+    self.next_id = self.next_id.wrapping_add(1);
+    self.receivers += 1;
+    (id, self.tail())
+  }
+}
+
+/// Sending half of the channel, can be cloned.
+pub struct Sender<T> {
+  shared: Arc<Shared<T>>,
+}
+
+impl<T> Sender<T> {
+  /// Send the message to all current receivers, return the number of
+  /// receivers.
+  ///
+  /// This never wait, if the channel is full, the oldest message is dropped.
+  /// Return error (with the message) if there is no receiver.
+  pub fn send(&self, value: T) -> Result<usize, SendError<T>> {
+    let mut state = self.shared.state.lock().unwrap();
+    if state.receivers == 0 {
+      return Err(SendError(value));
+    }
+
+    state.buffer.push_back(value);
+    if state.buffer.len() > state.capacity {
+      state.buffer.pop_front();
+      state.head += 1;
+    }
+
+    let receivers = state.receivers;
+
+    // take the wakers out first, so we don't wake while holding the lock
+    let wakers = std::mem::take(&mut state.wakers);
+    drop(state);
+    for (_, w) in wakers {
+      w.wake();
+    }
+
+    Ok(receivers)
+  }
+
+  /// New receiver, that get the messages sent after this call.
+  pub fn subscribe(&self) -> Receiver<T> {
+    let (id, next) = self.shared.state.lock().unwrap().new_receiver();
+    Receiver {
+      shared: self.shared.clone(),
+      id,
+      next,
+    }
+  }
+
+  pub fn receiver_count(&self) -> usize {
+    self.shared.state.lock().unwrap().receivers
+  }
+}
+
+impl<T> Clone for Sender<T> {
+  fn clone(&self) -> Sender<T> {
+    self.shared.state.lock().unwrap().senders += 1;
+    Sender {
+      shared: self.shared.clone(),
+    }
+  }
+}
+
+impl<T> Drop for Sender<T> {
+  fn drop(&mut self) {
+    let mut state = self.shared.state.lock().unwrap();
+    state.senders -= 1;
+    if state.senders > 0 {
+      return;
+    }
+
+    // the last one, wake up all receivers, so they see the channel is closed
+    let wakers = std::mem::take(&mut state.wakers);
+    drop(state);
+    for (_, w) in wakers {
+      w.wake();
+    }
+  }
+}
+
+impl<T> std::fmt::Debug for Sender<T> {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    f.write_str("Sender")
+  }
+}
+
+/// Receiving half of the channel, see [`Sender::subscribe`] to create more.
+///
+/// [`Sender::subscribe`]: struct.Sender.html#method.subscribe
+pub struct Receiver<T> {
+  shared: Arc<Shared<T>>,
+  id: usize,
+
+  // number of the next message to receive
+  next: u64,
+}
+
+impl<T: Clone> Receiver<T> {
+  /// Receive the next message, wait if there is none yet.
+  ///
+  /// Return [`RecvError::Closed`] when all senders are dropped and all
+  /// messages are received, or [`RecvError::Lagged`] if this receiver miss
+  /// some messages, the next call continue from the oldest kept message.
+  ///
+  /// [`RecvError::Closed`]: enum.RecvError.html#variant.Closed
+  /// [`RecvError::Lagged`]: enum.RecvError.html#variant.Lagged
+  pub fn recv(&mut self) -> Recv<'_, T> {
+    Recv { receiver: self }
+  }
+
+  /// Receive the next message without waiting.
+  pub fn try_recv(&mut self) -> Result<T, TryRecvError> {
+    let shared = self.shared.clone();
+    let state = shared.state.lock().unwrap();
+    self.take(&state)
+  }
+
+  fn take(&mut self, state: &State<T>) -> Result<T, TryRecvError> {
+    if self.next < state.head {
+      let missed = state.head - self.next;
+      self.next = state.head;
+      return Err(TryRecvError::Lagged(missed));
+    }
+
+    if self.next < state.tail() {
+      let value = state.buffer[(self.next - state.head) as usize].clone();
+      self.next += 1;
+      return Ok(value);
+    }
+
+    if state.senders == 0 {
+      Err(TryRecvError::Closed)
+    } else {
+      Err(TryRecvError::Empty)
+    }
+  }
+}
+
+impl<T> Drop for Receiver<T> {
+  fn drop(&mut self) {
+    let mut state = self.shared.state.lock().unwrap();
+    state.receivers -= 1;
+    state.wakers.remove(&self.id);
+  }
+}
+
+impl<T> std::fmt::Debug for Receiver<T> {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    f.write_str("Receiver")
+  }
+}
+
+/// Future returned by [`Receiver::recv`].
+///
+/// [`Receiver::recv`]: struct.Receiver.html#method.recv
+pub struct Recv<'a, T> {
+  receiver: &'a mut Receiver<T>,
+}
+
+impl<T: Clone> Future for Recv<'_, T> {
+  type Output = Result<T, RecvError>;
+
+  fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+    let receiver = &mut *self.receiver;
+    let shared = receiver.shared.clone();
+    let mut state = shared.state.lock().unwrap();
+
+    match receiver.take(&state) {
+      Ok(value) => Poll::Ready(Ok(value)),
+      Err(TryRecvError::Lagged(missed)) => Poll::Ready(Err(RecvError::Lagged(missed))),
+      Err(TryRecvError::Closed) => Poll::Ready(Err(RecvError::Closed)),
+      Err(TryRecvError::Empty) => {
+        // checked while holding the lock, so send can't be missed
+        match state.wakers.get(&receiver.id) {
+          Some(w) if w.will_wake(cx.waker()) => {}
+          _ => {
+            state.wakers.insert(receiver.id, cx.waker().clone());
+          }
+        }
+        Poll::Pending
+      }
+    }
+  }
+}
+
+impl<T> Drop for Recv<'_, T> {
+  fn drop(&mut self) {
+    let id = self.receiver.id;
+    self
+      .receiver
+      .shared
+      .state
+      .lock()
+      .unwrap()
+      .wakers
+      .remove(&id);
+  }
+}
+
+/// Error returned by [`Sender::send`] when there is no receiver, contain the
+/// message that is not sent.
+///
+/// [`Sender::send`]: struct.Sender.html#method.send
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct SendError<T>(pub T);
+
+impl<T> std::fmt::Debug for SendError<T> {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    f.write_str("SendError(..)")
+  }
+}
+
+impl<T> std::fmt::Display for SendError<T> {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    f.write_str("channel has no receiver")
+  }
+}
+
+impl<T> std::error::Error for SendError<T> {}
+
+/// Error returned by [`Receiver::recv`].
+///
+/// [`Receiver::recv`]: struct.Receiver.html#method.recv
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RecvError {
+  /// All senders are dropped, and all messages are received.
+  Closed,
+
+  /// The receiver fall behind, and miss this many messages.
+  Lagged(u64),
+}
+
+impl std::fmt::Display for RecvError {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      RecvError::Closed => f.write_str("channel is closed"),
+      RecvError::Lagged(missed) => write!(f, "receiver lagged behind, missed {} messages", missed),
+    }
+  }
+}
+
+impl std::error::Error for RecvError {}
+
+/// Error returned by [`Receiver::try_recv`].
+///
+/// [`Receiver::try_recv`]: struct.Receiver.html#method.try_recv
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TryRecvError {
+  /// No new message yet.
+  Empty,
+
+  /// All senders are dropped, and all messages are received.
+  Closed,
+
+  /// The receiver fall behind, and miss this many messages.
+  Lagged(u64),
+}
+
+impl std::fmt::Display for TryRecvError {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      TryRecvError::Empty => f.write_str("channel is empty"),
+      TryRecvError::Closed => f.write_str("channel is closed"),
+      TryRecvError::Lagged(missed) => {
+        write!(f, "receiver lagged behind, missed {} messages", missed)
+      }
+    }
+  }
+}
+
+impl std::error::Error for TryRecvError {}
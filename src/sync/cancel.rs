@@ -0,0 +1,229 @@
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+
+use crate::executor;
+
+/// Token to signal cancellation to a group of tasks.
+///
+/// All clones of the token share the same state, calling [`cancel`] on one of
+/// them will wake every task waiting on [`cancelled`].
+///
+/// [`cancel`]: #method.cancel
+/// [`cancelled`]: #method.cancelled
+#[derive(Clone, Default)]
+pub struct CancellationToken {
+  inner: Arc<Inner>,
+}
+
+#[derive(Default)]
+struct Inner {
+  cancelled: AtomicBool,
+  waiters: Mutex<Waiters>,
+}
+
+thread_local! {
+  // token of the task that is being polled, see check_cancelled
+  static CURRENT: Cell<*const Inner> = const { Cell::new(std::ptr::null()) };
+}
+
+#[derive(Default)]
+struct Waiters {
+  next_key: usize,
+  wakers: HashMap<usize, Waker>,
+}
+
+impl CancellationToken {
+  pub fn new() -> CancellationToken {
+    CancellationToken::default()
+  }
+
+  /// Cancel the token, wake all waiters.
+  ///
+  /// Calling this more than once is a no-op.
+  pub fn cancel(&self) {
+    if self.inner.cancelled.swap(true, Ordering::SeqCst) {
+      return;
+    }
+
+    // take the wakers out first, so we don't wake while holding the lock
+    let wakers = std::mem::take(&mut self.inner.waiters.lock().unwrap().wakers);
+    for (_, w) in wakers {
+      w.wake();
+    }
+  }
+
+  pub fn is_cancelled(&self) -> bool {
+    self.inner.cancelled.load(Ordering::SeqCst)
+  }
+
+  /// Future that resolve when the token is cancelled.
+  pub fn cancelled(&self) -> Cancelled {
+    Cancelled {
+      inner: self.inner.clone(),
+      key: None,
+    }
+  }
+
+  /// Run the task, that will be dropped as soon as the token is cancelled.
+  ///
+  /// The task is only dropped at its next yield point, not in the middle of
+  /// a poll, so CPU-bound task that doesn't yield can't be cancelled until
+  /// it is done, use [`check_cancelled`] inside its loops.
+  ///
+  /// [`check_cancelled`]: fn.check_cancelled.html
+  #[track_caller]
+  pub fn spawn<F: Future<Output = ()> + Send + 'static>(&self, f: F) {
+    let cancelled = self.cancelled();
+    let f = WithToken {
+      future: f,
+      inner: self.inner.clone(),
+    };
+    executor::spawn(async move {
+      race(f, cancelled).await;
+    });
+  }
+}
+
+impl std::fmt::Debug for CancellationToken {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    f.debug_struct("CancellationToken")
+      .field("cancelled", &self.is_cancelled())
+      .finish()
+  }
+}
+
+/// Future returned by [`CancellationToken::cancelled`].
+///
+/// [`CancellationToken::cancelled`]: struct.CancellationToken.html#method.cancelled
+pub struct Cancelled {
+  inner: Arc<Inner>,
+  key: Option<usize>,
+}
+
+impl Future for Cancelled {
+  type Output = ();
+
+  fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+    if self.inner.cancelled.load(Ordering::SeqCst) {
+      return Poll::Ready(());
+    }
+
+    let inner = self.inner.clone();
+    let mut waiters = inner.waiters.lock().unwrap();
+
+    // check again while holding the lock,
+    // cancel() may happen between first check and the lock
+    if inner.cancelled.load(Ordering::SeqCst) {
+      return Poll::Ready(());
+    }
+
+    let key = match self.key {
+      Some(key) => key,
+      None => {
+        let key = waiters.next_key;
+        waiters.next_key = waiters.next_key.wrapping_add(1);
+        self.key = Some(key);
+        key
+      }
+    };
+    waiters.wakers.insert(key, cx.waker().clone());
+
+    Poll::Pending
+  }
+}
+
+impl Drop for Cancelled {
+  fn drop(&mut self) {
+    if let Some(key) = self.key {
+      self.inner.waiters.lock().unwrap().wakers.remove(&key);
+    }
+  }
+}
+
+/// Cancellation checkpoint for CPU-bound task, stop the task here if it is
+/// cancelled, otherwise continue right away.
+///
+/// Cancellation only take effect when the task yield, a task that run long
+/// loops without awaiting anything that is pending can't be cancelled in the
+/// middle of it. Await this in such loops, when the task is cancelled, it
+/// never return, and the task is dropped in the same poll, as if it yield
+/// there. It is cheap, there is no waker or lock involved.
+///
+/// The task is cancelled if it is spawned by [`CancellationToken::spawn`]
+/// and the token is cancelled, its deadline passed (see
+/// [`spawn_with_deadline`]), or it is cancelled by [`cancel_matching`]
+/// (with `registry` feature).
+/// Outside of such task, this always continue.
+///
+/// [`CancellationToken::spawn`]: struct.CancellationToken.html#method.spawn
+/// [`spawn_with_deadline`]: ../fn.spawn_with_deadline.html
+/// [`cancel_matching`]: ../fn.cancel_matching.html
+pub async fn check_cancelled() {
+  // this is safe because the pointer is only set while WithToken is polled,
+  // and it hold the Arc
+  let token = CURRENT.with(|c| {
+    unsafe { c.get().as_ref() }
+      .map(|i| i.cancelled.load(Ordering::SeqCst))
+      .unwrap_or(false)
+  });
+
+  // pending without registering the waker is fine, whatever cancel the task
+  // stop it in this same poll (or wake it up)
+  if token || executor::current_cancel_requested() {
+    std::future::pending::<()>().await;
+  }
+}
+
+// mark the token as the current one while polling the future,
+// see check_cancelled
+struct WithToken<F> {
+  future: F,
+  inner: Arc<Inner>,
+}
+
+impl<F: Future<Output = ()>> Future for WithToken<F> {
+  type Output = ();
+
+  fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+    // this is safe because we never move future out of self
+    let this = unsafe { self.get_unchecked_mut() };
+    let inner: *const Inner = &*this.inner;
+    let future = unsafe { Pin::new_unchecked(&mut this.future) };
+
+    let prev = CURRENT.with(|c| c.replace(inner));
+    defer! {
+      CURRENT.with(|c| c.set(prev));
+    }
+    future.poll(cx)
+  }
+}
+
+// resolve when either a or b resolve, the result is dropped
+async fn race<A: Future<Output = ()>, B: Future<Output = ()>>(a: A, b: B) {
+  struct Select<A, B> {
+    a: A,
+    b: B,
+  }
+
+  impl<A: Future<Output = ()>, B: Future<Output = ()>> Future for Select<A, B> {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+      // this is safe because we never move a and b out of self
+      let this = unsafe { self.get_unchecked_mut() };
+      let a = unsafe { Pin::new_unchecked(&mut this.a) };
+      if a.poll(cx).is_ready() {
+        return Poll::Ready(());
+      }
+      let b = unsafe { Pin::new_unchecked(&mut this.b) };
+      b.poll(cx)
+    }
+  }
+
+  Select { a, b }.await
+}
@@ -0,0 +1,119 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use super::mutex::MutexGuard;
+use super::waiters::WaitList;
+
+/// Condition variable for tasks, used together with [`Mutex`].
+///
+/// Like `std::sync::Condvar`, but waiting does not block the processor.
+/// Spurious wake up is possible (e.g. other task take the lock first and
+/// change the state), so always check the condition in a loop,
+/// or use [`wait_while`].
+///
+/// [`Mutex`]: struct.Mutex.html
+/// [`wait_while`]: #method.wait_while
+#[derive(Default)]
+pub struct Condvar {
+  waiters: std::sync::Mutex<WaitList>,
+}
+
+impl Condvar {
+  pub fn new() -> Condvar {
+    Condvar::default()
+  }
+
+  /// Release the lock, wait until notified, then acquire the lock again.
+  pub async fn wait<'a, T: ?Sized>(&self, guard: MutexGuard<'a, T>) -> MutexGuard<'a, T> {
+    let mutex = guard.mutex;
+
+    // register before releasing the lock, so notification that happen
+    // right after the lock is released is not lost
+    let key = self.waiters.lock().unwrap().insert(None);
+    let wait = Wait {
+      condvar: self,
+      key: Some(key),
+    };
+    drop(guard);
+
+    wait.await;
+    mutex.lock().await
+  }
+
+  /// Wait while `condition` return true, handle spurious wake up.
+  pub async fn wait_while<'a, T: ?Sized>(
+    &self,
+    mut guard: MutexGuard<'a, T>,
+    mut condition: impl FnMut(&mut T) -> bool,
+  ) -> MutexGuard<'a, T> {
+    while condition(&mut *guard) {
+      guard = self.wait(guard).await;
+    }
+    guard
+  }
+
+  /// Wake one waiting task, if any.
+  pub fn notify_one(&self) {
+    let waker = self.waiters.lock().unwrap().notify_one();
+    if let Some(w) = waker {
+      w.wake();
+    }
+  }
+
+  /// Wake all waiting tasks.
+  pub fn notify_all(&self) {
+    let wakers = self.waiters.lock().unwrap().notify_all();
+    for w in wakers {
+      w.wake();
+    }
+  }
+}
+
+impl std::fmt::Debug for Condvar {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    f.write_str("Condvar")
+  }
+}
+
+struct Wait<'a> {
+  condvar: &'a Condvar,
+  key: Option<usize>,
+}
+
+impl Future for Wait<'_> {
+  type Output = ();
+
+  fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+    let key = self.key.unwrap();
+    let mut waiters = self.condvar.waiters.lock().unwrap();
+
+    if waiters.is_notified(key) {
+      waiters.remove(key);
+      drop(waiters);
+      self.key = None;
+      return Poll::Ready(());
+    }
+
+    waiters.update(key, cx.waker());
+    Poll::Pending
+  }
+}
+
+impl Drop for Wait<'_> {
+  fn drop(&mut self) {
+    if let Some(key) = self.key {
+      let mut waiters = self.condvar.waiters.lock().unwrap();
+
+      // we are notified but dropped before consuming it, pass it on,
+      // so notify_one is not lost
+      if waiters.remove(key) {
+        let waker = waiters.notify_one();
+        drop(waiters);
+        if let Some(w) = waker {
+          w.wake();
+        }
+      }
+    }
+  }
+}
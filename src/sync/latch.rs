@@ -0,0 +1,117 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+
+use super::waiters::WaitList;
+
+/// Completion barrier, resolve once it is counted down `n` times.
+///
+/// All clones of the latch share the same counter, give a clone to each
+/// task, call [`count_down`] when it is done, and await [`wait`] to wait
+/// for all of them, without holding their join handles.
+///
+/// [`count_down`]: #method.count_down
+/// [`wait`]: #method.wait
+#[derive(Clone)]
+pub struct CountdownLatch {
+  inner: Arc<Inner>,
+}
+
+struct Inner {
+  count: AtomicUsize,
+  waiters: Mutex<WaitList>,
+}
+
+impl CountdownLatch {
+  pub fn new(n: usize) -> CountdownLatch {
+    CountdownLatch {
+      inner: Arc::new(Inner {
+        count: AtomicUsize::new(n),
+        waiters: Mutex::new(WaitList::default()),
+      }),
+    }
+  }
+
+  /// Decrease the counter, wake all waiters when it reach zero.
+  ///
+  /// Calling this when the counter is already zero is a no-op.
+  pub fn count_down(&self) {
+    let prev = self
+      .inner
+      .count
+      .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |c| c.checked_sub(1));
+
+    if let Ok(1) = prev {
+      // take the wakers out first, so we don't wake while holding the lock
+      let wakers = self.inner.waiters.lock().unwrap().notify_all();
+      for w in wakers {
+        w.wake();
+      }
+    }
+  }
+
+  /// Remaining count.
+  pub fn count(&self) -> usize {
+    self.inner.count.load(Ordering::SeqCst)
+  }
+
+  /// Future that resolve when the counter reach zero.
+  pub fn wait(&self) -> LatchWait {
+    LatchWait {
+      inner: self.inner.clone(),
+      key: None,
+    }
+  }
+}
+
+impl std::fmt::Debug for CountdownLatch {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    f.debug_struct("CountdownLatch")
+      .field("count", &self.count())
+      .finish()
+  }
+}
+
+/// Future returned by [`CountdownLatch::wait`].
+///
+/// [`CountdownLatch::wait`]: struct.CountdownLatch.html#method.wait
+pub struct LatchWait {
+  inner: Arc<Inner>,
+  key: Option<usize>,
+}
+
+impl Future for LatchWait {
+  type Output = ();
+
+  fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+    if self.inner.count.load(Ordering::SeqCst) == 0 {
+      return Poll::Ready(());
+    }
+
+    let inner = self.inner.clone();
+    let mut waiters = inner.waiters.lock().unwrap();
+
+    // check again while holding the lock,
+    // count_down() may happen between first check and the lock
+    if inner.count.load(Ordering::SeqCst) == 0 {
+      return Poll::Ready(());
+    }
+
+    match self.key {
+      Some(key) => waiters.update(key, cx.waker()),
+      None => self.key = Some(waiters.insert(Some(cx.waker().clone()))),
+    }
+
+    Poll::Pending
+  }
+}
+
+impl Drop for LatchWait {
+  fn drop(&mut self) {
+    if let Some(key) = self.key {
+      self.inner.waiters.lock().unwrap().remove(key);
+    }
+  }
+}
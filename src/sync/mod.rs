@@ -0,0 +1,19 @@
+//! Synchronization primitives for tasks.
+
+pub mod broadcast;
+mod cancel;
+mod condvar;
+mod latch;
+mod mutex;
+mod once;
+mod rate;
+mod wait_group;
+mod waiters;
+
+pub use cancel::{check_cancelled, CancellationToken, Cancelled};
+pub use condvar::Condvar;
+pub use latch::{CountdownLatch, LatchWait};
+pub use mutex::{Lock, Mutex, MutexGuard};
+pub use once::Once;
+pub use rate::RateLimiter;
+pub use wait_group::{WaitGroup, WaitGroupGuard, WaitGroupWait};
@@ -0,0 +1,179 @@
+use std::cell::UnsafeCell;
+use std::future::Future;
+use std::ops::{Deref, DerefMut};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::task::{Context, Poll};
+
+use super::waiters::WaitList;
+
+/// Mutual exclusion lock for tasks.
+///
+/// Unlike `std::sync::Mutex`, waiting for the lock does not block the
+/// processor, and the guard can be held across `.await`.
+///
+/// Waiting tasks are woken in fifo order, but the lock is not strictly fair,
+/// a task that call [`lock`] when the lock is just released may get it first.
+///
+/// [`lock`]: #method.lock
+#[derive(Default)]
+pub struct Mutex<T: ?Sized> {
+  locked: AtomicBool,
+  waiters: std::sync::Mutex<WaitList>,
+  value: UnsafeCell<T>,
+}
+
+// this is safe because the value is only accessed through the guard,
+// and only one guard can exist at a time
+unsafe impl<T: ?Sized + Send> Send for Mutex<T> {}
+unsafe impl<T: ?Sized + Send> Sync for Mutex<T> {}
+
+impl<T> Mutex<T> {
+  pub fn new(value: T) -> Mutex<T> {
+    Mutex {
+      locked: AtomicBool::new(false),
+      waiters: std::sync::Mutex::new(WaitList::default()),
+      value: UnsafeCell::new(value),
+    }
+  }
+
+  pub fn into_inner(self) -> T {
+    self.value.into_inner()
+  }
+}
+
+impl<T: ?Sized> Mutex<T> {
+  /// Acquire the lock, wait until it is available.
+  pub fn lock(&self) -> Lock<'_, T> {
+    Lock {
+      mutex: self,
+      key: None,
+    }
+  }
+
+  /// Acquire the lock if it is available now.
+  pub fn try_lock(&self) -> Option<MutexGuard<'_, T>> {
+    if self
+      .locked
+      .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+      .is_ok()
+    {
+      Some(MutexGuard { mutex: self })
+    } else {
+      None
+    }
+  }
+
+  pub fn get_mut(&mut self) -> &mut T {
+    self.value.get_mut()
+  }
+
+  fn unlock(&self) {
+    self.locked.store(false, Ordering::Release);
+
+    let waker = self.waiters.lock().unwrap().notify_one();
+    if let Some(w) = waker {
+      w.wake();
+    }
+  }
+}
+
+impl<T: ?Sized> std::fmt::Debug for Mutex<T> {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    f.debug_struct("Mutex")
+      .field("locked", &self.locked.load(Ordering::Relaxed))
+      .finish()
+  }
+}
+
+/// Future returned by [`Mutex::lock`].
+///
+/// [`Mutex::lock`]: struct.Mutex.html#method.lock
+pub struct Lock<'a, T: ?Sized> {
+  mutex: &'a Mutex<T>,
+  key: Option<usize>,
+}
+
+impl<'a, T: ?Sized> Future for Lock<'a, T> {
+  type Output = MutexGuard<'a, T>;
+
+  fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<MutexGuard<'a, T>> {
+    let mutex = self.mutex;
+
+    if let Some(guard) = mutex.try_lock() {
+      if let Some(key) = self.key.take() {
+        mutex.waiters.lock().unwrap().remove(key);
+      }
+      return Poll::Ready(guard);
+    }
+
+    let mut waiters = mutex.waiters.lock().unwrap();
+    match self.key {
+      Some(key) => waiters.update(key, cx.waker()),
+      None => self.key = Some(waiters.insert(Some(cx.waker().clone()))),
+    }
+
+    // try again after registered,
+    // the lock may be released before we are registered
+    if let Some(guard) = mutex.try_lock() {
+      waiters.remove(self.key.take().unwrap());
+      return Poll::Ready(guard);
+    }
+
+    Poll::Pending
+  }
+}
+
+impl<T: ?Sized> Drop for Lock<'_, T> {
+  fn drop(&mut self) {
+    if let Some(key) = self.key {
+      let mut waiters = self.mutex.waiters.lock().unwrap();
+
+      // we are notified but never take the lock, pass it on
+      if waiters.remove(key) {
+        let waker = waiters.notify_one();
+        drop(waiters);
+        if let Some(w) = waker {
+          w.wake();
+        }
+      }
+    }
+  }
+}
+
+/// Guard of the [`Mutex`], the lock is released when it is dropped.
+///
+/// [`Mutex`]: struct.Mutex.html
+pub struct MutexGuard<'a, T: ?Sized> {
+  pub(super) mutex: &'a Mutex<T>,
+}
+
+unsafe impl<T: ?Sized + Sync> Sync for MutexGuard<'_, T> {}
+
+impl<T: ?Sized> Deref for MutexGuard<'_, T> {
+  type Target = T;
+
+  fn deref(&self) -> &T {
+    // this is safe because we hold the lock
+    unsafe { &*self.mutex.value.get() }
+  }
+}
+
+impl<T: ?Sized> DerefMut for MutexGuard<'_, T> {
+  fn deref_mut(&mut self) -> &mut T {
+    // this is safe because we hold the lock
+    unsafe { &mut *self.mutex.value.get() }
+  }
+}
+
+impl<T: ?Sized> Drop for MutexGuard<'_, T> {
+  fn drop(&mut self) {
+    self.mutex.unlock();
+  }
+}
+
+impl<T: ?Sized + std::fmt::Debug> std::fmt::Debug for MutexGuard<'_, T> {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    std::fmt::Debug::fmt(&**self, f)
+  }
+}
@@ -0,0 +1,65 @@
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use super::mutex::Mutex;
+
+/// One-time async initialization, like `std::sync::Once`, but the
+/// initializer is a future.
+///
+/// The first [`call_once`] run its initializer, the concurrent calls wait
+/// (without blocking the processor) until it is done, then return without
+/// running their own initializer. Calls after that return right away.
+///
+/// If the initializer panic, or the calling task is cancelled (its future
+/// is dropped) before the initializer is done, the `Once` is not poisoned,
+/// it is re-armed: the next waiting (or future) caller run its own
+/// initializer instead. So the initializer should be safe to retry.
+///
+/// [`call_once`]: #method.call_once
+#[derive(Default)]
+pub struct Once {
+  done: AtomicBool,
+
+  // held while the initializer is running, waiters are woken in fifo order
+  lock: Mutex<()>,
+}
+
+impl Once {
+  pub fn new() -> Once {
+    Once::default()
+  }
+
+  /// Run `f` if no initializer is completed yet, and wait until it is done.
+  pub async fn call_once<F, Fut>(&self, f: F)
+  where
+    F: FnOnce() -> Fut,
+    Fut: Future<Output = ()>,
+  {
+    if self.is_completed() {
+      return;
+    }
+
+    // the guard is dropped on panic or cancellation too, then the next waiter
+    // see it is not done yet, and run its own initializer
+    let _guard = self.lock.lock().await;
+    if self.is_completed() {
+      return;
+    }
+
+    f().await;
+    self.done.store(true, Ordering::Release);
+  }
+
+  /// Return true if an initializer is completed.
+  pub fn is_completed(&self) -> bool {
+    self.done.load(Ordering::Acquire)
+  }
+}
+
+impl std::fmt::Debug for Once {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    f.debug_struct("Once")
+      .field("completed", &self.is_completed())
+      .finish()
+  }
+}
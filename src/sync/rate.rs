@@ -0,0 +1,80 @@
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::timer::{sleep_until, Sleep};
+
+/// Token bucket rate limiter for tasks.
+///
+/// Tokens are refilled at `rate` per second, up to `burst` tokens. Waiting
+/// for a token use the timer, so it does not block the processor.
+///
+/// Waiters are served in the order they call [`acquire`], a token is reserved
+/// for the caller when it is called, not when it is awaited.
+///
+/// [`acquire`]: #method.acquire
+pub struct RateLimiter {
+  // time between tokens
+  interval: Duration,
+
+  // how far ahead of the schedule a token can be taken (burst - 1 intervals)
+  tolerance: Duration,
+
+  // when the next token is scheduled, if there is no burst
+  next: Mutex<Instant>,
+}
+
+impl RateLimiter {
+  /// Panic if `rate` or `burst` is zero.
+  pub fn new(rate: u32, burst: u32) -> RateLimiter {
+    assert!(rate > 0, "rate must be more than zero");
+    assert!(burst > 0, "burst must be more than zero");
+
+    let interval = Duration::from_secs(1) / rate;
+    RateLimiter {
+      interval,
+      tolerance: interval * (burst - 1),
+      next: Mutex::new(Instant::now()),
+    }
+  }
+
+  // reserve a token, return when it can be used
+  fn reserve(&self, now: Instant) -> Instant {
+    let mut next = self.next.lock().unwrap();
+    let start = std::cmp::max(*next, now);
+    *next = start + self.interval;
+    start.checked_sub(self.tolerance).unwrap_or(now)
+  }
+
+  /// Take a token, the returned future resolve when it can be used.
+  ///
+  /// The token is reserved when this is called, not when the future is
+  /// polled, dropping the future before it is resolved does not give the
+  /// token back.
+  pub fn acquire(&self) -> Sleep {
+    sleep_until(self.reserve(Instant::now()))
+  }
+
+  /// Take a token if it is available now.
+  pub fn try_acquire(&self) -> bool {
+    let now = Instant::now();
+    let mut next = self.next.lock().unwrap();
+    let start = std::cmp::max(*next, now);
+    if start.checked_sub(self.tolerance).unwrap_or(now) > now {
+      return false;
+    }
+    *next = start + self.interval;
+    true
+  }
+}
+
+impl std::fmt::Debug for RateLimiter {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    f.debug_struct("RateLimiter")
+      .field("interval", &self.interval)
+      .field(
+        "burst",
+        &(self.tolerance.as_nanos() / self.interval.as_nanos() + 1),
+      )
+      .finish()
+  }
+}
@@ -0,0 +1,170 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+
+use super::waiters::WaitList;
+
+/// Wait for a dynamic set of tasks, like golang `sync.WaitGroup`.
+///
+/// Unlike [`CountdownLatch`], the counter can go up again, call [`add`] (or
+/// [`guard`]) for each new piece of work, even from inside the work itself,
+/// and [`done`] (or drop the guard) when it is finished. [`wait`] resolve
+/// when the counter reach zero.
+///
+/// Add before handing the work off, e.g. call [`guard`] before spawning the
+/// child task, not inside the child, so the counter never reach zero while
+/// there is still work to be added. Waiter that see the counter reach zero is
+/// resolved, even if the counter go up again right after, a new [`wait`]
+/// wait for the new work.
+///
+/// [`CountdownLatch`]: struct.CountdownLatch.html
+/// [`add`]: #method.add
+/// [`guard`]: #method.guard
+/// [`done`]: #method.done
+/// [`wait`]: #method.wait
+#[derive(Clone, Default)]
+pub struct WaitGroup {
+  inner: Arc<Mutex<Inner>>,
+}
+
+#[derive(Default)]
+struct Inner {
+  count: usize,
+  waiters: WaitList,
+}
+
+impl WaitGroup {
+  pub fn new() -> WaitGroup {
+    WaitGroup::default()
+  }
+
+  /// Increase the counter by `n`.
+  pub fn add(&self, n: usize) {
+    let mut inner = self.inner.lock().unwrap();
+    inner.count = inner
+      .count
+      .checked_add(n)
+      .expect("WaitGroup counter overflow");
+  }
+
+  /// Decrease the counter, wake all waiters when it reach zero.
+  ///
+  /// Panic if the counter is already zero, that is always a bug, [`add`] and
+  /// `done` are not paired.
+  ///
+  /// [`add`]: #method.add
+  pub fn done(&self) {
+    let mut inner = self.inner.lock().unwrap();
+    inner.count = match inner.count.checked_sub(1) {
+      Some(count) => count,
+      None => {
+        drop(inner);
+        panic!("WaitGroup::done is called more than WaitGroup::add");
+      }
+    };
+
+    if inner.count == 0 {
+      // take the wakers out first, so we don't wake while holding the lock
+      let wakers = inner.waiters.notify_all();
+      drop(inner);
+      for w in wakers {
+        w.wake();
+      }
+    }
+  }
+
+  /// Increase the counter by one, and decrease it when the guard is dropped.
+  pub fn guard(&self) -> WaitGroupGuard {
+    self.add(1);
+    WaitGroupGuard {
+      group: self.clone(),
+    }
+  }
+
+  /// Current counter.
+  pub fn count(&self) -> usize {
+    self.inner.lock().unwrap().count
+  }
+
+  /// Future that resolve when the counter reach zero.
+  pub fn wait(&self) -> WaitGroupWait {
+    WaitGroupWait {
+      inner: self.inner.clone(),
+      key: None,
+    }
+  }
+}
+
+impl std::fmt::Debug for WaitGroup {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    f.debug_struct("WaitGroup")
+      .field("count", &self.count())
+      .finish()
+  }
+}
+
+/// Decrease the counter of [`WaitGroup`] when dropped, see
+/// [`WaitGroup::guard`].
+///
+/// [`WaitGroup`]: struct.WaitGroup.html
+/// [`WaitGroup::guard`]: struct.WaitGroup.html#method.guard
+pub struct WaitGroupGuard {
+  group: WaitGroup,
+}
+
+impl Drop for WaitGroupGuard {
+  fn drop(&mut self) {
+    self.group.done();
+  }
+}
+
+impl std::fmt::Debug for WaitGroupGuard {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    f.write_str("WaitGroupGuard")
+  }
+}
+
+/// Future returned by [`WaitGroup::wait`].
+///
+/// [`WaitGroup::wait`]: struct.WaitGroup.html#method.wait
+pub struct WaitGroupWait {
+  inner: Arc<Mutex<Inner>>,
+  key: Option<usize>,
+}
+
+impl Future for WaitGroupWait {
+  type Output = ();
+
+  fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+    let inner = self.inner.clone();
+    let mut inner = inner.lock().unwrap();
+
+    // notified when the counter reach zero, it may be increased again since
+    let notified = match self.key {
+      Some(key) => inner.waiters.is_notified(key),
+      None => false,
+    };
+    if notified || inner.count == 0 {
+      if let Some(key) = self.key.take() {
+        inner.waiters.remove(key);
+      }
+      return Poll::Ready(());
+    }
+
+    match self.key {
+      Some(key) => inner.waiters.update(key, cx.waker()),
+      None => self.key = Some(inner.waiters.insert(Some(cx.waker().clone()))),
+    }
+
+    Poll::Pending
+  }
+}
+
+impl Drop for WaitGroupWait {
+  fn drop(&mut self) {
+    if let Some(key) = self.key {
+      self.inner.lock().unwrap().waiters.remove(key);
+    }
+  }
+}
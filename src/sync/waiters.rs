@@ -0,0 +1,80 @@
+use std::collections::VecDeque;
+use std::task::Waker;
+
+// fifo list of waiting tasks, the list itself is not synchronized,
+// it must be guarded by a lock
+#[derive(Default)]
+pub(super) struct WaitList {
+  next_key: usize,
+  entries: VecDeque<(usize, Entry)>,
+}
+
+enum Entry {
+  // waker is None if the waiter is registered but not polled yet
+  Waiting(Option<Waker>),
+  Notified,
+}
+
+impl WaitList {
+  // add new waiter at the back of the list, return its key
+  pub(super) fn insert(&mut self, waker: Option<Waker>) -> usize {
+    let key = self.next_key;
+    self.next_key = self.next_key.wrapping_add(1);
+    self.entries.push_back((key, Entry::Waiting(waker)));
+    key
+  }
+
+  // update the waker, and wait again if it is notified,
+  // the waiter keep its position in the list
+  pub(super) fn update(&mut self, key: usize, waker: &Waker) {
+    if let Some((_, entry)) = self.entries.iter_mut().find(|(k, _)| *k == key) {
+      match entry {
+        Entry::Waiting(Some(w)) if w.will_wake(waker) => {}
+        _ => *entry = Entry::Waiting(Some(waker.clone())),
+      }
+    }
+  }
+
+  pub(super) fn is_notified(&self, key: usize) -> bool {
+    self
+      .entries
+      .iter()
+      .any(|(k, entry)| *k == key && matches!(entry, Entry::Notified))
+  }
+
+  // return true if the waiter is notified, but not consumed the notification
+  pub(super) fn remove(&mut self, key: usize) -> bool {
+    match self.entries.iter().position(|(k, _)| *k == key) {
+      Some(index) => matches!(self.entries.remove(index), Some((_, Entry::Notified))),
+      None => false,
+    }
+  }
+
+  // notify the first waiter that is not notified yet,
+  // return its waker, the caller should wake it after releasing the lock
+  pub(super) fn notify_one(&mut self) -> Option<Waker> {
+    self
+      .entries
+      .iter_mut()
+      .find(|(_, entry)| matches!(entry, Entry::Waiting(_)))
+      .and_then(
+        |(_, entry)| match std::mem::replace(entry, Entry::Notified) {
+          Entry::Waiting(waker) => waker,
+          Entry::Notified => None,
+        },
+      )
+  }
+
+  pub(super) fn notify_all(&mut self) -> Vec<Waker> {
+    self
+      .entries
+      .iter_mut()
+      .filter_map(
+        |(_, entry)| match std::mem::replace(entry, Entry::Notified) {
+          Entry::Waiting(waker) => waker,
+          Entry::Notified => None,
+        },
+      )
+      .collect()
+  }
+}
@@ -0,0 +1,93 @@
+//! Utilities for testing code that spawn tasks.
+//!
+//! Only available with `registry` feature.
+
+use std::sync::atomic::Ordering;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::executor;
+use crate::registry;
+
+// how long the guard wait for in-flight tasks to finish before reporting
+const GRACE_PERIOD: Duration = Duration::from_secs(1);
+
+/// Guard returned by [`assert_no_leaks`].
+///
+/// [`assert_no_leaks`]: fn.assert_no_leaks.html
+#[must_use = "the check is done when the guard is dropped"]
+pub struct NoLeaks {
+  since: usize,
+}
+
+/// Track all tasks spawned after this call, and panic when the returned guard
+/// is dropped if any of them is leaked or dropped.
+///
+/// Leaked task is task that is still live (see [`task_dump`]) when the guard
+/// is dropped, the guard will wait a bit for in-flight tasks to complete
+/// before giving up. Dropped task is task that is destroyed before it is
+/// completed, e.g. because its machine is replaced or the executor is
+/// [`shutdown`].
+///
+/// Put the guard before any [`JoinHandle`] in the test, so the handles are
+/// dropped first.
+///
+/// Task ids are global, so tasks spawned by other tests running at the same
+/// time are tracked too, run the tests with `--test-threads=1` if that is a
+/// problem.
+///
+/// [`task_dump`]: ../fn.task_dump.html
+/// [`shutdown`]: ../fn.shutdown.html
+/// [`JoinHandle`]: ../struct.JoinHandle.html
+pub fn assert_no_leaks() -> NoLeaks {
+  registry::WATCHERS.fetch_add(1, Ordering::Relaxed);
+  NoLeaks {
+    since: executor::next_task_id(),
+  }
+}
+
+impl NoLeaks {
+  fn check(&self) -> Result<(), String> {
+    let deadline = Instant::now() + GRACE_PERIOD;
+    let mut leaked = registry::live_since(self.since);
+    while !leaked.is_empty() && Instant::now() < deadline {
+      thread::sleep(Duration::from_millis(1));
+      leaked = registry::live_since(self.since);
+    }
+
+    let dropped: Vec<usize> = registry::DROPPED
+      .lock()
+      .unwrap()
+      .iter()
+      .copied()
+      .filter(|&id| id >= self.since)
+      .collect();
+
+    if leaked.is_empty() && dropped.is_empty() {
+      Ok(())
+    } else {
+      Err(format!(
+        "lelet: leaked tasks: {:?}, dropped tasks: {:?}",
+        leaked, dropped
+      ))
+    }
+  }
+}
+
+impl Drop for NoLeaks {
+  fn drop(&mut self) {
+    let result = self.check();
+
+    // the last guard clean up the dropped list
+    if registry::WATCHERS.fetch_sub(1, Ordering::Relaxed) == 1 {
+      registry::DROPPED.lock().unwrap().clear();
+    }
+
+    // don't panic while panicking, that will abort
+    if let Err(err) = result {
+      if !thread::panicking() {
+        panic!("{}", err);
+      }
+    }
+  }
+}
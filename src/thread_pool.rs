@@ -1,11 +1,12 @@
 use std::hint::unreachable_unchecked;
-use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
 use std::thread;
 use std::time::Duration;
 
 use crossbeam_channel::{bounded, Receiver, Sender, TrySendError};
 use once_cell::sync::Lazy;
 
+use crate::config;
 use crate::utils::monotonic_ms;
 
 const IDLE_THRESHOLD: Duration = Duration::from_secs(60);
@@ -14,6 +15,8 @@ type Job = Box<dyn FnOnce() + Send>;
 
 struct Pool {
   last_exit: AtomicU64,
+  threads: AtomicUsize,
+  max_threads: usize,
   sender: Sender<Job>,
   receiver: Receiver<Job>,
 }
@@ -22,22 +25,69 @@ static POOL: Lazy<Pool> = Lazy::new(|| {
   let (sender, receiver) = bounded(0);
   Pool {
     last_exit: AtomicU64::new(0),
+    threads: AtomicUsize::new(0),
+    max_threads: config::get().max_threads,
     sender,
     receiver,
   }
 });
 
+// a thread that is reserved to run a job, see reserve
+pub struct Slot(Sender<Job>);
+
+impl Slot {
+  pub fn run(self, job: Job) {
+    // the reserved thread is waiting for this, it is never disconnected
+    self.0.send(job).unwrap();
+  }
+}
+
 impl Pool {
-  fn put_job(&self, job: Job) {
-    self.sender.try_send(job).unwrap_or_else(|err| match err {
-      TrySendError::Full(job) => {
+  fn reserve(&self) -> Option<Slot> {
+    let (sender, receiver) = bounded::<Job>(1);
+
+    // the reserved thread wait for the real job,
+    // if the slot is dropped, it just go back to the pool
+    let wait: Job = Box::new(move || {
+      if let Ok(job) = receiver.recv() {
+        job();
+      }
+    });
+
+    match self.sender.try_send(wait) {
+      Ok(()) => Some(Slot(sender)),
+      Err(TrySendError::Full(wait)) => {
+        if self.threads.fetch_add(1, Ordering::Relaxed) >= self.max_threads {
+          self.threads.fetch_sub(1, Ordering::Relaxed);
+          return None;
+        }
+
         let receiver = self.receiver.clone();
-        thread::spawn(move || thread_main(receiver));
-        self.sender.send(job).unwrap();
+        let spawned = thread::Builder::new().spawn(move || {
+          wait();
+          thread_main(receiver);
+        });
+
+        match spawned {
+          Ok(_) => Some(Slot(sender)),
+          Err(_) => {
+            self.threads.fetch_sub(1, Ordering::Relaxed);
+            None
+          }
+        }
       }
       // will never disconnected, because we holding reciever for cloning
-      TrySendError::Disconnected(_) => unsafe { unreachable_unchecked() },
-    });
+      Err(TrySendError::Disconnected(_)) => unsafe { unreachable_unchecked() },
+    }
+  }
+
+  fn put_job(&self, job: Job) {
+    match self.reserve() {
+      Some(slot) => slot.run(job),
+
+      // no more thread can be spawned, wait until one is idle
+      None => self.sender.send(job).unwrap(),
+    }
   }
 }
 
@@ -49,14 +99,14 @@ fn thread_main(receiver: Receiver<Job>) {
         // only 1 thread is allowed to exit per IDLE_THRESHOLD
         let now = monotonic_ms();
         let last_exit = POOL.last_exit.load(Ordering::Relaxed);
-        if now - last_exit >= (IDLE_THRESHOLD.as_millis() as u64) {
-          if POOL
+        if now - last_exit >= (IDLE_THRESHOLD.as_millis() as u64)
+          && POOL
             .last_exit
-            .compare_and_swap(last_exit, now, Ordering::Relaxed)
-            == last_exit
-          {
-            return;
-          }
+            .compare_exchange(last_exit, now, Ordering::Relaxed, Ordering::Relaxed)
+            .is_ok()
+        {
+          POOL.threads.fetch_sub(1, Ordering::Relaxed);
+          return;
         }
       }
     }
@@ -66,3 +116,14 @@ fn thread_main(receiver: Receiver<Job>) {
 pub fn spawn_box(job: Job) {
   POOL.put_job(job);
 }
+
+// number of threads in the pool, idle or busy
+pub fn threads() -> usize {
+  POOL.threads.load(Ordering::Relaxed)
+}
+
+// reserve a thread without blocking,
+// return None if no thread is idle and no more thread can be spawned
+pub fn reserve() -> Option<Slot> {
+  POOL.reserve()
+}
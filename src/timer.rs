@@ -0,0 +1,370 @@
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::convert::TryFrom;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{self, AtomicBool};
+use std::sync::{Arc, Condvar, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use once_cell::sync::Lazy;
+
+use crate::config;
+use crate::executor;
+use crate::utils::abort_on_panic;
+
+// singleton: TIMER
+struct Timer {
+  entries: Mutex<Entries>,
+  cond: Condvar,
+
+  // see Builder::timer_coalescing, deadlines are rounded from start
+  granularity: Option<Duration>,
+  start: Instant,
+}
+
+// shared between the timer thread and Sleep future
+struct State {
+  fired: AtomicBool,
+  waker: Mutex<Option<Waker>>,
+}
+
+struct Entry {
+  deadline: Instant,
+  state: Arc<State>,
+}
+
+// entries of dropped Sleep stay in the heap (BinaryHeap can't remove
+// arbitrary entry), they are compacted when the heap grow to compact_at
+struct Entries {
+  heap: BinaryHeap<Entry>,
+  compact_at: usize,
+}
+
+const MIN_COMPACT_AT: usize = 64;
+
+static TIMER: Lazy<Timer> = Lazy::new(|| {
+  thread::spawn(move || abort_on_panic(move || TIMER.main()));
+
+  Timer {
+    entries: Mutex::new(Entries {
+      heap: BinaryHeap::new(),
+      compact_at: MIN_COMPACT_AT,
+    }),
+    cond: Condvar::new(),
+    granularity: config::get().timer_granularity,
+    start: Instant::now(),
+  }
+});
+
+impl Timer {
+  fn main(&self) {
+    let mut entries = self.entries.lock().unwrap();
+    loop {
+      let now = Instant::now();
+
+      // fire all expired entries
+      while let Some(e) = entries.heap.peek() {
+        if e.deadline > now {
+          break;
+        }
+        let e = entries.heap.pop().unwrap();
+        e.state.fired.store(true, atomic::Ordering::Release);
+        let waker = e.state.waker.lock().unwrap().take();
+        if let Some(w) = waker {
+          w.wake();
+        }
+      }
+
+      entries = match entries.heap.peek().map(|e| e.deadline) {
+        Some(deadline) => self.cond.wait_timeout(entries, deadline - now).unwrap().0,
+        None => self.cond.wait(entries).unwrap(),
+      };
+    }
+  }
+
+  // round up, so the entry never fire before the deadline, entries that are
+  // rounded to the same deadline are fired together in one wake up
+  fn coalesce(&self, deadline: Instant) -> Instant {
+    let granularity = match self.granularity {
+      Some(granularity) => granularity.as_nanos(),
+      None => return deadline,
+    };
+    let since = deadline.saturating_duration_since(self.start).as_nanos();
+    let rounded = since + (granularity - since % granularity) % granularity;
+    // too far to be rounded, it doesn't matter anymore
+    u64::try_from(rounded)
+      .ok()
+      .and_then(|rounded| self.start.checked_add(Duration::from_nanos(rounded)))
+      .unwrap_or(deadline)
+  }
+
+  fn register(&self, deadline: Instant, state: Arc<State>) {
+    let deadline = self.coalesce(deadline);
+    let mut entries = self.entries.lock().unwrap();
+
+    // only need to notify the timer thread when the earliest deadline changed
+    let notify = entries
+      .heap
+      .peek()
+      .map(|e| deadline < e.deadline)
+      .unwrap_or(true);
+
+    if entries.heap.len() >= entries.compact_at {
+      entries.compact();
+    }
+    entries.heap.push(Entry { deadline, state });
+
+    if notify {
+      self.cond.notify_one();
+    }
+  }
+}
+
+impl Entries {
+  // drop the entries that is only owned by the heap (the Sleep is dropped),
+  // compact_at is doubled from the remaining length, so this is amortized
+  fn compact(&mut self) {
+    let mut heap = std::mem::take(&mut self.heap).into_vec();
+    heap.retain(|e| Arc::strong_count(&e.state) > 1);
+    self.heap = BinaryHeap::from(heap);
+    self.compact_at = std::cmp::max(MIN_COMPACT_AT, self.heap.len() * 2);
+  }
+}
+
+// BinaryHeap is max-heap, reverse the order so the earliest deadline is on top
+impl Ord for Entry {
+  fn cmp(&self, other: &Entry) -> Ordering {
+    other.deadline.cmp(&self.deadline)
+  }
+}
+
+impl PartialOrd for Entry {
+  fn partial_cmp(&self, other: &Entry) -> Option<Ordering> {
+    Some(self.cmp(other))
+  }
+}
+
+impl PartialEq for Entry {
+  fn eq(&self, other: &Entry) -> bool {
+    self.deadline == other.deadline
+  }
+}
+
+impl Eq for Entry {}
+
+/// Future returned by [`sleep`].
+///
+/// [`sleep`]: fn.sleep.html
+pub struct Sleep {
+  // None if the deadline can't be represented, it never fire
+  deadline: Option<Instant>,
+  state: Option<Arc<State>>,
+}
+
+/// Wait until `duration` has elapsed, without blocking the processor.
+///
+/// If `duration` is too large to be represented as deadline (e.g.
+/// `Duration::MAX`), it never finish.
+pub fn sleep(duration: Duration) -> Sleep {
+  Sleep {
+    deadline: Instant::now().checked_add(duration),
+    state: None,
+  }
+}
+
+/// Wait until `deadline` is reached, without blocking the processor.
+pub fn sleep_until(deadline: Instant) -> Sleep {
+  Sleep {
+    deadline: Some(deadline),
+    state: None,
+  }
+}
+
+impl Sleep {
+  /// The deadline, `None` if it never finish, see [`sleep`].
+  ///
+  /// [`sleep`]: fn.sleep.html
+  pub fn deadline(&self) -> Option<Instant> {
+    self.deadline
+  }
+}
+
+impl Future for Sleep {
+  type Output = ();
+
+  fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+    let deadline = match self.deadline {
+      Some(deadline) => deadline,
+      None => return Poll::Pending,
+    };
+    if Instant::now() >= deadline {
+      return Poll::Ready(());
+    }
+
+    match &self.state {
+      Some(state) => {
+        *state.waker.lock().unwrap() = Some(cx.waker().clone());
+
+        // the timer may fire before we update the waker
+        if state.fired.load(atomic::Ordering::Acquire) {
+          return Poll::Ready(());
+        }
+      }
+      None => {
+        let state = Arc::new(State {
+          fired: AtomicBool::new(false),
+          waker: Mutex::new(Some(cx.waker().clone())),
+        });
+        TIMER.register(deadline, state.clone());
+        self.state = Some(state);
+      }
+    }
+
+    Poll::Pending
+  }
+}
+
+impl Drop for Sleep {
+  fn drop(&mut self) {
+    // the entry is not removed from the timer (it is compacted later), just
+    // drop the waker, so the task is not woken up for nothing
+    if let Some(state) = &self.state {
+      state.waker.lock().unwrap().take();
+    }
+  }
+}
+
+impl std::fmt::Debug for Sleep {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    f.debug_struct("Sleep")
+      .field("deadline", &self.deadline)
+      .finish()
+  }
+}
+
+/// Error returned by [`timeout`] when the deadline has elapsed.
+///
+/// [`timeout`]: fn.timeout.html
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Elapsed;
+
+impl std::fmt::Display for Elapsed {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    f.write_str("deadline has elapsed")
+  }
+}
+
+impl std::error::Error for Elapsed {}
+
+/// Future returned by [`timeout`].
+///
+/// [`timeout`]: fn.timeout.html
+pub struct Timeout<F> {
+  future: F,
+  sleep: Sleep,
+}
+
+/// Wait for the future, but give up with [`Elapsed`] after `duration`.
+///
+/// When the deadline has elapsed, the future is not polled anymore. If
+/// `duration` is too large to be represented as deadline (e.g.
+/// `Duration::MAX`), it never give up.
+///
+/// Inside a task that has deadline, it give up at the task deadline at the
+/// latest, see [`spawn_with_deadline`].
+///
+/// [`Elapsed`]: struct.Elapsed.html
+/// [`spawn_with_deadline`]: fn.spawn_with_deadline.html
+pub fn timeout<F: Future>(duration: Duration, future: F) -> Timeout<F> {
+  let mut sleep = sleep(duration);
+  if let Some(task_deadline) = executor::current_deadline() {
+    if sleep.deadline.map(|d| task_deadline < d).unwrap_or(true) {
+      sleep.deadline = Some(task_deadline);
+    }
+  }
+  Timeout { future, sleep }
+}
+
+impl<F: Future> Future for Timeout<F> {
+  type Output = Result<F::Output, Elapsed>;
+
+  fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+    // this is safe because we never move future out of self
+    let this = unsafe { self.get_unchecked_mut() };
+    let future = unsafe { Pin::new_unchecked(&mut this.future) };
+
+    if let Poll::Ready(output) = future.poll(cx) {
+      return Poll::Ready(Ok(output));
+    }
+
+    match Pin::new(&mut this.sleep).poll(cx) {
+      Poll::Ready(()) => Poll::Ready(Err(Elapsed)),
+      Poll::Pending => Poll::Pending,
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn state() -> Arc<State> {
+    Arc::new(State {
+      fired: AtomicBool::new(false),
+      waker: Mutex::new(None),
+    })
+  }
+
+  #[test]
+  fn coalesce_round_up() {
+    let start = Instant::now();
+    let timer = Timer {
+      entries: Mutex::new(Entries {
+        heap: BinaryHeap::new(),
+        compact_at: MIN_COMPACT_AT,
+      }),
+      cond: Condvar::new(),
+      granularity: Some(Duration::from_millis(10)),
+      start,
+    };
+    let ms = |ms| start + Duration::from_millis(ms);
+    assert_eq!(timer.coalesce(ms(0)), ms(0));
+    assert_eq!(timer.coalesce(ms(1)), ms(10));
+    assert_eq!(timer.coalesce(ms(10)), ms(10));
+    assert_eq!(timer.coalesce(ms(11)), ms(20));
+  }
+
+  #[test]
+  fn compact_dropped_entries() {
+    let mut entries = Entries {
+      heap: BinaryHeap::new(),
+      compact_at: MIN_COMPACT_AT,
+    };
+    let now = Instant::now();
+    let mut alive = Vec::new();
+    for i in 0..1000 {
+      let state = state();
+      if i % 10 == 0 {
+        alive.push(state.clone());
+      }
+      entries.heap.push(Entry {
+        deadline: now + Duration::from_millis(i),
+        state,
+      });
+    }
+
+    entries.compact();
+    assert_eq!(entries.heap.len(), 100);
+    assert_eq!(entries.compact_at, 200);
+    // still ordered by deadline
+    assert_eq!(entries.heap.pop().unwrap().deadline, now);
+
+    drop(alive);
+    entries.compact();
+    assert_eq!(entries.heap.len(), 0);
+    assert_eq!(entries.compact_at, MIN_COMPACT_AT);
+  }
+}
@@ -1,4 +1,4 @@
-use std::time::Instant;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
 
 use lazy_static::lazy_static;
 
@@ -34,10 +34,52 @@ macro_rules! defer {
   };
 }
 
+lazy_static! {
+  static ref START: Instant = Instant::now();
+}
+
 pub fn monotonic_ms() -> u64 {
-  lazy_static! {
-    static ref START: Instant = Instant::now();
-  }
   let start = *START;
   Instant::now().duration_since(start).as_millis() as u64
 }
+
+pub fn monotonic_us() -> u64 {
+  let start = *START;
+  Instant::now().duration_since(start).as_micros() as u64
+}
+
+pub fn random_seed() -> u64 {
+  SystemTime::now()
+    .duration_since(UNIX_EPOCH)
+    .map(|d| d.as_nanos() as u64)
+    .unwrap_or(0)
+}
+
+// xorshift64*, not cryptographically secure,
+// but fast and good enough for scheduling decision
+pub struct Rng(u64);
+
+impl Rng {
+  pub fn new(seed: u64) -> Rng {
+    // splitmix64 to spread the seed bits, state must not be zero
+    let mut z = seed.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^= z >> 31;
+    Rng(if z == 0 { 1 } else { z })
+  }
+
+  pub fn next_u64(&mut self) -> u64 {
+    let mut x = self.0;
+    x ^= x >> 12;
+    x ^= x << 25;
+    x ^= x >> 27;
+    self.0 = x;
+    x.wrapping_mul(0x2545_f491_4f6c_dd1d)
+  }
+
+  // random number in range [0, n)
+  pub fn below(&mut self, n: usize) -> usize {
+    (self.next_u64() % n as u64) as usize
+  }
+}
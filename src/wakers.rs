@@ -0,0 +1,101 @@
+use std::future::Future;
+use std::mem::ManuallyDrop;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll, RawWaker, RawWakerVTable, Waker};
+
+// how many times the task's waker is cloned and woken, stored in the task tag,
+// see TaskInfo::waker_clones
+#[derive(Default)]
+pub(crate) struct WakeCounts {
+  clones: AtomicUsize,
+  wakes: AtomicUsize,
+}
+
+impl WakeCounts {
+  pub(crate) fn clones(&self) -> usize {
+    self.clones.load(Ordering::Relaxed)
+  }
+
+  pub(crate) fn wakes(&self) -> usize {
+    self.wakes.load(Ordering::Relaxed)
+  }
+}
+
+// the real waker, and where to count
+struct Counted {
+  inner: Waker,
+  counts: Arc<WakeCounts>,
+}
+
+static VTABLE: RawWakerVTable = RawWakerVTable::new(clone, wake, wake_by_ref, drop);
+
+unsafe fn clone(ptr: *const ()) -> RawWaker {
+  let counted = ManuallyDrop::new(Arc::from_raw(ptr as *const Counted));
+  counted.counts.clones.fetch_add(1, Ordering::Relaxed);
+  RawWaker::new(Arc::into_raw(Arc::clone(&counted)) as *const (), &VTABLE)
+}
+
+unsafe fn wake(ptr: *const ()) {
+  let counted = Arc::from_raw(ptr as *const Counted);
+  counted.counts.wakes.fetch_add(1, Ordering::Relaxed);
+  counted.inner.wake_by_ref();
+}
+
+unsafe fn wake_by_ref(ptr: *const ()) {
+  let counted = &*(ptr as *const Counted);
+  counted.counts.wakes.fetch_add(1, Ordering::Relaxed);
+  counted.inner.wake_by_ref();
+}
+
+unsafe fn drop(ptr: *const ()) {
+  std::mem::drop(Arc::from_raw(ptr as *const Counted));
+}
+
+// poll the future with a waker that count clone and wake
+pub(crate) struct CountWakes<F> {
+  future: F,
+  counts: Arc<WakeCounts>,
+
+  // reused while the task's waker is the same
+  waker: Option<Arc<Counted>>,
+}
+
+impl<F> CountWakes<F> {
+  pub(crate) fn new(future: F, counts: Arc<WakeCounts>) -> CountWakes<F> {
+    CountWakes {
+      future,
+      counts,
+      waker: None,
+    }
+  }
+}
+
+impl<F: Future> Future for CountWakes<F> {
+  type Output = F::Output;
+
+  fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<F::Output> {
+    // this is safe because we never move future out of self
+    let this = unsafe { self.get_unchecked_mut() };
+
+    let stale = match &this.waker {
+      Some(counted) => !counted.inner.will_wake(cx.waker()),
+      None => true,
+    };
+    if stale {
+      this.waker = Some(Arc::new(Counted {
+        inner: cx.waker().clone(),
+        counts: this.counts.clone(),
+      }));
+    }
+    let counted = this.waker.as_ref().unwrap();
+
+    // borrow, the reference count is not incremented, so it must not be dropped
+    let raw = RawWaker::new(Arc::as_ptr(counted) as *const (), &VTABLE);
+    let waker = ManuallyDrop::new(unsafe { Waker::from_raw(raw) });
+
+    let future = unsafe { Pin::new_unchecked(&mut this.future) };
+    future.poll(&mut Context::from_waker(&waker))
+  }
+}
@@ -0,0 +1,33 @@
+use lelet::Builder;
+
+// spare processors get their machine thread only when added, so the init
+// doesn't fail when there are not enough threads for all of them
+#[test]
+fn spare_processor_machine_is_lazy() {
+  let num_cpus = std::cmp::max(1, num_cpus::get());
+  Builder::new()
+    .max_processors(num_cpus + 2)
+    .max_threads(num_cpus + 1)
+    .init()
+    .unwrap();
+
+  let topology = lelet::topology();
+  assert_eq!(topology.len(), num_cpus + 2);
+  for p in &topology[num_cpus..] {
+    assert!(p.retired);
+    assert_eq!(p.machine_id, None);
+  }
+
+  let index = lelet::add_processor().unwrap();
+  assert_eq!(index, num_cpus);
+
+  // the added processor run tasks
+  let reserved = lelet::reserve_processor(index).unwrap();
+  assert_eq!(lelet::block_on(reserved.spawn(async { 1 })).unwrap(), 1);
+  drop(reserved);
+
+  // no thread left for the last spare
+  let err = lelet::add_processor().unwrap_err();
+  assert!(err.contains("no thread"), "{}", err);
+  assert!(lelet::topology()[num_cpus + 1].retired);
+}
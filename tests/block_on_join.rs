@@ -0,0 +1,46 @@
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+// wait for the spawned tasks, and the tasks they spawn, without awaiting
+// their handles
+#[test]
+fn join_nested_tasks() {
+  let done = Arc::new(AtomicBool::new(false));
+  let output = lelet::block_on_join({
+    let done = done.clone();
+    async move {
+      drop(lelet::spawn(async move {
+        lelet::sleep(Duration::from_millis(50)).await;
+        drop(lelet::spawn(async move {
+          lelet::sleep(Duration::from_millis(50)).await;
+          done.store(true, Ordering::SeqCst);
+        }));
+      }));
+      1
+    }
+  });
+  assert_eq!(output, 1);
+  assert!(done.load(Ordering::SeqCst));
+}
+
+// the tasks don't outlive the call, even if the future panic
+#[test]
+fn join_wait_on_panic() {
+  let done = Arc::new(AtomicBool::new(false));
+  let result = panic::catch_unwind(AssertUnwindSafe(|| {
+    lelet::block_on_join({
+      let done = done.clone();
+      async move {
+        drop(lelet::spawn(async move {
+          lelet::sleep(Duration::from_millis(100)).await;
+          done.store(true, Ordering::SeqCst);
+        }));
+        panic!("block_on_join future panicked");
+      }
+    })
+  }));
+  assert!(result.is_err());
+  assert!(done.load(Ordering::SeqCst));
+}
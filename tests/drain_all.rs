@@ -0,0 +1,46 @@
+#![cfg(feature = "raw")]
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use lelet::Builder;
+
+// tasks left in the local queue of a blocking machine are only reachable
+// through the inherited queue of the machine that replaced it
+#[test]
+fn drain_all_after_replacement() {
+  // the inherited tasks are taken one by one, so they stay there
+  Builder::new().inherit_ratio(1).init().unwrap();
+
+  let machines = || -> Vec<_> { lelet::topology().iter().map(|p| p.machine_id).collect() };
+  let initial = machines();
+
+  let ran = Arc::new(AtomicUsize::new(0));
+  let blocking = lelet::spawn(async {
+    // nothing run after this, including the new machine
+    lelet::pause();
+    thread::sleep(Duration::from_millis(500));
+  });
+  for _ in 0..100 {
+    let ran = ran.clone();
+    drop(lelet::spawn(async move {
+      ran.fetch_add(1, Ordering::SeqCst);
+    }));
+  }
+
+  // wait until the blocking machine is replaced
+  let started = Instant::now();
+  while machines() == initial {
+    assert!(started.elapsed() < Duration::from_secs(5));
+    thread::sleep(Duration::from_millis(10));
+  }
+
+  let drained = lelet::raw::drain_all();
+  assert_eq!(drained.len() + ran.load(Ordering::SeqCst), 100);
+  drop(drained);
+
+  lelet::resume();
+  lelet::block_on(blocking).unwrap();
+}
@@ -0,0 +1,22 @@
+use std::time::Instant;
+
+// with lazy init (the default), the first spawn start the executor threads
+#[test]
+fn lazy_init_first_spawn_latency() {
+  let started = Instant::now();
+  let first = lelet::spawn(async { 1 });
+  let first_spawn = started.elapsed();
+
+  let started = Instant::now();
+  let second = lelet::spawn(async { 2 });
+  let second_spawn = started.elapsed();
+
+  assert_eq!(lelet::block_on(first).unwrap(), 1);
+  assert_eq!(lelet::block_on(second).unwrap(), 2);
+  assert!(
+    second_spawn < first_spawn,
+    "{:?} >= {:?}",
+    second_spawn,
+    first_spawn
+  );
+}
@@ -0,0 +1,19 @@
+use std::time::Instant;
+
+use lelet::{Builder, InitMode};
+
+// with eager init, starting the threads is paid by init, not by the first
+// spawn
+#[test]
+fn eager_init_first_spawn_latency() {
+  let started = Instant::now();
+  Builder::new().init_mode(InitMode::Eager).init().unwrap();
+  let init = started.elapsed();
+
+  let started = Instant::now();
+  let handle = lelet::spawn(async { 1 });
+  let first_spawn = started.elapsed();
+
+  assert_eq!(lelet::block_on(handle).unwrap(), 1);
+  assert!(first_spawn < init, "{:?} >= {:?}", first_spawn, init);
+}
@@ -0,0 +1,40 @@
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use lelet::Priority;
+
+// tasks that are checked for aging but not promoted keep their order
+#[test]
+fn aging_keeps_fifo_order() {
+  let order = Arc::new(Mutex::new(Vec::new()));
+
+  // the order is only kept within one queue, with more processors, run
+  // them all on one reserved processor
+  let reserved = lelet::reserve_processor(0).ok();
+
+  lelet::pause();
+  let mut handles = Vec::new();
+  for i in 0..50 {
+    let order = order.clone();
+    let task = async move {
+      order.lock().unwrap().push(i);
+    };
+    handles.push(match &reserved {
+      Some(reserved) => reserved.spawn(task),
+      None => lelet::spawn(task),
+    });
+    std::thread::sleep(Duration::from_millis(10));
+  }
+  // high priority tasks make the sysmon check the normal queues
+  for _ in 0..3 {
+    handles.push(lelet::spawn_with_priority(Priority::High, async {}));
+  }
+  std::thread::sleep(Duration::from_millis(400));
+  lelet::resume();
+
+  for h in handles {
+    lelet::block_on(h).unwrap();
+  }
+  let order = order.lock().unwrap();
+  assert_eq!(*order, (0..50).collect::<Vec<_>>());
+}
@@ -0,0 +1,23 @@
+use std::time::{Duration, Instant};
+
+use lelet::sync::RateLimiter;
+
+// the token is reserved by acquire, not by the first poll
+#[test]
+fn acquire_reserve_eagerly() {
+  let limiter = RateLimiter::new(10, 1);
+
+  let started = Instant::now();
+  let first = limiter.acquire();
+  let second = limiter.acquire();
+
+  lelet::block_on(second);
+  let elapsed = started.elapsed();
+  assert!(elapsed >= Duration::from_millis(90), "{:?}", elapsed);
+  assert!(elapsed < Duration::from_millis(190), "{:?}", elapsed);
+
+  // reserved before the second one, so it is ready already
+  let started = Instant::now();
+  lelet::block_on(first);
+  assert!(started.elapsed() < Duration::from_millis(10));
+}
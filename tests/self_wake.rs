@@ -0,0 +1,55 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+// wake itself on every poll, until stopped
+struct SelfWake(Arc<AtomicBool>);
+
+impl Future for SelfWake {
+  type Output = ();
+
+  fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+    if self.0.load(Ordering::SeqCst) {
+      return Poll::Ready(());
+    }
+    cx.waker().wake_by_ref();
+    Poll::Pending
+  }
+}
+
+// a task that keep requeuing itself can't starve the tasks queued behind it
+#[test]
+fn self_wake_does_not_starve() {
+  // one queue, with more processors, run them all on one reserved processor
+  let reserved = lelet::reserve_processor(0).ok();
+  let spawn = |f: Box<dyn Future<Output = ()> + Send + Unpin>| match &reserved {
+    Some(reserved) => reserved.spawn(f),
+    None => lelet::spawn(f),
+  };
+
+  let stop = Arc::new(AtomicBool::new(false));
+  let self_wake = spawn(Box::new(SelfWake(stop.clone())));
+
+  let done = Arc::new(AtomicUsize::new(0));
+  let started = Instant::now();
+  let handles: Vec<_> = (0..1000)
+    .map(|_| {
+      let done = done.clone();
+      spawn(Box::new(Box::pin(async move {
+        done.fetch_add(1, Ordering::SeqCst);
+      })))
+    })
+    .collect();
+  for h in handles {
+    lelet::block_on(h).unwrap();
+  }
+  let elapsed = started.elapsed();
+
+  stop.store(true, Ordering::SeqCst);
+  lelet::block_on(self_wake).unwrap();
+  assert_eq!(done.load(Ordering::SeqCst), 1000);
+  assert!(elapsed < Duration::from_secs(2), "{:?}", elapsed);
+}
@@ -0,0 +1,53 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use lelet::Builder;
+
+// wake itself once, so the task is pushed again
+struct YieldNow(bool);
+
+impl Future for YieldNow {
+  type Output = ();
+
+  fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+    if self.0 {
+      return Poll::Ready(());
+    }
+    self.0 = true;
+    cx.waker().wake_by_ref();
+    Poll::Pending
+  }
+}
+
+// queued tasks, and the tasks woken up while draining, are run
+#[test]
+fn drain_run_woken_tasks() {
+  Builder::new().shutdown_drain(true).init().unwrap();
+
+  let done = Arc::new(AtomicUsize::new(0));
+
+  lelet::pause();
+  let handles: Vec<_> = (0..20)
+    .map(|_| {
+      let done = done.clone();
+      lelet::spawn(async move {
+        for _ in 0..10 {
+          YieldNow(false).await;
+        }
+        done.fetch_add(1, Ordering::SeqCst);
+      })
+    })
+    .collect();
+
+  lelet::shutdown();
+  assert_eq!(done.load(Ordering::SeqCst), 20);
+  for h in handles {
+    lelet::block_on(h).unwrap();
+  }
+
+  // spawn is still rejected
+  assert!(lelet::block_on(lelet::spawn(async {})).unwrap_err().is_cancelled());
+}
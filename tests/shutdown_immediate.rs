@@ -0,0 +1,88 @@
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, Sender};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use lelet::{JoinHandle, ShutdownMode};
+
+// spawn from its destructor, that is run when the task is dropped by shutdown
+struct SpawnOnDrop {
+  polled: Arc<AtomicBool>,
+  spawned: Mutex<Sender<JoinHandle<()>>>,
+}
+
+impl Drop for SpawnOnDrop {
+  fn drop(&mut self) {
+    let polled = self.polled.clone();
+    let handle = lelet::spawn(async move { polled.store(true, Ordering::SeqCst) });
+    self.spawned.lock().unwrap().send(handle).unwrap();
+  }
+}
+
+// queued tasks, and the tasks woken up after shutdown, are dropped, and so
+// are the tasks spawned from their destructors
+//
+// one test, the executor can only be shut down once per process
+#[test]
+fn immediate_drop_woken_tasks() {
+  let done = Arc::new(AtomicUsize::new(0));
+
+  // waiting on the timer when the executor is stopped
+  let sleeping: Vec<_> = (0..10)
+    .map(|_| {
+      let done = done.clone();
+      lelet::spawn(async move {
+        lelet::sleep(Duration::from_millis(200)).await;
+        done.fetch_add(1, Ordering::SeqCst);
+      })
+    })
+    .collect();
+  std::thread::sleep(Duration::from_millis(50));
+
+  // sleeping machine run one task when it is woken up before it see the
+  // pause, wake them all, so they are waiting for resume
+  lelet::pause();
+  while lelet::topology().iter().any(|p| p.sleeping) {
+    drop(lelet::spawn(async {}));
+    std::thread::sleep(Duration::from_millis(10));
+  }
+
+  let queued: Vec<_> = (0..10)
+    .map(|_| {
+      let done = done.clone();
+      lelet::spawn(async move {
+        done.fetch_add(1, Ordering::SeqCst);
+      })
+    })
+    .collect();
+
+  let polled = Arc::new(AtomicBool::new(false));
+  let (spawned, spawned_notif) = channel();
+  let guard = SpawnOnDrop {
+    polled: polled.clone(),
+    spawned: Mutex::new(spawned),
+  };
+  let spawn_on_drop = lelet::spawn(async move {
+    let _guard = guard;
+  });
+
+  lelet::shutdown_with_mode(ShutdownMode::Immediate);
+
+  // spawn while shutting down is rejected
+  let handle = spawned_notif.recv_timeout(Duration::from_secs(5)).unwrap();
+  assert!(lelet::block_on(handle).unwrap_err().is_cancelled());
+  assert!(lelet::block_on(spawn_on_drop).unwrap_err().is_cancelled());
+  assert!(!polled.load(Ordering::SeqCst));
+  assert!(lelet::try_spawn(async {}).is_err());
+
+  // unblock has no output to give when rejected, it panics (documented)
+  let unblock = lelet::unblock(|| ());
+  let unblocked =
+    std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| lelet::block_on(unblock)));
+  assert!(unblocked.is_err());
+
+  for h in queued.into_iter().chain(sleeping) {
+    assert!(lelet::block_on(h).unwrap_err().is_cancelled());
+  }
+  assert_eq!(done.load(Ordering::SeqCst), 0);
+}
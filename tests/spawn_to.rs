@@ -0,0 +1,26 @@
+#![cfg(feature = "raw")]
+
+use lelet::Builder;
+
+// task for invalid or retired processor fall back to the rotation, instead
+// of panicking or waiting on a processor that is not run
+#[test]
+fn spawn_to_fallback() {
+  let num_cpus = std::cmp::max(1, num_cpus::get());
+  Builder::new().max_processors(num_cpus + 2).init().unwrap();
+
+  // out of range
+  let h = lelet::raw::spawn_to(num_cpus + 10, async { 1 });
+  assert_eq!(lelet::block_on(h).unwrap(), 1);
+
+  // spare processor, retired without machine
+  assert!(lelet::topology()[num_cpus + 1].retired);
+  let h = lelet::raw::spawn_to(num_cpus + 1, async { 2 });
+  assert_eq!(lelet::block_on(h).unwrap(), 2);
+
+  // retired by retire_processor, its machine is parked
+  let index = lelet::add_processor().unwrap();
+  lelet::retire_processor(index).unwrap();
+  let h = lelet::raw::spawn_to(index, async { 3 });
+  assert_eq!(lelet::block_on(h).unwrap(), 3);
+}
@@ -0,0 +1,44 @@
+use std::sync::mpsc::channel;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Duration;
+
+use lelet::Builder;
+
+// all processors block, and there is no thread left to replace them
+#[test]
+fn stall_is_reported_and_recovered() {
+  let num_cpus = std::cmp::max(1, num_cpus::get());
+  let (stalled, stall_notif) = channel();
+  let stalled = Mutex::new(stalled);
+  Builder::new()
+    .max_threads(num_cpus)
+    .stall_handler(move || stalled.lock().unwrap().send(()).unwrap())
+    .init()
+    .unwrap();
+
+  let gate = Arc::new((Mutex::new(false), Condvar::new()));
+  let handles: Vec<_> = (0..num_cpus)
+    .map(|i| {
+      let gate = gate.clone();
+      lelet::spawn(async move {
+        let (open, cond) = &*gate;
+        let mut open = open.lock().unwrap();
+        while !*open {
+          open = cond.wait(open).unwrap();
+        }
+        i
+      })
+    })
+    .collect();
+
+  stall_notif.recv_timeout(Duration::from_secs(5)).unwrap();
+
+  // the blocking tasks return, the executor run tasks again
+  let (open, cond) = &*gate;
+  *open.lock().unwrap() = true;
+  cond.notify_all();
+  for (i, h) in handles.into_iter().enumerate() {
+    assert_eq!(lelet::block_on(h).unwrap(), i);
+  }
+  assert_eq!(lelet::block_on(lelet::spawn(async { 1 })).unwrap(), 1);
+}
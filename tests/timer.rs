@@ -0,0 +1,18 @@
+use std::time::Duration;
+
+use lelet::{sleep, timeout, Elapsed};
+
+// the deadline overflows Instant, it never fire instead of panic
+#[test]
+fn max_duration_never_fire() {
+  let forever = sleep(Duration::MAX);
+  assert_eq!(forever.deadline(), None);
+  let res = lelet::block_on(timeout(Duration::from_millis(20), forever));
+  assert_eq!(res, Err(Elapsed));
+
+  let res = lelet::block_on(timeout(Duration::MAX, async { 1 }));
+  assert_eq!(res, Ok(1));
+
+  let res = lelet::block_on(timeout(Duration::MAX, sleep(Duration::from_millis(20))));
+  assert_eq!(res, Ok(()));
+}